@@ -8,7 +8,9 @@ chomp $bin;
 -x $bin or die "Cannot find yoshimi";
 my @cmd = ();
 
-@cmd = ($bin,  '-i', '-J', '--alsa-midi=1', '-c', '-K', '-L', "'Simple Clonewheel.xiz'", '-N', 'Yoshimi01', '-R', '48000');
+## yoshimi connects to nothing.  lpx_manager connects to it, so each
+## pad plays one note
+@cmd = ($bin,  '-i', '-J', '--alsa-midi', '-c', '-K', '-L', "'Simple Clonewheel.xiz'", '-N', 'Yoshimi01', '-R', '48000');
 &run(@cmd);
 sleep 1;
 
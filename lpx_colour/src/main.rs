@@ -1,25 +1,34 @@
-use midi_connection::MIDICommunicator;
+use lpx_protocol::{LedMessage, Lighting};
+use midi_connection::{
+    client_name_from_args, default_client_name, Direction, LpxPorts, MIDICommunicator,
+};
 
 use std::env;
 use std::error::Error;
 // use std::io::stdin;
 fn main() -> Result<(), Box<dyn Error>> {
-    // Get the pad (11..99) and colour (r,g,b)
-    let args: Vec<String> = env::args().collect();
+    // Get the pad (11..99) and colour (r,g,b), after `--client NAME`
+    // and `--lpx-out PORT`
+    let mut args: Vec<String> = env::args().collect();
+    let client_name = client_name_from_args(&mut args)?.unwrap_or_else(default_client_name);
 
-    let mut midi_communicator1 = MIDICommunicator::new(
-        "Launchpad X:Launchpad X MIDI 1",
-        "120-Proof-1",
-        |_, _, _| {},
-        (),
-        2,
-    )?;
+    let lpx = LpxPorts::from_args(&mut args, client_name.as_str())?;
+    let mut midi_communicator1: MIDICommunicator = MIDICommunicator::builder(
+        lpx.daw_out.as_str(),
+        format!("{}-LPX", client_name).as_str(),
+    )
+    .direction(Direction::Output)
+    .build()?;
 
     let pad: u8 = args[1].parse()?;
     let red: u8 = args[2].parse()?;
     let green: u8 = args[3].parse()?;
     let blue: u8 = args[4].parse()?;
-    let msg: [u8; 13] = [240, 0, 32, 41, 2, 12, 3, 3, pad, red, green, blue, 247];
+    // Checks the pad is on the LPX.  Red, green and blue over 127 are
+    // taken as 127
+    let msg = LedMessage::new()
+        .light(pad, Lighting::rgb(red, green, blue))?
+        .build();
 
     midi_communicator1.send(&msg)?;
 
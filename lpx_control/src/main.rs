@@ -1,6 +1,11 @@
 //! Use the MIDI control keys from the LPX to run programmes.
 // use std::io::stdin;
-use midi_connection::MIDICommunicator;
+use lpx_protocol::{FrameLayer, Framebuffer, LedMessage, Lighting, Pad};
+use midi_connection::crossbeam_channel::{self, select, Receiver, Sender};
+use midi_connection::{
+    client_name_from_args, default_client_name, exit_status, on_exit_from_args, ConnectionEvent,
+    Direction, LpxPorts, LpxRestore, MIDICommunicator, MidiMessage, Midir, Shutdown, Tap,
+};
 use std::collections::HashMap;
 use std::env;
 use std::error::Error;
@@ -8,17 +13,23 @@ use std::path::Path;
 use std::process;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::thread;
-use std::time::Duration;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 // Colours used for the keys to provide feedback
 static ENABLEDCOLOUR: u8 = 87; // Ready
 static DISABLEDCOLOUR: u8 = 5; // Disabled
 static SELECTEDCOLOUR: u8 = 67; // In use
 
+// How a control pad is lit while its commands run: pulsing while it
+// starts, flashing while it stops
+static STARTING: Lighting = Lighting::Pulse(SELECTEDCOLOUR);
+static STOPPING: Lighting = Lighting::Flash(SELECTEDCOLOUR, ENABLEDCOLOUR);
+static SELECTED: Lighting = Lighting::Static(SELECTEDCOLOUR);
+
 // The number of seconds to make the controls inactive when
 // notes played
-static SLEEPDURATION: usize = 2;
+static SLEEPDURATION: u64 = 2;
 
 /// Dispatcher matches a control key to an executable and executes it
 struct Dispatcher {
@@ -28,7 +39,12 @@ struct Dispatcher {
     down_table: HashMap<u8, String>,
 
     // The last pad pressed.  At the start this is None
-    last: Option<u8>,
+    last: Option<Pad>,
+
+    // Commands go to the thread that runs them.  `None` once it is
+    // told to stop
+    jobs: Option<Sender<Job>>,
+    job_thread: Option<JoinHandle<()>>,
 }
 impl std::fmt::Debug for Dispatcher {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -53,31 +69,30 @@ impl Dispatcher {
         let mut up_table: HashMap<u8, String> = HashMap::new();
         let mut down_table: HashMap<u8, String> = HashMap::new();
 
-        // When a button pressed store its MIDI value here
-        let last: Option<u8> = None;
-
-        up_table.insert(19, "ON-CTL.19".to_string());
-        up_table.insert(29, "ON-CTL.29".to_string());
-        up_table.insert(39, "ON-CTL.39".to_string());
-        up_table.insert(49, "ON-CTL.49".to_string());
-        up_table.insert(59, "ON-CTL.59".to_string());
-        up_table.insert(69, "ON-CTL.69".to_string());
-        up_table.insert(79, "ON-CTL.79".to_string());
-        up_table.insert(89, "ON-CTL.89".to_string());
-
-        down_table.insert(19, "OFF-CTL.19".to_string());
-        down_table.insert(29, "OFF-CTL.29".to_string());
-        down_table.insert(39, "OFF-CTL.39".to_string());
-        down_table.insert(49, "OFF-CTL.49".to_string());
-        down_table.insert(59, "OFF-CTL.59".to_string());
-        down_table.insert(69, "OFF-CTL.69".to_string());
-        down_table.insert(79, "OFF-CTL.79".to_string());
-        down_table.insert(89, "OFF-CTL.89".to_string());
+        // When a button pressed store it here
+        let last: Option<Pad> = None;
+
+        // The buttons down the right hand side, CCs 19 to 89
+        for control in Pad::right_column().filter_map(Pad::cc) {
+            up_table.insert(control, format!("ON-CTL.{}", control));
+            down_table.insert(control, format!("OFF-CTL.{}", control));
+        }
+
+        // Commands are run on their own thread, that stops when the
+        // `Dispatcher` is dropped and the commands queued have run
+        let (jobs, queued) = crossbeam_channel::unbounded::<Job>();
+        let job_thread = thread::spawn(move || {
+            for job in queued.iter() {
+                job.run();
+            }
+        });
 
         Self {
             down_table: down_table,
             up_table: up_table,
             last: last,
+            jobs: Some(jobs),
+            job_thread: Some(job_thread),
         }
     }
 
@@ -113,68 +128,154 @@ impl Dispatcher {
         // eprintln!("run_cmd({}) Ends", &cmd);
     }
 
+    /// Run `cmd` for `pad` on the thread that runs commands.  The
+    /// pad is lit `running` while it runs, and then `then`, or if that
+    /// is `None` goes back to the colour of the other controls
+    fn queue(
+        &self,
+        pad: Pad,
+        cmd: &str,
+        running: Lighting,
+        then: Option<Lighting>,
+        lights: Arc<Mutex<Lights>>,
+    ) {
+        let job = Job {
+            pad,
+            cmd: cmd.to_string(),
+            running,
+            then,
+            lights,
+        };
+        let sent = match self.jobs.as_ref() {
+            Some(jobs) => jobs.send(job).is_ok(),
+            None => false,
+        };
+        if !sent {
+            eprintln!("Cannot run {}: the command thread has stopped", cmd);
+        }
+    }
+
     /// A control pad has been pressed
-    /// `ctl` is the pad number
-    fn run_ctl(&mut self, ctl: u8, lpx_midi: Arc<Mutex<MIDICommunicator<()>>>) {
+    /// `ctl` is the pad
+    fn run_ctl(&mut self, ctl: Pad, lights: Arc<Mutex<Lights>>) {
         // Shut down the last control used
         // eprintln!("run_ctl({}) Starts", ctl);
         if let Some(x) = self.last {
             // eprintln!("There was a last: {}", &x);
-            match self.down_table.get(&x) {
-                Some(cmd) => {
-                    // There is a command to run for shutting down last control
-
-                    // Start flashing the selected pad to illustrate it is turning off
-                    let out_message_flash: [u8; 11] =
-                        [240, 0, 32, 41, 2, 12, 3, 2, x, SELECTEDCOLOUR, 247];
-                    let mut midi_comm = lpx_midi.lock().unwrap();
-                    match midi_comm.send(&out_message_flash) {
-                        Ok(()) => (), //eprintln!("Sent message: {:?}", &out_message_flash),
-                        Err(err) => eprintln!("Failed send: {:?}", err),
-                    };
-
-                    Self::run_cmd(cmd.as_str());
-
-                    // Colour the pad enabled
-                    let out_message_disable: [u8; 11] =
-                        [240, 0, 32, 41, 2, 12, 3, 0, x, ENABLEDCOLOUR, 247];
-                    match midi_comm.send(&out_message_disable) {
-                        Ok(()) => (), //eprintln!("Sent message: {:?}", &out_message_disable),
-                        Err(err) => eprintln!("Failed send: {:?}", err),
-                    };
-                }
-                // The last control does not need anything special to
-                // shutdown
-                None => (),
+            // The last control may not need anything special to
+            // shutdown
+            if let Some(cmd) = self.down_table.get(&x.number()) {
+                // There is a command to run for shutting down last
+                // control.  Then it is no longer selected
+                self.queue(x, cmd.as_str(), STOPPING, None, lights.clone());
             }
         }
         self.last = Some(ctl);
 
-        match self.up_table.get(&ctl) {
-            Some(cmd) => {
-                // eprintln!("run_ctl({}) Run command: {}", ctl, &cmd);
-                // Flash pad to show it is being enabled
-                let out_message_flash: [u8; 11] =
-                    [240, 0, 32, 41, 2, 12, 3, 2, ctl, SELECTEDCOLOUR, 247];
-                let mut midi_comm = lpx_midi.lock().unwrap();
-                match midi_comm.send(&out_message_flash) {
-                    Ok(()) => (), //eprintln!("Sent message: {:?}", &out_message_flash),
-                    Err(err) => eprintln!("Failed send: {:?}", err),
-                };
-
-                Self::run_cmd(cmd.as_str());
-
-                // Colour pad selected
-                let out_message_enable: [u8; 11] =
-                    [240, 0, 32, 41, 2, 12, 3, 0, ctl, SELECTEDCOLOUR, 247];
-                match midi_comm.send(&out_message_enable) {
-                    Ok(()) => (), //eprintln!("Sent message: {:?}", &out_message_enable),
-                    Err(err) => eprintln!("Failed send: {:?}", err),
-                };
+        if let Some(cmd) = self.up_table.get(&ctl.number()) {
+            // eprintln!("run_ctl({}) Run command: {}", ctl, &cmd);
+            // Colour pad selected when it has run
+            self.queue(ctl, cmd.as_str(), STARTING, Some(SELECTED), lights);
+        }
+        // eprintln!("run_ctl({}) finish", ctl);
+    }
+}
+
+impl Drop for Dispatcher {
+    /// Wait for the commands queued to run, so the pads they light
+    /// are lit before the LPX is put back
+    fn drop(&mut self) {
+        self.jobs = None;
+        if let Some(job_thread) = self.job_thread.take() {
+            if job_thread.join().is_err() {
+                eprintln!("A command panicked");
             }
-            None => (),
+        }
+    }
+}
+
+/// A command to run for a control pad.  Commands are run one at a
+/// time, in order, on their own thread, so the LPX is still handled
+/// while they run
+struct Job {
+    pad: Pad,
+    cmd: String,
+    // How the pad is lit while the command runs, and after.  `None`
+    // after is the colour of the other controls
+    running: Lighting,
+    then: Option<Lighting>,
+    lights: Arc<Mutex<Lights>>,
+}
+impl Job {
+    fn run(self) {
+        // Show the command is running.  The LPX is only locked while
+        // the pad is drawn and sent
+        self.lights
+            .lock()
+            .unwrap()
+            .light(self.pad, Some(self.running));
+        Dispatcher::run_cmd(self.cmd.as_str());
+        self.lights.lock().unwrap().light(self.pad, self.then);
+    }
+}
+
+/// What the control pads show, and the connection to the LPX that
+/// shows it.  Colours are drawn into `frame`: the controls, enabled
+/// or not, and over them the pad that is selected.  `show` sends the
+/// pads that have changed
+struct Lights {
+    frame: Framebuffer,
+    lpx_midi: MIDICommunicator,
+}
+impl std::fmt::Debug for Lights {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Lights")
+    }
+}
+impl Lights {
+    fn send(&mut self, message: &[u8]) {
+        match self.lpx_midi.send(message) {
+            Ok(()) => (), //eprintln!("Sent message: {:?}", &message),
+            Err(err) => eprintln!("Failed send: {:?}", err),
         };
-        // eprintln!("run_ctl({}) finish", ctl);
+    }
+
+    /// Draw `pad` over the other controls with `lighting`, or if
+    /// `None` take it away, and show it
+    fn light(&mut self, pad: Pad, lighting: Option<Lighting>) {
+        match lighting.map(|lighting| self.frame.draw(FrameLayer::Pressed, pad, lighting)) {
+            Some(Err(err)) => eprintln!("Cannot light pad: {}", err),
+            Some(Ok(())) => (),
+            None => self.frame.erase(FrameLayer::Pressed, pad),
+        }
+        self.show();
+    }
+
+    /// Send the pads that have changed since the last time, in one
+    /// message
+    fn show(&mut self) {
+        let mut message = LedMessage::new();
+        message
+            .lights(self.frame.flush())
+            .expect("Pads in the framebuffer are on the LPX");
+        if !message.is_empty() {
+            self.send(&message.build());
+        }
+    }
+
+    /// Draw the control pads enabled or disabled.  If `enable` is
+    /// true they are coloured green (87) and if not red (5)
+    fn draw_controls(&mut self, enable: bool) {
+        let pad_colour = if enable {
+            ENABLEDCOLOUR
+        } else {
+            DISABLEDCOLOUR
+        };
+        for pad in Pad::right_column() {
+            self.frame
+                .draw(FrameLayer::Controls, pad, Lighting::Static(pad_colour))
+                .expect("The control colours are in the palette");
+        }
     }
 }
 
@@ -183,106 +284,77 @@ struct LpxControl {
     // The source of truth for the state of the controls.  Includes the selected pad if there is one
     lpx_state: Arc<Mutex<LPXState>>,
 
-    // The counter the monitoring thread uses.  When put to sleep
-    // `sleeping` is set to this plus SLEEPDURATION
-    counter: Arc<Mutex<usize>>,
+    // When put to sleep, by notes being played, the controls are
+    // inactive until this time
+    wake_at: Instant,
 
-    // Used to put controls to sleep when a MIDI key pressed
-    // lpx_midi_sink: MIDICommunicator<()>,
-    sleeping: Arc<Mutex<usize>>,
+    // True if the controls are drawn disabled.  They are woken, by
+    // the main loop, at `wake_at`
+    asleep: bool,
 
     // Change the colours of the LPX to reflect enabled/disabled
-    // state.  Has to be called from the main thread of this class
-    // that controls how long the LPX Ctl pads are inactive after
-    // notes sent.  It is also used to change the colour of the
-    // selected key
-    lpx_midi: Arc<Mutex<MIDICommunicator<()>>>,
+    // state.  It is also used to change the colour of the selected
+    // key
+    lights: Arc<Mutex<Lights>>,
+
+    // Connection events from `lpx_midi`.  When the LPX is plugged
+    // back in it has lost its colours
+    lpx_events: Receiver<ConnectionEvent>,
 }
 impl LpxControl {
-    fn new() -> LpxControl {
+    /// `lpx_midi` is the connection to the LPX for lighting, and
+    /// `lpx_events` its connection events
+    fn new(lpx_midi: MIDICommunicator, lpx_events: Receiver<ConnectionEvent>) -> LpxControl {
         LpxControl {
-            sleeping: Arc::new(Mutex::new(0)),
             lpx_state: Arc::new(Mutex::new(LPXState::new())),
-
-            // For controlling the colours of the control pads
-            lpx_midi: Arc::new(Mutex::new(
-                MIDICommunicator::new(
-                    "Launchpad X:Launchpad X MIDI 1",
-                    "120-Proof-CTL",
-                    move |_, _, _| {},
-                    (),
-                    2,
-                )
-                .unwrap(),
-            )),
-            counter: Arc::new(Mutex::new(0)),
+            wake_at: Instant::now(),
+            asleep: false,
+            lights: Arc::new(Mutex::new(Lights {
+                frame: Framebuffer::new(),
+                lpx_midi,
+            })),
+            lpx_events,
         }
     }
 
     /// Return if the LPX control buttuns are "sleeping"
     fn sleeping(&self) -> bool {
-        let g = self.sleeping.lock().unwrap();
-        *g != 0
+        Instant::now() < self.wake_at
     }
 
-    /// Put the controls on the LPX to sleep
-    fn sleep(&self, s: usize) {
+    /// Put the controls on the LPX to sleep for `s` seconds from now
+    fn sleep(&mut self, s: u64) {
         // eprint!("sleep({}) start", s);
-        let mut g = self.sleeping.lock().unwrap();
-        let c = self.counter.lock().unwrap();
-        *g = *c + s * 10;
-
-        let lpx_state = self.lpx_state.clone();
-        let lpx_midi = self.lpx_midi.clone();
-
-        let mut lpx_midi_mut = lpx_midi.lock().unwrap();
-        let mut lpx_state_mut = lpx_state.lock().unwrap();
-        enable_lpx(false, &mut lpx_midi_mut, &mut lpx_state_mut);
+        // Put off waking up
+        self.wake_at = Instant::now() + Duration::from_secs(s);
+        self.paint();
     }
 
-    /// Starts the thread that monitors `sleeping`.  It maintains a
-    /// local counter `c`, wakes up periodically and increments `c`.
-    /// It then compares the value of `c` to `sleeping`.  If `c >
-    /// sleeping` then both `c` and `sleeping` reset to 0 and lpx is
-    /// enabled.  Otherwise the lpx is dissabled.
-    fn start(&self) {
-        // eprintln!("start called");
-        let sleeping = self.sleeping.clone();
-        let lpx_state = self.lpx_state.clone();
-        let lpx_midi = self.lpx_midi.clone();
-        let counter = self.counter.clone();
-        // Initialise
-        {
-            let mut lpx_midi_mut = lpx_midi.lock().unwrap();
-            let mut lpx_state_mut = lpx_state.lock().unwrap();
-            lpx_state_mut.active = false; // Force an update
-            lpx_state_mut.last_pad = None; // No pad selected
-            enable_lpx(true, &mut lpx_midi_mut, &mut lpx_state_mut);
+    /// Fires when the controls are to wake up.  Never if they are
+    /// awake
+    fn wake_timer(&self) -> Receiver<Instant> {
+        if self.asleep {
+            crossbeam_channel::at(self.wake_at)
+        } else {
+            crossbeam_channel::never()
         }
+    }
 
-        thread::spawn(move || loop {
-            // Sleep for 100 milli seconds
-            thread::sleep(Duration::new(0, 100_000_000));
-            {
-                // eprintln!("Main sleep monitor loop top");
-                let mut c = counter.lock().unwrap();
-                *c += 1;
-                let mut sleeping_mut = sleeping.lock().unwrap();
-
-                // Check if we need to wake up
-                if *c >= *sleeping_mut {
-                    *sleeping_mut = 0;
-                    *c = 0;
-                    let mut lpx_midi_mut = lpx_midi.lock().unwrap();
-                    let mut lpx_state_mut = lpx_state.lock().unwrap();
-                    enable_lpx(true, &mut lpx_midi_mut, &mut lpx_state_mut);
-                }
+    /// Colour the control pads to show if they are enabled.  Only
+    /// the pads that change are sent.  Called at the start, when
+    /// they go to sleep and wake up, and when the LPX is plugged
+    /// back in
+    fn paint(&mut self) {
+        self.asleep = self.sleeping();
+        let mut lights = self.lights.lock().unwrap();
+        lights.draw_controls(!self.asleep);
+        lights.show();
+    }
 
-                // Check the locking buttons
-                // match
-                // eprintln!("Main sleep monitor loop bottom");
-            }
-        });
+    /// The LPX has been plugged back in, and lost its colours
+    fn repaint(&mut self) {
+        self.lights.lock().unwrap().frame.forget_all();
+        self.paint();
     }
 }
 
@@ -293,10 +365,10 @@ struct MidiCommTools {
     locking_state: LockingState,
 }
 impl MidiCommTools {
-    fn new() -> Self {
+    fn new(lpx_midi: MIDICommunicator, lpx_events: Receiver<ConnectionEvent>) -> Self {
         let dispatcher = Dispatcher::new();
-        let lpx_control = LpxControl::new();
-        lpx_control.start();
+        let mut lpx_control = LpxControl::new(lpx_midi, lpx_events);
+        lpx_control.paint();
         Self {
             lpx_control: lpx_control,
             dispatcher: dispatcher,
@@ -325,200 +397,284 @@ enum LockingState {
 #[derive(Debug)]
 struct LPXState {
     last_pad: Option<u8>,
-    active: bool,
     locking_state: LockingState,
 }
 impl LPXState {
     fn new() -> LPXState {
         LPXState {
             last_pad: None,
-            active: false,
             locking_state: LockingState::Unlocked,
         }
     }
 }
 
-/// Process a MIDI message
+/// Process a control change from the control pad `pad`
 fn process_message(
-    message: &[u8; 3],
+    pad: u8,
+    vel: u8,
     dispatcher: &mut Dispatcher, // defines which external programmes to run
-    lpx_midi: Arc<Mutex<MIDICommunicator<()>>>,
+    lights: Arc<Mutex<Lights>>,
     lpx_state: Arc<Mutex<LPXState>>,
 ) {
-    // eprintln!("process_message({:?})", message);
-    if message[0] == 176 {
-        // A ctl message
-        // eprintln!("process_message message({:?})", message);
-
-        let pad = message[1];
-        let vel = message[2];
-        if pad >= 19 && vel > 0 {
-            // There is some noise coming from the LPX with ctl-key 7
-            // The rest are control signals that we want
-            // The locked state of the LPX must be considered here.  Lock
-            // the mode using pads: 91, 92, 93, 94 in succession and
-            // unlock with 94, 93, 92, 91.  If locked reject any control
-            // key but 94 (which starts unlocking).  If locking/unlocking
-            // the pad must be the next in the sequence r state is swiched
-            // to unlocked/locked.
-
-            let lps = &mut lpx_state.lock().unwrap();
-            eprintln!("state({:?}) message({:?})", lps, message);
-            match lps.locking_state {
-                LockingState::Locked => {
-                    if pad == 94 {
-                        lps.locking_state = LockingState::Unlocking;
-                        return;
-                    }
+    // eprintln!("process_message pad({}) vel({})", pad, vel);
+    if let (Some(control), true) = (Pad::from_cc(pad), vel > 0) {
+        // There is some noise coming from the LPX with ctl-key 7
+        // The rest are control signals that we want
+        // The locked state of the LPX must be considered here.  Lock
+        // the mode using pads: 91, 92, 93, 94 in succession and
+        // unlock with 94, 93, 92, 91.  If locked reject any control
+        // key but 94 (which starts unlocking).  If locking/unlocking
+        // the pad must be the next in the sequence r state is swiched
+        // to unlocked/locked.
+
+        let lps = &mut lpx_state.lock().unwrap();
+        eprintln!("state({:?}) pad({}) vel({})", lps, pad, vel);
+        match lps.locking_state {
+            LockingState::Locked => {
+                if pad == 94 {
+                    lps.locking_state = LockingState::Unlocking;
+                    return;
                 }
+            }
 
-                LockingState::Unlocked => {
-                    if pad == 91 {
-                        lps.locking_state = LockingState::Locking;
-                        return;
-                    }
-                }
-                LockingState::Locking => {
-                    eprintln!("last_pad({:?}) pad({})", lps.last_pad, pad);
-                    if lps.last_pad != Some(pad - 1) {
-                        lps.locking_state = LockingState::Unlocked;
-                        return;
-                    } else if pad == 94 {
-                        lps.locking_state = LockingState::Locked;
-                        eprintln!("Locking");
-                        return;
-                    }
+            LockingState::Unlocked => {
+                if pad == 91 {
+                    lps.locking_state = LockingState::Locking;
+                    return;
                 }
-                LockingState::Unlocking => {
-                    if lps.last_pad != Some(pad + 1) {
-                        // A pad other than 91, 92, 93, or 94 in sequence
-                        lps.locking_state = LockingState::Locked;
-                        return;
-                    } else if pad == 91 {
-                        lps.locking_state = LockingState::Unlocked;
-                        return;
-                    }
+            }
+            LockingState::Locking => {
+                eprintln!("last_pad({:?}) pad({})", lps.last_pad, pad);
+                if lps.last_pad != Some(pad - 1) {
+                    lps.locking_state = LockingState::Unlocked;
+                    return;
+                } else if pad == 94 {
+                    lps.locking_state = LockingState::Locked;
+                    eprintln!("Locking");
+                    return;
                 }
-            };
-            // eprintln!("lps.locking_state({:?}) after block", lps.locking_state);
-
-            // `dispatcher` will decide if any programmes get run
-            lps.last_pad = Some(pad);
-            if lps.locking_state != LockingState::Locked {
-                // eprintln!("lps.locking_state({:?})", lps.locking_state);
-                if pad < 91 {
-                    // Do not run for locking pads
-                    dispatcher.run_ctl(pad, lpx_midi);
+            }
+            LockingState::Unlocking => {
+                if lps.last_pad != Some(pad + 1) {
+                    // A pad other than 91, 92, 93, or 94 in sequence
+                    lps.locking_state = LockingState::Locked;
+                    return;
+                } else if pad == 91 {
+                    lps.locking_state = LockingState::Unlocked;
+                    return;
                 }
             }
-
-            lps.last_pad = Some(pad);
-        }
-    }
-}
-
-/// Change the colour of the control pads.  Depending on the parameter
-/// `enable`.  If `enable` is true the pads are being enabled and are
-/// coloured green (87) and if !enabled the pads are being disabled
-/// and are coloured red (5)
-fn enable_lpx(enable: bool, lpx_midi: &mut MIDICommunicator<()>, lpx_state: &mut LPXState) {
-    if lpx_state.active != enable {
-        // eprintln!(
-        //     "enable_lpx: enable({}) active_pad: {:?}",
-        //     enable, lpx_state.last_pad
-        // );
-        let pad_colour = if enable {
-            ENABLEDCOLOUR
-        } else {
-            DISABLEDCOLOUR
         };
-
-        let active_pad: Option<u8> = lpx_state.last_pad;
-
-        for i in 1..9 {
-            let p = i * 10 + 9; // Pad
-            if let Some(pad) = active_pad {
-                if pad == p {
-                    // Ignore the active pad
-                    // eprintln!("Ignore pad: {}", &p);
-                    continue;
-                }
+        // eprintln!("lps.locking_state({:?}) after block", lps.locking_state);
+
+        // `dispatcher` will decide if any programmes get run
+        lps.last_pad = Some(pad);
+        if lps.locking_state != LockingState::Locked {
+            // eprintln!("lps.locking_state({:?})", lps.locking_state);
+            if !control.is_top_row() {
+                // Do not run for locking pads
+                dispatcher.run_ctl(control, lights);
             }
-            let out_message_colour_change: [u8; 11] =
-                [240, 0, 32, 41, 2, 12, 3, 0, p, pad_colour, 247];
-            match lpx_midi.send(&out_message_colour_change) {
-                Ok(()) => (), //eprintln!("Sent message: {:?}", &out_message_colour_change),
-                Err(err) => eprintln!("Failed send: {:?}", err),
-            };
         }
-        lpx_state.active = enable;
+
+        lps.last_pad = Some(pad);
     }
 }
 
 /// Main loop.
 /// Listen to the LPX MIDI and if it is a CTL signal process it, and
-/// perhaps run some external programmes
-fn run() -> Result<(), Box<dyn Error>> {
+/// perhaps run some external programmes.  Returns the signal that
+/// stopped it, if one did
+fn run() -> Result<Option<i32>, Box<dyn Error>> {
     // `midi_comm_tools` handles all communications with the LPX.  It
     // holds a `Dispatcher` and a `LpxControl`.  The `Dispatcher`
     // translates control messages from the LPX into actions on the
     // computer.
-    // The `LpxControl` holds the data needed to sleep for a defined
-    // period (a wake up and check the time model), A`LPXState` that
-    // has the enabled/disabled state as well as the active LPX pad,
-    // and a `MIDICommunicator` to change the pad colours on the LPX
-    let midi_comm_tools = MidiCommTools::new();
-
-    // The main loop is the closure in this communicator
-    let _foo = MIDICommunicator::new(
-        "Launchpad X:Launchpad X MIDI 2",
-        "120-Proof-CTL",
-        move |_stamp, message, midi_comm_tools| {
-            // eprintln!(
-            //     "{}: Msg: {:?} (len = {})",
-            //     (stamp as f64) / 1_000_000.0,
-            //     &message,
-            //     message.len()
-            // );
-
-            // The messages that wil be processed here are length
-            // three.  MIDI notes are also length three, and when they
-            // come by the controls are inactivated for a period to
-            // avoid accedentally changing the set up of the
-            // instrument
-            if message.len() == 3 {
-                if message[0] == 176 {
-                    if !midi_comm_tools.lpx_control.sleeping() {
-                        let array = <[u8; 3]>::try_from(message).unwrap();
-                        process_message(
-                            &array,
-                            &mut midi_comm_tools.dispatcher,
-                            midi_comm_tools.lpx_control.lpx_midi.clone(),
-                            midi_comm_tools.lpx_control.lpx_state.clone(),
-                        );
-                    }
-                } else if message[0] == 144 {
-                    // A MIDI note
-                    if midi_comm_tools.locking_state != LockingState::Locked {
-                        // No point in going to sleep if locked
-                        midi_comm_tools.lpx_control.sleep(SLEEPDURATION);
+    // The `LpxControl` holds the time the controls wake up, a
+    // `LPXState` that has the locking state as well as the active LPX
+    // pad, and the `Lights`: a `Framebuffer` and a `MIDICommunicator`
+    // to change the pad colours on the LPX
+    let mut args: Vec<String> = env::args().collect();
+    let client_name = client_name_from_args(&mut args)?.unwrap_or_else(default_client_name);
+    let on_exit = on_exit_from_args(&mut args)?;
+    // `--tap FILE` records the MIDI in and out
+    let tap = Tap::from_args(&mut args)?;
+
+    // From here a signal stops the programme cleanly
+    let shutdown = Shutdown::new()?;
+    let lpx = LpxPorts::from_args(&mut args, client_name.as_str())?;
+
+    // For the colours of the control pads.  Its events are passed to
+    // the main loop, that paints the pads again when the LPX is
+    // plugged back in
+    let (lpx_event_tx, lpx_events) = crossbeam_channel::unbounded();
+    let lpx_midi = MIDICommunicator::builder(
+        lpx.daw_out.as_str(),
+        format!("{}-LPX-Out", client_name).as_str(),
+    )
+    .direction(Direction::Output)
+    .reconnect(true)
+    .tap(tap.as_ref())
+    .on_event(move |event| {
+        let _ = lpx_event_tx.send(event);
+    })
+    .build()?;
+
+    // Puts the LPX back when it is dropped.  It is declared before
+    // `midi_comm_tools` so it is dropped after it, and no colours
+    // come after it, however `run` returns
+    let mut lpx_restore = LpxRestore::new(lpx_midi.clone(), on_exit);
+    if let Some(daw_in) = lpx.daw_in.as_ref() {
+        let layout_name = format!("{}-Layout", client_name);
+        if let Err(err) =
+            lpx_restore.remember_layout(daw_in.as_str(), layout_name.as_str(), Arc::new(Midir))
+        {
+            eprintln!("Asking the LPX its layout: {}", err);
+        }
+    }
+    let mut midi_comm_tools = MidiCommTools::new(lpx_midi, lpx_events);
+
+    // Messages from the LPX arrive on `lpx_messages`
+    let (midi_in, lpx_messages) = MIDICommunicator::builder(
+        lpx.midi_in.as_str(),
+        format!("{}-LPX-In", client_name).as_str(),
+    )
+    .direction(Direction::Input)
+    .reconnect(true)
+    .tap(tap.as_ref())
+    .on_event(|event| eprintln!("LPX input: {:?}", event))
+    .build_with_receiver()?;
+
+    // The main loop.  Messages from the LPX, events from the
+    // connection that colours it, and the controls waking up
+    let lpx_events = midi_comm_tools.lpx_control.lpx_events.clone();
+    let mut stopped_by = None;
+    loop {
+        let wake = midi_comm_tools.lpx_control.wake_timer();
+        let timed_message = select! {
+            recv(lpx_messages) -> timed_message => match timed_message {
+                Ok(timed_message) => timed_message,
+                Err(_) => break,
+            },
+            recv(shutdown.receiver()) -> signal => {
+                stopped_by = signal.ok();
+                break;
+            }
+            recv(lpx_events) -> event => {
+                if let Ok(event) = event {
+                    eprintln!("LPX output: {:?}", event);
+                    if let ConnectionEvent::Connected { .. } = event {
+                        // The LPX has lost its colours
+                        midi_comm_tools.lpx_control.repaint();
                     }
                 }
+                continue;
             }
-        },
-        midi_comm_tools,
-        1,
-    )?;
+            recv(wake) -> _ => {
+                midi_comm_tools.lpx_control.paint();
+                continue;
+            }
+        };
+        // eprintln!(
+        //     "{}: Msg: {:?}",
+        //     (timed_message.stamp as f64) / 1_000_000.0,
+        //     &timed_message.bytes,
+        // );
 
-    loop {
-        // Infinite loop
-        thread::sleep(Duration::new(1, 0));
+        // Control changes come from the control pads.  When notes
+        // come by the controls are inactivated for a period to avoid
+        // accedentally changing the set up of the instrument
+        match timed_message.message() {
+            Ok(MidiMessage::ControlChange {
+                controller, value, ..
+            }) => {
+                if !midi_comm_tools.lpx_control.sleeping() {
+                    process_message(
+                        controller,
+                        value,
+                        &mut midi_comm_tools.dispatcher,
+                        midi_comm_tools.lpx_control.lights.clone(),
+                        midi_comm_tools.lpx_control.lpx_state.clone(),
+                    );
+                }
+            }
+            Ok(MidiMessage::NoteOn { .. }) | Ok(MidiMessage::NoteOff { .. }) => {
+                // A MIDI note
+                if midi_comm_tools.locking_state != LockingState::Locked {
+                    // No point in going to sleep if locked
+                    midi_comm_tools.lpx_control.sleep(SLEEPDURATION);
+                }
+            }
+            Ok(_) => (),
+            Err(err) => eprintln!("From LPX: {}", err),
+        }
     }
+    // The input is closed first, then the commands queued are waited
+    // for, and then the LPX is put back
+    drop(midi_in);
+    drop(midi_comm_tools);
+    drop(lpx_restore);
+    Ok(stopped_by)
 }
 
 fn main() {
-    match run() {
-        Ok(_) => (),
-        Err(err) => eprintln!("Error: {}", err),
+    process::exit(match run() {
+        Ok(None) => 0,
+        Ok(Some(signal)) => exit_status(signal),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            1
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midi_connection::Loopback;
+
+    #[test]
+    fn controls_sleep_and_wake() {
+        let loopback = Loopback::new(&["LPX DAW"]);
+        let lpx_midi = MIDICommunicator::builder("LPX DAW", "120-Proof-Test")
+            .direction(Direction::Output)
+            .transport(Arc::new(loopback.clone()))
+            .build()
+            .unwrap();
+        let mut lpx_control = LpxControl::new(lpx_midi, crossbeam_channel::never());
+        let controls = |colour| {
+            LedMessage::new()
+                .colours(Pad::right_column().map(|pad| (pad.number(), colour)))
+                .unwrap()
+                .build()
+        };
+
+        lpx_control.paint();
+        assert_eq!(loopback.take_sent("LPX DAW"), vec![controls(ENABLEDCOLOUR)]);
+        assert!(!lpx_control.sleeping());
+        // Only what changes is sent
+        lpx_control.paint();
+        assert!(loopback.take_sent("LPX DAW").is_empty());
+
+        // Notes are played
+        lpx_control.sleep(SLEEPDURATION);
+        assert!(lpx_control.sleeping());
+        assert_eq!(
+            loopback.take_sent("LPX DAW"),
+            vec![controls(DISABLEDCOLOUR)]
+        );
+
+        // Plugged back in, every control pad is sent again
+        lpx_control.repaint();
+        assert_eq!(
+            loopback.take_sent("LPX DAW"),
+            vec![controls(DISABLEDCOLOUR)]
+        );
+
+        // Woken up
+        lpx_control.wake_at = Instant::now();
+        lpx_control.wake_timer().recv().unwrap();
+        lpx_control.paint();
+        assert_eq!(loopback.take_sent("LPX DAW"), vec![controls(ENABLEDCOLOUR)]);
     }
 }
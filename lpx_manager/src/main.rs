@@ -1,26 +1,60 @@
-use midi_connection::MIDICommunicator;
+use lpx_protocol::{FrameLayer, Framebuffer, Lighting, Pad};
+use midi_connection::crossbeam_channel::{self, select};
+use midi_connection::{
+    client_name_from_args, compare_golden, config_client_name, default_client_name, exit_status,
+    latency_from_args, lpx_interface, on_exit_from_args, read_text, write_text, ConnectionEvent,
+    Direction, FanOut, Latency, Layer, LedPipeline, Logger, Loopback, LpxInterface, LpxPorts,
+    LpxRestore, MIDICommunicator, Measure, MidiMessage, Midir, OnExit, Replay, RouteConfig, Router,
+    Shutdown, StampClock, Tap, Transport,
+};
 use std::env;
 use std::fs::File;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 //use std::io::stdin;
 use std::io::{self, BufRead};
-use std::thread;
-use std::time::Duration;
 //use std::path::Path;
 
 //use std::env;
 // use midir;
 use std::error::Error;
 
+/// How a pad is lit while it is held down: blue.  It could as well
+/// flash (`Lighting::Flash`) or pulse
+const PRESSED: Lighting = Lighting::Static(50);
+
 struct Adapter {
     // Adapter receives MIDI notes from the LPX, changes them
     // according the the asignments in `midi_map` herein and sends
     // them to the synthesiser.   and sends colour change messages to the
     // LPX
-    midi_out_synth: MIDICommunicator<()>,
-    midi_out_lpx: MIDICommunicator<()>,
+    // The synthesisers, each with its own channel, transpose and
+    // velocity
+    synths: FanOut,
+    midi_out_lpx: MIDICommunicator,
+    // Pad colours go to the LPX through this, so they never hold up
+    // notes
+    leds: LedPipeline,
+    // What the pads show: the scale in the base layer and the pads
+    // held down over it.  Only the pads that change are sent
+    frame: Framebuffer,
+    // Set by the main thread when the LPX is plugged back in.  Every
+    // pad is sent again with the next pad pressed, so the callback
+    // never waits for the main thread
+    repaint: Arc<AtomicBool>,
+    // Errors are written out by another thread, so they never hold
+    // up notes either
+    log: Logger,
+    // With `--latency` how long each note takes to get through is
+    // measured, from the time stamps of the LPX's messages
+    latency: Option<Latency>,
+    stamps: StampClock,
     midi_map: [u8; 99], // key is MIDI from LPX value MIDI to synth
     scale: Vec<u8>,     // At most 12 unique intergers in 1..12 inclusive
-    midi_note_to_pads: Vec<(Option<u8>, Option<u8>)>, // Each note at most 2 pads
+    midi_note_to_pads: Vec<(Option<Pad>, Option<Pad>)>, // Each note at most 2 pads
     root_note: u8,
 }
 impl std::fmt::Debug for Adapter {
@@ -36,7 +70,7 @@ impl Adapter {
     /// The colour of a pad.  Root notes get red(5), scale green(17),
     /// others cream(113)
     fn pad_colour(&self, pad_in: u8) -> Option<u8> {
-        if pad_in % 10 > 0 && pad_in % 10 < 9 {
+        if Pad::from_programmer_note(pad_in).is_some() {
             let pad_out = self.adapt(pad_in);
 
             let diff_12 = ((self.root_note as i16 - pad_out as i16).abs() % 12) as u8;
@@ -64,77 +98,117 @@ impl Adapter {
         }
     }
 
+    /// A pad on the LPX has been pressed, or released if `velocity`
+    /// is 0.  Send the note to the synthesiser and change the colour
+    /// of the pads that play it.  `stamp` is when the message came
+    fn press(&mut self, stamp: u64, channel: u8, pad_in: u8, velocity: u8) {
+        if Pad::from_programmer_note(pad_in).is_none() {
+            // Not a MIDI key
+            return;
+        }
+        let arrived = self
+            .latency
+            .as_ref()
+            .map(|latency| latency.arrived(&mut self.stamps, stamp));
+        // A key press, adapt it (translate the position on the LPX
+        // represented by `pad_in` into a MIDI note)
+        let midi_note_out: u8 = self.adapt(pad_in);
+        let note_out = MidiMessage::NoteOn {
+            channel,
+            note: midi_note_out,
+            velocity,
+        };
+        // eprintln!("note_out({:?})", &note_out);
+        match self.synths.send_message(&note_out) {
+            Ok(()) => (),
+            Err(err) => self.log.error("Sending note", err),
+        };
+        if let (Some(latency), Some(arrived)) = (self.latency.as_ref(), arrived) {
+            // As the synthesisers' layers sent it, to know it when it
+            // comes back
+            latency.sent(arrived, self.synths.notes(midi_note_out));
+        }
+
+        // The key that is pressed, light it with `PRESSED` while it
+        // is held.  When released its standard colour shows again.
+        // There are possibly two pads that play the note
+        let pads = self.midi_note_to_pads[midi_note_out as usize];
+        for pad in [pads.0, pads.1].into_iter().flatten() {
+            if velocity == 0 {
+                self.frame.erase(FrameLayer::Pressed, pad);
+            } else if let Err(err) = self.frame.draw(FrameLayer::Pressed, pad, PRESSED) {
+                self.log.error("Lighting pad", err.into());
+            }
+        }
+        self.show();
+    }
+
+    /// Send the pads that have changed.  All of them if the LPX has
+    /// been plugged back in, as its colours are lost
+    fn show(&mut self) {
+        if self.repaint.swap(false, Ordering::Relaxed) {
+            self.frame.forget_all();
+        }
+        if let Err(err) = self.leds.paint(self.frame.flush()) {
+            self.log.error("Lighting pads", err);
+        }
+    }
+
     fn new(
-        midi_out_synth: MIDICommunicator<()>,
-        midi_out_lpx: MIDICommunicator<()>,
+        synths: FanOut,
+        midi_out_lpx: MIDICommunicator,
+        leds: LedPipeline,
+        latency: Option<Latency>,
         scale: &Vec<u8>,
         root_note: u8, // Where the scale is rooted.  The MIDI note
     ) -> Self {
         let mut midi_map = [0_u8; 99];
-        let mut midi_note_to_pads = (0..99)
+        let mut midi_note_to_pads = (0..128)
             .map(|_| (None, None))
-            .collect::<Vec<(Option<u8>, Option<u8>)>>();
-
-        // The layout of the notes
-        let delta: [u8; 80] = [
-            1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 6, 7, 8, 9, 10, 11, 12, 13, 0, 0, 11, 12, 13, 14, 15, 16,
-            17, 18, 0, 0, 16, 17, 18, 19, 20, 21, 22, 23, 0, 0, 21, 22, 23, 24, 25, 26, 27, 28, 0,
-            0, 26, 27, 28, 29, 30, 31, 32, 33, 0, 0, 31, 32, 33, 34, 35, 36, 37, 38, 0, 0, 36, 37,
-            38, 39, 40, 41, 42, 43, 0, 0,
-        ];
-
-        // The middle key in this scheme is 34.  Middle C is MIDI 60
-        // So adjustment...
-        let adj_note = root_note - 24;
-
-        let mut i: u8 = 11;
-
-        for d in delta {
-            // `i` is the number for a pad.  No pads 10, 20,
-            // 30,... and pads 19, 29, 39,... are control pads
-            if i % 10 != 0 && i % 10 != 9 {
-                let midi_note = d + adj_note;
-
-                // Incoming MIDI signals `i` mapped to output MIDI `midi_note`.
-                midi_map[i as usize] = midi_note;
-
-                let row = i / 10;
-                let col = i % 10;
-                // This function returns the (at most) two pads that emit this note
-                let f = |p| {
-                    if p < 80 && col > 5 && col < 9 {
-                        // Not in top row, and in right hand
-                        // three columns.  Pads here have a
-                        // duplicate in the row above
-                        Some((row + 1) * 10 + col - 6)
-                    } else if p > 20 && col < 4 {
-                        // Not in bottom row and in left hand columns.
-                        // Pads here have
-                        // a duplicate in the row below
-                        Some((row - 1) * 10 + col + 5)
-                    } else {
-                        None
-                    }
-                };
+            .collect::<Vec<(Option<Pad>, Option<Pad>)>>();
+
+        // The notes are laid out as in the LPX's Note mode, each row
+        // a fourth above the one below.  The middle key in this
+        // scheme is 34.  Middle C is MIDI 60 So adjustment...
+        let lowest = root_note - 23;
 
-                let pads: (Option<u8>, Option<u8>) = (Some(i), f(i));
+        for pad in Pad::grid() {
+            if let Some(midi_note) = pad.note_mode_note(lowest) {
+                // Incoming MIDI signals `pad` mapped to output MIDI `midi_note`.
+                midi_map[pad.number() as usize] = midi_note;
+
+                // The (at most) two pads that emit this note
+                let pads: (Option<Pad>, Option<Pad>) = (Some(pad), pad.note_mode_twin());
                 // eprintln!(
-                //     "i({}) midi_note_to_pads[{}] row({}) col({}) = {:?}",
-                //     i, midi_note, row, col, pads
+                //     "pad({}) midi_note_to_pads[{}] = {:?}",
+                //     pad, midi_note, pads
                 // );
                 midi_note_to_pads[midi_note as usize] = pads;
             }
-            i += 1;
         }
         //eprintln!("End of Adapter::new");
-        Self {
-            midi_out_synth: midi_out_synth,
+        let mut adapter = Self {
+            synths,
             midi_out_lpx: midi_out_lpx,
+            leds,
+            frame: Framebuffer::new(),
+            repaint: Arc::new(AtomicBool::new(false)),
+            log: Logger::new(),
+            latency,
+            stamps: StampClock::new(),
             midi_map: midi_map,
             scale: scale.to_vec(),
             midi_note_to_pads: midi_note_to_pads,
             root_note: root_note,
+        };
+        for pad in Pad::grid() {
+            let colour = adapter.pad_colour(pad.number()).unwrap();
+            adapter
+                .frame
+                .draw(FrameLayer::Base, pad, Lighting::Static(colour))
+                .expect("Pad colours are in the palette");
         }
+        adapter
     }
 }
 
@@ -149,61 +223,261 @@ struct DeviceNames {
     midi_sink_lpx: String,
     midi_sink_lpx_120: String,
 
-    midi_sink_synth: String,
+    /// Replies from the LPX to the commands.  Needed to restore its
+    /// layout on exit
+    midi_source_lpx_daw: Option<String>,
+
+    /// Each synthesiser, with its `Layer` settings
+    midi_sink_synth: Vec<String>,
     midi_sink_synth_120: String,
+
+    /// Other connections, from the routing file named by `routes:`
+    /// (see `RouteConfig`).  E.g. the LPX's pressure straight to the
+    /// synthesiser
+    routes: Option<RouteConfig>,
+    /// The routes' connections are named from this
+    client_name: String,
 }
 
+// The loopback ports used when replaying a recorded session
+const REPLAY_SOURCE_LPX: &str = "Replay LPX MIDI 2";
+const REPLAY_SINK_LPX: &str = "Replay LPX MIDI 1";
+const REPLAY_SINK_SYNTH: &str = "Replay Synth";
+
 impl DeviceNames {
-    fn new(cfg_fn: &str) -> io::Result<DeviceNames> {
+    /// The names for replaying a session through a `Loopback`
+    fn replay(client_name: Option<String>) -> DeviceNames {
+        let client_name = client_name.unwrap_or_else(default_client_name);
+        DeviceNames {
+            midi_source_lpx: REPLAY_SOURCE_LPX.to_string(),
+            midi_source_lpx_120: format!("{}-LPX-In", client_name),
+            midi_sink_lpx: REPLAY_SINK_LPX.to_string(),
+            midi_sink_lpx_120: format!("{}-LPX-Out", client_name),
+            midi_source_lpx_daw: None,
+            midi_sink_synth: vec![REPLAY_SINK_SYNTH.to_string()],
+            midi_sink_synth_120: format!("{}-Synth", client_name),
+            routes: None,
+            client_name,
+        }
+    }
+
+    /// Read `cfg_fn`.  `client_name`, from the command line, is used
+    /// before one in the file
+    fn new(cfg_fn: &str, client_name: Option<String>) -> Result<DeviceNames, Box<dyn Error>> {
         // panic!("Unfinished.");
 
         // Read a configuration file for midi_source_lpx, midi_sink_lpx, midi_sink_synth
         let mut midi_source_lpx = "".to_string(); //"Launchpad X:Launchpad X MIDI 2".to_string();
         let mut midi_sink_lpx = "".to_string();
-        let mut midi_sink_synth = "".to_string();
+        let mut midi_sink_synth: Vec<String> = Vec::new();
+        let mut midi_source_lpx_daw: Option<String> = None;
+        let mut routes: Option<RouteConfig> = None;
+        // The names of our end of each connection
+        let mut cfg_client_name: Option<String> = None;
+        let mut midi_source_lpx_120: Option<String> = None;
+        let mut midi_sink_lpx_120: Option<String> = None;
+        let mut midi_sink_synth_120: Option<String> = None;
 
         let file = File::open(cfg_fn)?;
         let lines = io::BufReader::new(file).lines();
         for line in lines {
             if let Ok(l) = line {
                 // `l` is the line
-                if l.starts_with("midi_source_lpx:") {
+                if let Some(name) = l.strip_prefix("client_name:") {
+                    cfg_client_name = Some(name.to_string());
+                } else if let Some(name) = l.strip_prefix("midi_source_lpx_120:") {
+                    midi_source_lpx_120 = Some(name.to_string());
+                } else if let Some(name) = l.strip_prefix("midi_sink_lpx_120:") {
+                    midi_sink_lpx_120 = Some(name.to_string());
+                } else if let Some(name) = l.strip_prefix("midi_sink_synth_120:") {
+                    midi_sink_synth_120 = Some(name.to_string());
+                } else if let Some(name) = l.strip_prefix("midi_source_lpx_daw:") {
+                    midi_source_lpx_daw = Some(name.to_string());
+                } else if let Some(path) = l.strip_prefix("routes:") {
+                    routes = Some(RouteConfig::load(path)?);
+                } else if l.starts_with("midi_source_lpx:") {
                     midi_source_lpx = l.strip_prefix("midi_source_lpx:").unwrap().to_string();
                 } else if l.starts_with("midi_sink_lpx:") {
                     midi_sink_lpx = l.strip_prefix("midi_sink_lpx:").unwrap().to_string();
                 } else if l.starts_with("midi_sink_synth:") {
-                    midi_sink_synth = l.strip_prefix("midi_sink_synth:").unwrap().to_string();
+                    midi_sink_synth.push(l.strip_prefix("midi_sink_synth:").unwrap().to_string());
                 } else {
                     panic!("{} misunderstood", &l);
                 }
             }
         }
+        if midi_sink_synth.is_empty() {
+            return Err(format!("{}: No midi_sink_synth", cfg_fn).into());
+        }
+
+        let client_name = client_name
+            .or(cfg_client_name)
+            .unwrap_or_else(|| config_client_name(cfg_fn));
+
+        // The LPX ports are optional.  Without them look for the LPX
+        if midi_source_lpx.is_empty() || midi_sink_lpx.is_empty() {
+            let lpx = LpxPorts::find_as(client_name.as_str())?;
+            if midi_source_lpx.is_empty() {
+                midi_source_lpx = lpx.midi_in;
+            }
+            if midi_sink_lpx.is_empty() {
+                midi_sink_lpx = lpx.daw_out;
+            }
+            if midi_source_lpx_daw.is_none() {
+                midi_source_lpx_daw = lpx.daw_in;
+            }
+        }
         Ok(DeviceNames {
             midi_source_lpx: midi_source_lpx, //"Launchpad X:Launchpad X MIDI 2",
-            midi_source_lpx_120: "120-Proof-MIDI-In-LPX".to_string(),
+            midi_source_lpx_120: midi_source_lpx_120
+                .unwrap_or_else(|| format!("{}-LPX-In", client_name)),
 
             midi_sink_lpx: midi_sink_lpx, //"Launchpad X:Launchpad X MIDI 1".to_string(),
-            midi_sink_lpx_120: "120-Proof-MIDI-Out-LPX".to_string(),
+            midi_sink_lpx_120: midi_sink_lpx_120
+                .unwrap_or_else(|| format!("{}-LPX-Out", client_name)),
+            midi_source_lpx_daw,
 
             midi_sink_synth: midi_sink_synth, //"Pure Data:Pure Data Midi-In 2".to_string(),
-            midi_sink_synth_120: "120-Proof-MIDI-Out-PD".to_string(),
+            midi_sink_synth_120: midi_sink_synth_120
+                .unwrap_or_else(|| format!("{}-Synth", client_name)),
+            routes,
+            client_name,
         })
     }
 }
-fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
+/// A message from the LPX, for the adapter.  Pads play notes, and
+/// anything else goes back to the LPX
+fn from_lpx(stamp: u64, message: &[u8], adapter: &mut Adapter) {
+    // eprintln!("midi_in stamp({:?}) message({:?})", &stamp, &message);
+    match MidiMessage::decode(message) {
+        Ok(MidiMessage::NoteOn {
+            channel,
+            note,
+            velocity,
+        }) => adapter.press(stamp, channel, note, velocity),
+        Ok(MidiMessage::NoteOff { channel, note, .. }) => adapter.press(stamp, channel, note, 0),
+        Ok(message) => match adapter.midi_out_lpx.send_message(&message) {
+            Ok(()) => (),
+            Err(err) => adapter.log.error("Random message(?)", err),
+        },
+        Err(err) => adapter.log.error("From LPX", err),
+    };
+}
+
+/// Play the LPX input recorded in `session` (a text file written by
+/// a `Tap`) through the adapter, and check what it sends against
+/// `golden`.  Only the pads are played, from whichever LPX they were
+/// recorded on.  With `bless` write `golden` instead.  Without it a
+/// missing `golden` is an error
+fn replay_session(
+    loopback: &Loopback,
+    leds: &LedPipeline,
+    session: &Path,
+    golden: &Path,
+    bless: bool,
+) -> Result<(), Box<dyn Error>> {
+    let records = read_text(session)?;
+    if !bless && !golden.exists() {
+        return Err(format!(
+            "{}: No such golden file.  Give --bless to write it",
+            golden.display()
+        )
+        .into());
+    }
+    let output = Replay::new(
+        loopback,
+        REPLAY_SOURCE_LPX,
+        &[REPLAY_SINK_SYNTH, REPLAY_SINK_LPX],
+    )
+    .recorded(|port| lpx_interface(port) == Some(LpxInterface::Midi))
+    .run(&records, || leds.flush());
+    if bless {
+        write_text(golden, &output)?;
+        eprintln!("{}: Wrote {}", session.display(), golden.display());
+    } else {
+        compare_golden(&output, &read_text(golden)?)?;
+        eprintln!("{}: Output matches {}", session.display(), golden.display());
+    }
+    Ok(())
+}
+
+/// Ask the LPX what layout it is in, so `lpx_restore` can put it back
+fn remember_layout(
+    lpx_restore: &mut LpxRestore,
+    device_names: &DeviceNames,
+    transport: Arc<dyn Transport>,
+) {
+    let daw_in = match device_names.midi_source_lpx_daw.as_ref() {
+        Some(daw_in) => daw_in,
+        None => {
+            eprintln!(
+                "No midi_source_lpx_daw in the configuration.  The LPX layout cannot be restored"
+            );
+            return;
+        }
+    };
+    let client_name = format!("{}-Layout", device_names.midi_sink_lpx_120);
+    match lpx_restore.remember_layout(daw_in.as_str(), client_name.as_str(), transport) {
+        Ok(Some(_)) => (),
+        Ok(None) => eprintln!("The LPX did not say what layout it is in.  It cannot be restored"),
+        Err(err) => eprintln!("Asking the LPX its layout: {}", err),
+    }
+}
+
+/// Returns the signal that stopped it, if one did
+fn run() -> Result<Option<i32>, Box<dyn Error>> {
+    let mut args: Vec<String> = env::args().collect();
+    let client_name = client_name_from_args(&mut args)?;
+    let on_exit = on_exit_from_args(&mut args)?;
+    // `--tap FILE` records the MIDI in and out
+    let tap = Tap::from_args(&mut args)?;
+    let measure = latency_from_args(&mut args)?;
+
+    // From here a signal stops the programme cleanly
+    let shutdown = Shutdown::new()?;
 
     // This is the scale.  Should be able to pass this in on the command line.
     let scale: Vec<u8>;
     let root_note: u8;
-    if args.is_empty() {
+    if args.len() < 2 {
         panic!("Need arguments");
     }
+
+    // `--replay SESSION GOLDEN` in place of the config file plays a
+    // recorded session through a loopback, instead of connecting to
+    // the LPX and synthesiser.  `--bless` writes `GOLDEN`
+    let bless = match args.iter().position(|arg| arg == "--bless") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    let replay: Option<(&str, &str)> = if args[1] == "--replay" {
+        match (args.get(2), args.get(3)) {
+            (Some(session), Some(golden)) => Some((session.as_str(), golden.as_str())),
+            _ => panic!("--replay needs a session and a golden file"),
+        }
+    } else {
+        None
+    };
+    let loopback = Loopback::new(&[REPLAY_SOURCE_LPX, REPLAY_SINK_LPX, REPLAY_SINK_SYNTH]);
+    let transport: Arc<dyn Transport> = match replay {
+        Some(_) => Arc::new(loopback.clone()),
+        None => Arc::new(Midir),
+    };
+
     // First argument is the config file name.  Next the root
     // note.  The rest of the arguments is scale
     let mut iter = args.iter();
-    let cfg_fn = iter.nth(1).unwrap().as_str();
-    let root_note_iv = iter.nth(0).unwrap().as_str();
+    let device_names = match replay {
+        Some(_) => {
+            iter.nth(3);
+            DeviceNames::replay(client_name)
+        }
+        None => DeviceNames::new(iter.nth(1).unwrap().as_str(), client_name)?,
+    };
+    let root_note_iv = iter.next().unwrap().as_str();
     // eprintln!("Root note as text: {}", root_note_iv);
     root_note = root_note_iv.parse::<u8>()?;
 
@@ -218,133 +492,255 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
     scale = intermediate_value;
     // eprintln!(
-    //     "lpx_manager: config file: {} root note: {} scales: {:?}",
-    //     cfg_fn, root_note, scale
+    //     "lpx_manager: root note: {} scales: {:?}",
+    //     root_note, scale
     // );
 
-    let device_names = DeviceNames::new(cfg_fn).unwrap();
-    let midi_out_synth: MIDICommunicator<()> = MIDICommunicator::new(
-        device_names.midi_sink_synth.as_str(),
-        device_names.midi_sink_synth_120.as_str(),
-        |_, _, _| {},
-        (),
-        2,
-    )?;
+    // Every synthesiser plays each note.  The first has the usual
+    // client name, the others are numbered
+    let mut synths = FanOut::new();
+    for (i, spec) in device_names.midi_sink_synth.iter().enumerate() {
+        let (port, layer) = Layer::parse_sink(spec)?;
+        let client_name = match i {
+            0 => device_names.midi_sink_synth_120.clone(),
+            _ => format!("{}-{}", device_names.midi_sink_synth_120, i + 1),
+        };
+        let midi_out_synth = MIDICommunicator::builder(port.as_str(), client_name.as_str())
+            .direction(Direction::Output)
+            .transport(transport.clone())
+            .reconnect(true)
+            .tap(tap.as_ref())
+            .on_event(move |event| eprintln!("Synthesiser {}: {:?}", port, event))
+            .build()?;
+        synths.add(midi_out_synth, layer);
+    }
 
-    let midi_out_lpx: MIDICommunicator<()> = MIDICommunicator::new(
+    let midi_out_lpx: MIDICommunicator = MIDICommunicator::builder(
         device_names.midi_sink_lpx.as_str(),
         device_names.midi_sink_lpx_120.as_str(),
-        |_, _, _| {},
-        (),
-        2,
-    )?;
+    )
+    .direction(Direction::Output)
+    .transport(transport.clone())
+    .reconnect(true)
+    .tap(tap.as_ref())
+    .build()?;
+
+    // The pad colours have a connection of their own, so messages
+    // passed back to the LPX never wait behind them.  Its events are
+    // passed to the main thread, that repaints the pads when the LPX
+    // is plugged back in
+    let (lpx_event_tx, lpx_event_rx) = crossbeam_channel::unbounded::<ConnectionEvent>();
+    let midi_out_leds: MIDICommunicator = MIDICommunicator::builder(
+        device_names.midi_sink_lpx.as_str(),
+        format!("{}-LEDs", device_names.midi_sink_lpx_120).as_str(),
+    )
+    .direction(Direction::Output)
+    .transport(transport.clone())
+    .reconnect(true)
+    .tap(tap.as_ref())
+    .on_event(move |event| {
+        let _ = lpx_event_tx.send(event);
+    })
+    .build()?;
+
+    // What is dropped last is done last.  On the way out the input
+    // is closed first, then held notes are turned off, then the
+    // colours waiting are sent, and then the LPX is put back
+    let mut lpx_restore = LpxRestore::new(midi_out_lpx.clone(), on_exit);
+    if on_exit == OnExit::Restore {
+        remember_layout(&mut lpx_restore, &device_names, transport.clone());
+    }
+    let leds = LedPipeline::new(midi_out_leds);
+    // To wait for the colours when replaying
+    let replay_leds = leds.clone();
+    let _notes_off = synths.release_on_drop();
+
+    let latency = match measure {
+        Measure::Off => None,
+        _ => Some(Latency::new()),
+    };
+    let mut adapter = Adapter::new(
+        synths,
+        midi_out_lpx,
+        leds,
+        latency.clone(),
+        &scale,
+        root_note,
+    );
 
-    let mut adapter = Adapter::new(midi_out_synth, midi_out_lpx, &scale, root_note);
     // Initialise LPX colours
-    for i in 11..90 {
-        if i % 10 > 0 && i % 10 < 9 {
-            // let ten_millis = Duration::from_millis(100);
-            // thread::sleep(ten_millis);
-
-            let colour = adapter.pad_colour(i as u8).unwrap();
-            let out_message_colour_change: [u8; 11] = [240, 0, 32, 41, 2, 12, 3, 0, i, colour, 247];
-
-            match adapter.midi_out_lpx.send(&out_message_colour_change) {
-                Ok(()) => {
-                    // eprintln!(
-                    //     "Colour: {} Pad: {} Sent: {:?}",
-                    //     &colour, i, &out_message_colour_change
-                    // )
-                }
-                Err(err) => eprintln!("Initialising colours: Failed send: {:?}", err),
-            };
-        }
-    }
+    adapter.show();
+
+    // The adapter belongs to the callback.  The main thread only
+    // asks it to paint the LPX again if it is replugged
+    let repaint = adapter.repaint.clone();
 
     // The process that listens
 
-    let _midi_in: MIDICommunicator<Adapter> = MIDICommunicator::new(
+    let _midi_in: MIDICommunicator = MIDICommunicator::builder(
         device_names.midi_source_lpx.as_str(),
         device_names.midi_source_lpx_120.as_str(),
-        |_stamp, message, adapter| {
-            // eprintln!("midi_in stamp({:?}) message({:?})", &_stamp, &message);
-
-            let pad_in = message[1];
-            let velocity = message[2];
-            // eprintln!("pad_in({}) velocity({})", pad_in, velocity);
-            // Send note to synthesiser
-            match message[0] {
-                144 => {
-                    if pad_in % 10 > 0 && pad_in % 10 < 9 {
-                        // A key press, adapt it (translate the position
-                        // on the LPX represented by `pad_in` into a MIDI
-                        // note)
-                        let midi_note_out: u8 = adapter.adapt(pad_in);
-                        let out_message_midi_note = [144, midi_note_out, velocity];
-                        // eprintln!("out_message_midi_note({:?})", &out_message_midi_note);
-                        match adapter.midi_out_synth.send(&out_message_midi_note) {
-                            Ok(()) => (),
-                            Err(err) => eprintln!("Sending note: Failed send: {:?}", err),
-                        };
-
-                        // The key that is pressed, flash it blue(50) as it is
-                        // pressed.  It's standard colour otherwise
-                        let pad_colour: u8 = match velocity {
-                            0 =>
-                            // Key up.  Return to unpressed colour
-                            {
-                                adapter.pad_colour(pad_in).unwrap() // Safe as pad_in is filtered
-                            }
-                            _ => 50,
-                        };
-
-                        // There are possibly two pads to adjust colour of
-                        let pads = adapter.midi_note_to_pads[midi_note_out as usize];
-                        if let Some(p) = pads.0 {
-                            let out_message_colour_change: [u8; 11] =
-                                [240, 0, 32, 41, 2, 12, 3, 0, p, pad_colour, 247];
-                            // eprintln!(
-                            //     "out_message_colour_change({:?})",
-                            //     &out_message_colour_change
-                            // );
-                            match adapter.midi_out_lpx.send(&out_message_colour_change) {
-                                Ok(()) => (),
-                                Err(err) => {
-                                    eprintln!("Press colour change: Failed send: {:?}", err)
-                                }
-                            };
-                        }
-                        if let Some(p) = pads.1 {
-                            let out_message_colour_change: [u8; 11] =
-                                [240, 0, 32, 41, 2, 12, 3, 0, p, pad_colour, 247];
-                            // eprintln!(
-                            //     "out_message_colour_change({:?})",
-                            //     &out_message_colour_change
-                            // );
-                            match adapter.midi_out_lpx.send(&out_message_colour_change) {
-                                Ok(()) => (),
-                                Err(err) => {
-                                    eprintln!("Press colour change(2) : Failed send: {:?}", err)
-                                }
-                            };
-                        }
+    )
+    .direction(Direction::Input)
+    .transport(transport.clone())
+    .reconnect(true)
+    .tap(tap.as_ref())
+    .on_event(|event| eprintln!("LPX input: {:?}", event))
+    .callback(from_lpx, adapter)
+    .build()?;
+
+    // The connections in the routing file run beside the adapter
+    let _router = match device_names.routes.as_ref() {
+        Some(routes) => Some(Router::start_with(
+            routes,
+            device_names.client_name.as_str(),
+            transport.clone(),
+        )?),
+        None => None,
+    };
+
+    // With `--latency-loopback PORT` the notes sent to the
+    // synthesiser come back in on `PORT`, to time the round trip
+    let _midi_loopback = match (&measure, latency.as_ref()) {
+        (Measure::RoundTrip(port), Some(latency)) => Some(
+            MIDICommunicator::builder(
+                port.as_str(),
+                format!("{}-Latency", device_names.midi_sink_synth_120).as_str(),
+            )
+            .direction(Direction::Input)
+            .transport(transport.clone())
+            .reconnect(true)
+            .on_event(|event| eprintln!("Latency loopback: {:?}", event))
+            .callback(
+                |stamp, message, (latency, stamps): &mut (Latency, StampClock)| {
+                    if let Ok(MidiMessage::NoteOn { note, .. }) = MidiMessage::decode(message) {
+                        let arrived = latency.arrived(stamps, stamp);
+                        latency.returned(arrived, note);
                     }
-                }
-                _ => match adapter.midi_out_lpx.send(&[message[0], pad_in, velocity]) {
-                    Ok(()) => (),
-                    Err(err) => eprintln!("Random message(?): Failed send: {:?}", err),
                 },
-            };
-        },
-        adapter,
-        1,
-    )?;
+                (latency.clone(), StampClock::new()),
+            )
+            .build()?,
+        ),
+        _ => None,
+    };
+
+    // The measurements so far are written out now and then, and when
+    // stopped
+    let report = match latency {
+        Some(_) => crossbeam_channel::tick(Duration::from_secs(10)),
+        None => crossbeam_channel::never(),
+    };
+    let mut reported = 0;
+    let report_latency = |reported: &mut u64| {
+        if let Some(latency) = latency.as_ref() {
+            if latency.adapter().count() > *reported {
+                *reported = latency.adapter().count();
+                eprint!("{}", latency);
+            }
+        }
+    };
+
+    if let Some((session, golden)) = replay {
+        let replayed = replay_session(
+            &loopback,
+            &replay_leds,
+            Path::new(session),
+            Path::new(golden),
+            bless,
+        );
+        report_latency(&mut reported);
+        return replayed.map(|_| None);
+    }
 
-    // let mut input: String = String::new();
-    // input.clear();
-    // stdin().read_line(&mut input)?; // wait for next enter key press
+    // Wait for the LPX to be replugged.  Its colours are lost so
+    // paint them again.  Until a signal to stop
     loop {
-        thread::sleep(Duration::from_millis(100));
+        select! {
+            recv(lpx_event_rx) -> event => match event {
+                Ok(event) => {
+                    eprintln!("LPX output: {:?}", event);
+                    if let ConnectionEvent::Connected { .. } = event {
+                        repaint.store(true, Ordering::Relaxed);
+                    }
+                }
+                Err(_) => return Ok(None),
+            },
+            recv(report) -> _ => report_latency(&mut reported),
+            recv(shutdown.receiver()) -> signal => {
+                report_latency(&mut reported);
+                return Ok(signal.ok());
+            }
+        }
+    }
+}
+
+fn main() {
+    process::exit(match run() {
+        Ok(None) => 0,
+        Ok(Some(signal)) => exit_status(signal),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            1
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The recorded session and its golden output, in `testdata`
+    fn testdata(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    /// Replay `session` through an adapter playing the scale of the
+    /// golden file
+    fn replay(session: &Path, golden: &Path) -> Result<(), Box<dyn Error>> {
+        let loopback = Loopback::new(&[REPLAY_SOURCE_LPX, REPLAY_SINK_LPX, REPLAY_SINK_SYNTH]);
+        let output = |port: &str, client_name: &str| {
+            MIDICommunicator::builder(port, client_name)
+                .direction(Direction::Output)
+                .transport(Arc::new(loopback.clone()))
+                .build()
+        };
+        let mut synths = FanOut::new();
+        synths.add(
+            output(REPLAY_SINK_SYNTH, "120-Proof-Test-Synth")?,
+            Layer::default(),
+        );
+        let midi_out_lpx = output(REPLAY_SINK_LPX, "120-Proof-Test-LPX-Out")?;
+        let leds = LedPipeline::new(output(REPLAY_SINK_LPX, "120-Proof-Test-LEDs")?);
+        let mut adapter = Adapter::new(
+            synths,
+            midi_out_lpx,
+            leds.clone(),
+            None,
+            &vec![1, 3, 5, 6, 8, 10, 12],
+            60,
+        );
+        adapter.show();
+        let _midi_in = MIDICommunicator::builder(REPLAY_SOURCE_LPX, "120-Proof-Test-LPX-In")
+            .direction(Direction::Input)
+            .transport(Arc::new(loopback.clone()))
+            .callback(from_lpx, adapter)
+            .build()?;
+        replay_session(&loopback, &leds, session, golden, false)
+    }
+
+    #[test]
+    fn replays_the_golden_session() {
+        replay(&testdata("session.txt"), &testdata("session.golden")).unwrap();
+    }
+
+    #[test]
+    fn golden_file_must_exist() {
+        let golden = std::env::temp_dir().join(format!("lpx-golden-{}", std::process::id()));
+        assert!(replay(&testdata("session.txt"), &golden).is_err());
+        // It is only written with `--bless`
+        assert!(!golden.exists());
     }
-    // Ok(())
 }
@@ -7,36 +7,58 @@
 /// 06h (6): Custom mode 3 (Lighting mode in Drum Rack layout by factory default)
 /// 07h (7): Custom mode 4 (Lighting mode in Session layout by factory default)
 /// 0Dh (13): DAW Faders (only selectable in DAW mode) 7Fh (127): Programmer mode
-use midi_connection::MIDICommunicator;
+use lpx_protocol::{select_layout, Layout};
+use midi_connection::{
+    client_name_from_args, default_client_name, Direction, LpxArgs,
+    MIDICommunicator,
+};
 
 use std::env;
 use std::error::Error;
 // use std::thread;
 // use std::time;
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut midi_communicator1 = MIDICommunicator::new(
-        "Launchpad X:Launchpad X MIDI 1",
-        "120-Proof-1",
-        |_, _, _| {},
-        (),
-        3,
-    )?;
-    // This is the MIDI message that puts the LPX into programmer's
-    // mode.
+    // Mode is the first and only argument, after `--client NAME`,
+    // and `--lpx-out PORT` to choose the LPX
+    let mut args: Vec<String> = env::args().collect();
+    let client_name =
+        client_name_from_args(&mut args)?.unwrap_or_else(default_client_name);
+    // The LPX is only looked for once there is a mode to send it
+    let lpx = LpxArgs::from_args(&mut args)?;
 
-    // Mode is the first and only argument
-    let args: Vec<String> = env::args().collect();
     if args.len() == 1 || args.len() > 2 {
         // No args or too many args
-        println!("Usage:\n\t{} <mode>\n<mode> in: \n 00h (0): Session (only selectable in DAW mode)\n 01h (1): Note mode\n 04h (4): Custom mode 1 (Drum Rack by factory default)\n 05h (5): Custom mode 2 (Keys by factory default)\n 06h (6): Custom mode 3 (Lighting mode in Drum Rack layout by factory default)
-\n 07h (7): Custom mode 4 (Lighting mode in Session layout by factory default)\n 0Dh (13): DAW Faders (only selectable in DAW mode) 7Fh (127): Programmer mode\n", args[0]);
+        println!(
+            "Usage:\n\t{} [--client NAME] [--lpx-out PORT] <mode>\n<mode> in: ",
+            args[0]
+        );
+        for layout in Layout::ALL {
+            println!(
+                " {:02X}h ({}): {}",
+                layout.number(),
+                layout.number(),
+                layout
+            );
+        }
     } else {
         assert!(args.len() == 2);
         let mode: &str = &args[1];
-        match mode.parse() {
-            Ok(mode) => {
-                let msg: [u8; 9] = [240, 0, 32, 41, 2, 12, 0, mode, 247];
-                midi_communicator1.send(&msg).unwrap()
+        match mode.parse::<u8>() {
+            Ok(mode) => match Layout::try_from(mode) {
+                Ok(layout) => {
+                    let lpx = lpx.find(client_name.as_str())?;
+                    let mut midi_communicator1 = MIDICommunicator::builder(
+                        lpx.daw_out.as_str(),
+                        format!("{}-LPX", client_name).as_str(),
+                    )
+                    .callback(|_, _, _| {}, ())
+                    .direction(Direction::Both)
+                    .build()?;
+                    // This is the MIDI message that puts the LPX
+                    // into the layout
+                    midi_communicator1.send(&select_layout(layout))?
+                }
+                Err(err) => eprintln!("Mode {}: {}", mode, err),
             },
             Err(err) => eprintln!("Mode {}: {:?}", mode, err),
         };
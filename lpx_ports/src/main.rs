@@ -0,0 +1,291 @@
+//! Lists the MIDI ports, to help write `midi.cfg`
+//!
+//! Usage:
+//!     lpx_ports              A table of input and output ports
+//!     lpx_ports --json       The same as JSON
+//!     lpx_ports --cfg [FILE] Write a starter `midi.cfg` to FILE, or
+//!                            to stdout
+//! `--client NAME` sets the name it is known by to the MIDI system
+use midi_connection::{
+    alsa_id, client_name_from_args, default_client_name, lpx_interface, strip_alsa_id,
+    LpxInterface, Midir, Transport,
+};
+use std::env;
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::process;
+
+/// A MIDI port as reported by the system
+struct Port {
+    // The whole name, with the ALSA numbers if there are any
+    full_name: String,
+    alsa_id: Option<(u32, u32)>,
+    lpx: Option<LpxInterface>,
+}
+
+impl Port {
+    fn new(full_name: String) -> Self {
+        Port {
+            alsa_id: alsa_id(full_name.as_str()),
+            lpx: lpx_interface(full_name.as_str()),
+            full_name,
+        }
+    }
+
+    /// The name without ALSA numbers.  The numbers change when
+    /// devices are plugged in, the names do not
+    fn name(&self) -> &str {
+        strip_alsa_id(self.full_name.as_str())
+    }
+}
+
+/// `s` as a JSON string, with quotes
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(format!("\\u{:04x}", c as u32).as_str()),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// A JSON array of `ports`
+fn json_ports(ports: &[Port]) -> String {
+    let objects: Vec<String> = ports
+        .iter()
+        .map(|port| {
+            let (client, alsa_port) = match port.alsa_id {
+                Some((client, alsa_port)) => (client.to_string(), alsa_port.to_string()),
+                None => ("null".to_string(), "null".to_string()),
+            };
+            let lpx = match port.lpx {
+                Some(LpxInterface::Daw) => json_string("daw"),
+                Some(LpxInterface::Midi) => json_string("midi"),
+                None => "null".to_string(),
+            };
+            format!(
+                "    {{\"name\": {}, \"full_name\": {}, \"client\": {}, \"port\": {}, \"lpx\": {}}}",
+                json_string(port.name()),
+                json_string(port.full_name.as_str()),
+                client,
+                alsa_port,
+                lpx
+            )
+        })
+        .collect();
+    if objects.is_empty() {
+        "[]".to_string()
+    } else {
+        format!("[\n{}\n  ]", objects.join(",\n"))
+    }
+}
+
+fn print_table(title: &str, ports: &[Port]) {
+    println!("{}:", title);
+    for port in ports {
+        let id = match port.alsa_id {
+            Some((client, alsa_port)) => format!("{}:{}", client, alsa_port),
+            None => "-".to_string(),
+        };
+        let lpx = match port.lpx {
+            Some(interface) => format!("  (LPX {})", interface),
+            None => "".to_string(),
+        };
+        println!("  {:>7}  {}{}", id, port.name(), lpx);
+    }
+}
+
+/// The only port in `ports` that is the LPX `interface`
+fn find_lpx<'a>(
+    ports: &'a [Port],
+    interface: LpxInterface,
+    what: &str,
+) -> Result<&'a Port, String> {
+    let found: Vec<&Port> = ports.iter().filter(|p| p.lpx == Some(interface)).collect();
+    match found.as_slice() {
+        [port] => Ok(port),
+        [] => Err(format!(
+            "No LPX {} {} found.  Is it plugged in?",
+            interface, what
+        )),
+        _ => Err(format!(
+            "More than one LPX {} {}.  Choose one by hand: {:?}",
+            interface,
+            what,
+            found.iter().map(|p| p.name()).collect::<Vec<&str>>()
+        )),
+    }
+}
+
+/// A `midi.cfg` for `lpx_manager` and `lpx_scale`.  The synthesiser
+/// is a guess, the first output that is not the LPX
+fn starter_cfg(inputs: &[Port], outputs: &[Port]) -> Result<String, String> {
+    let source_lpx = find_lpx(inputs, LpxInterface::Midi, "input")?;
+    let sink_lpx = find_lpx(outputs, LpxInterface::Daw, "output")?;
+    let synth = outputs
+        .iter()
+        .filter(|p| p.lpx.is_none())
+        .find(|p| !p.name().starts_with("Midi Through"))
+        .or_else(|| outputs.iter().find(|p| p.lpx.is_none()))
+        .ok_or("No output for the synthesiser")?;
+    eprintln!(
+        "Using \"{}\" for the synthesiser.  Edit midi_sink_synth if that is wrong",
+        synth.name()
+    );
+    Ok(format!(
+        "midi_source_lpx:{}\nmidi_sink_lpx:{}\nmidi_sink_synth:{}\n",
+        source_lpx.name(),
+        sink_lpx.name(),
+        synth.name()
+    ))
+}
+
+/// The input and output ports, listed as `client_name`
+fn list_ports(client_name: &str) -> Result<(Vec<Port>, Vec<Port>), Box<dyn Error>> {
+    let inputs: Vec<Port> = Midir
+        .input_ports(client_name)?
+        .into_iter()
+        .map(Port::new)
+        .collect();
+    let outputs: Vec<Port> = Midir
+        .output_ports(client_name)?
+        .into_iter()
+        .map(Port::new)
+        .collect();
+    Ok((inputs, outputs))
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "Usage:\n\t{} [--client NAME] [--json | --cfg [FILE]]\n\
+         List the MIDI ports.  --json prints JSON.  --cfg writes a starter midi.cfg to FILE, or stdout",
+        program
+    );
+    process::exit(2);
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args: Vec<String> = env::args().collect();
+    let client_name = client_name_from_args(&mut args)?.unwrap_or_else(default_client_name);
+
+    // The arguments are checked before the MIDI system is asked
+    match args.get(1).map(|a| a.as_str()) {
+        None => {
+            let (inputs, outputs) = list_ports(client_name.as_str())?;
+            print_table("Inputs", &inputs);
+            print_table("Outputs", &outputs);
+        }
+        Some("--json") if args.len() == 2 => {
+            let (inputs, outputs) = list_ports(client_name.as_str())?;
+            println!(
+                "{{\n  \"inputs\": {},\n  \"outputs\": {}\n}}",
+                json_ports(&inputs),
+                json_ports(&outputs)
+            );
+        }
+        Some("--cfg") if args.len() <= 3 => {
+            let (inputs, outputs) = list_ports(client_name.as_str())?;
+            let cfg = match starter_cfg(&inputs, &outputs) {
+                Ok(cfg) => cfg,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            };
+            match args.get(2) {
+                // Never overwrite a configuration that may have been
+                // edited by hand
+                Some(path) => OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(path)
+                    .map_err(|err| format!("{}: {}", path, err))?
+                    .write_all(cfg.as_bytes())?,
+                None => io::stdout().write_all(cfg.as_bytes())?,
+            }
+        }
+        _ => usage(args[0].as_str()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ports(names: &[&str]) -> Vec<Port> {
+        names
+            .iter()
+            .map(|name| Port::new(name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_string("Synth"), "\"Synth\"");
+        assert_eq!(json_string("a \"b\" c\\d"), "\"a \\\"b\\\" c\\\\d\"");
+        assert_eq!(
+            json_string("1\n2\t3\u{1}4\u{1f}"),
+            "\"1\\n2\\t3\\u00014\\u001f\""
+        );
+    }
+
+    #[test]
+    fn json_port_list() {
+        assert_eq!(json_ports(&[]), "[]");
+        assert_eq!(
+            json_ports(&ports(&["Launchpad X:Launchpad X MIDI 1 24:0", "Yoshimi"])),
+            "[\n    \
+             {\"name\": \"Launchpad X:Launchpad X MIDI 1\", \
+             \"full_name\": \"Launchpad X:Launchpad X MIDI 1 24:0\", \
+             \"client\": 24, \"port\": 0, \"lpx\": \"daw\"},\n    \
+             {\"name\": \"Yoshimi\", \"full_name\": \"Yoshimi\", \
+             \"client\": null, \"port\": null, \"lpx\": null}\n  ]"
+        );
+    }
+
+    #[test]
+    fn starter_configuration() {
+        let inputs = ports(&[
+            "Midi Through:Midi Through Port-0 14:0",
+            "Launchpad X:Launchpad X MIDI 1 24:0",
+            "Launchpad X:Launchpad X MIDI 2 24:1",
+        ]);
+        let outputs = ports(&[
+            "Midi Through:Midi Through Port-0 14:0",
+            "Launchpad X:Launchpad X MIDI 1 24:0",
+            "Launchpad X:Launchpad X MIDI 2 24:1",
+            "yoshimi:input 128:0",
+        ]);
+        assert_eq!(
+            starter_cfg(&inputs, &outputs).unwrap(),
+            "midi_source_lpx:Launchpad X:Launchpad X MIDI 2\n\
+             midi_sink_lpx:Launchpad X:Launchpad X MIDI 1\n\
+             midi_sink_synth:yoshimi:input\n"
+        );
+
+        // Midi Through only if there is nothing else
+        let cfg = starter_cfg(&inputs, &outputs[..3]).unwrap();
+        assert!(cfg.ends_with("midi_sink_synth:Midi Through:Midi Through Port-0\n"));
+        assert!(starter_cfg(&inputs, &outputs[1..3]).is_err());
+
+        // Two LPXs cannot be told apart, and none is no use
+        let two = ports(&[
+            "Launchpad X:Launchpad X MIDI 2 24:1",
+            "Launchpad X:Launchpad X MIDI 2 28:1",
+        ]);
+        let err = starter_cfg(&two, &outputs).unwrap_err();
+        assert!(err.starts_with("More than one LPX"), "{}", err);
+        let err = starter_cfg(&inputs[..1], &outputs).unwrap_err();
+        assert!(err.starts_with("No LPX"), "{}", err);
+    }
+}
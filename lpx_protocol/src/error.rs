@@ -0,0 +1,34 @@
+use std::error::Error;
+use std::fmt;
+
+/// An argument to a command that the Launchpad X would not understand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Pads are numbered 11 to 99, by row then column, and no column
+    /// is 0
+    BadPad(u8),
+
+    /// Palette colours, and each of red, green and blue, are 0 to 127
+    BadColour(u8),
+
+    /// Not one of the layouts in `Layout`
+    BadLayout(u8),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::BadPad(pad) => {
+                write!(f, "There is no pad {} on the Launchpad X", pad)
+            }
+            ProtocolError::BadColour(colour) => {
+                write!(f, "Colour {} is not in 0 to 127", colour)
+            }
+            ProtocolError::BadLayout(layout) => {
+                write!(f, "{} is not a Launchpad X layout", layout)
+            }
+        }
+    }
+}
+
+impl Error for ProtocolError {}
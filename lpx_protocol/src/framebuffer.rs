@@ -0,0 +1,253 @@
+//! What the Launchpad X's pads should show, and what they have been
+//! sent.  Colours are drawn into layers: the layout in `Base`, the
+//! state of the control strip in `Controls`, and pads being pressed in
+//! `Pressed`, over the rest.  A pad shows the colour of the highest
+//! layer that has drawn it.
+//!
+//! `Framebuffer::flush` gives only the pads that have changed since
+//! the last flush, so the code that colours pads can say what they
+//! should be and leave it to the framebuffer to work out what to send.
+//! Pads that no layer has drawn are never sent, so two programmes can
+//! share the LPX if they draw different pads.
+use crate::{Lighting, Pad, ProtocolError};
+
+/// Pad numbers are below this
+const PADS: usize = 100;
+
+/// The layers, each drawn over the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameLayer {
+    /// The layout: the colour a pad has when nothing else is going on
+    Base,
+    /// Whether the control pads are enabled
+    Controls,
+    /// Pads being pressed, or in use
+    Pressed,
+}
+
+impl FrameLayer {
+    pub const ALL: [FrameLayer; 3] = [FrameLayer::Base, FrameLayer::Controls, FrameLayer::Pressed];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    // Each layer's lighting, by pad number
+    layers: [[Option<Lighting>; PADS]; FrameLayer::ALL.len()],
+    // The lighting last sent to each pad.  `None` if not known
+    sent: [Option<Lighting>; PADS],
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Framebuffer {
+            layers: [[None; PADS]; FrameLayer::ALL.len()],
+            sent: [None; PADS],
+        }
+    }
+}
+
+impl Framebuffer {
+    pub fn new() -> Framebuffer {
+        Framebuffer::default()
+    }
+
+    /// Draw `pad` in `layer` with `lighting`.  Its palette colours
+    /// must be 0 to 127, and its red, green and blue are clamped to
+    /// that
+    pub fn draw(
+        &mut self,
+        layer: FrameLayer,
+        pad: Pad,
+        lighting: Lighting,
+    ) -> Result<(), ProtocolError> {
+        self.layers[layer.index()][pad.number() as usize] = Some(lighting.checked()?);
+        Ok(())
+    }
+
+    /// Take `pad` out of `layer`, so the layers below it show
+    pub fn erase(&mut self, layer: FrameLayer, pad: Pad) {
+        self.layers[layer.index()][pad.number() as usize] = None;
+    }
+
+    /// Take every pad out of `layer`
+    pub fn erase_layer(&mut self, layer: FrameLayer) {
+        self.layers[layer.index()] = [None; PADS];
+    }
+
+    /// How `pad` should be lit, from the highest layer that has
+    /// drawn it.  `None` if no layer has
+    pub fn lighting(&self, pad: Pad) -> Option<Lighting> {
+        self.lighting_at(pad.number() as usize)
+    }
+
+    fn lighting_at(&self, pad: usize) -> Option<Lighting> {
+        self.layers.iter().rev().find_map(|layer| layer[pad])
+    }
+
+    /// Every pad that is drawn, and how it should be lit, as pairs of
+    /// pad number and lighting
+    pub fn lights(&self) -> impl Iterator<Item = (u8, Lighting)> + '_ {
+        (0..PADS).filter_map(|pad| self.lighting_at(pad).map(|lighting| (pad as u8, lighting)))
+    }
+
+    /// `pad` has been lit some other way, so send it on the next
+    /// flush whether or not it has changed
+    pub fn forget(&mut self, pad: Pad) {
+        self.sent[pad.number() as usize] = None;
+    }
+
+    /// The LPX has lost its colours (it has been plugged back in), so
+    /// send every pad that is drawn on the next flush
+    pub fn forget_all(&mut self) {
+        self.sent = [None; PADS];
+    }
+
+    /// The pads that have changed since the last flush, as pairs of
+    /// pad number and lighting, for an `LedMessage` or an
+    /// `LedPipeline`.
+    /// A pad that has been erased from every layer is turned off.
+    /// Each pad is taken as sent as it comes out of the iterator
+    pub fn flush(&mut self) -> Flush<'_> {
+        Flush {
+            frame: self,
+            pad: 0,
+        }
+    }
+}
+
+/// The changes from `Framebuffer::flush`
+pub struct Flush<'a> {
+    frame: &'a mut Framebuffer,
+    pad: usize,
+}
+
+impl Iterator for Flush<'_> {
+    type Item = (u8, Lighting);
+    fn next(&mut self) -> Option<(u8, Lighting)> {
+        while self.pad < PADS {
+            let pad = self.pad;
+            self.pad += 1;
+            let lighting = match (self.frame.lighting_at(pad), self.frame.sent[pad]) {
+                (Some(lighting), sent) if sent != Some(lighting) => lighting,
+                // Erased, so off
+                (None, Some(sent)) if sent != Lighting::OFF => Lighting::OFF,
+                _ => continue,
+            };
+            self.frame.sent[pad] = Some(lighting);
+            return Some((pad as u8, lighting));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Lighting::Static;
+
+    #[test]
+    fn layers_and_flush() {
+        let pad = |number| Pad::try_from(number).unwrap();
+        let mut frame = Framebuffer::new();
+        for grid in Pad::grid() {
+            frame.draw(FrameLayer::Base, grid, Static(17)).unwrap();
+        }
+        frame.draw(FrameLayer::Base, pad(11), Static(5)).unwrap();
+        assert_eq!(frame.lights().count(), 64);
+        let first: Vec<(u8, Lighting)> = frame.flush().collect();
+        assert_eq!(first.len(), 64);
+        assert_eq!(first[0], (11, Static(5)));
+        // Nothing has changed
+        assert_eq!(frame.flush().next(), None);
+
+        // Pressed over the layout, then released back to it
+        frame
+            .draw(FrameLayer::Pressed, pad(12), Static(50))
+            .unwrap();
+        frame.draw(FrameLayer::Pressed, pad(11), Static(5)).unwrap();
+        assert_eq!(frame.lighting(pad(12)), Some(Static(50)));
+        assert_eq!(frame.flush().collect::<Vec<_>>(), vec![(12, Static(50))]);
+        frame.erase_layer(FrameLayer::Pressed);
+        assert_eq!(frame.flush().collect::<Vec<_>>(), vec![(12, Static(17))]);
+
+        // Controls go between the two
+        frame
+            .draw(FrameLayer::Controls, pad(19), Static(87))
+            .unwrap();
+        frame
+            .draw(FrameLayer::Pressed, pad(19), Static(67))
+            .unwrap();
+        frame
+            .draw(FrameLayer::Controls, pad(29), Static(87))
+            .unwrap();
+        frame
+            .draw(FrameLayer::Controls, pad(29), Static(5))
+            .unwrap();
+        assert_eq!(
+            frame.flush().collect::<Vec<_>>(),
+            vec![(19, Static(67)), (29, Static(5))]
+        );
+
+        // Another lighting type in the same colour is a change
+        frame
+            .draw(FrameLayer::Pressed, pad(19), Lighting::Pulse(67))
+            .unwrap();
+        frame
+            .draw(FrameLayer::Pressed, pad(29), Lighting::Flash(5, 87))
+            .unwrap();
+        assert_eq!(
+            frame.flush().collect::<Vec<_>>(),
+            vec![(19, Lighting::Pulse(67)), (29, Lighting::Flash(5, 87))]
+        );
+        frame.erase(FrameLayer::Pressed, pad(29));
+
+        // Erased from every layer is off, once
+        frame.erase(FrameLayer::Controls, pad(29));
+        assert_eq!(frame.lighting(pad(29)), None);
+        assert_eq!(frame.flush().collect::<Vec<_>>(), vec![(29, Static(0))]);
+        assert_eq!(frame.flush().next(), None);
+
+        assert_eq!(
+            frame.draw(FrameLayer::Base, pad(11), Static(128)),
+            Err(ProtocolError::BadColour(128))
+        );
+        assert_eq!(frame.lighting(pad(11)), Some(Static(5)));
+        frame
+            .draw(FrameLayer::Base, pad(13), Lighting::Rgb(200, 0, 64))
+            .unwrap();
+        assert_eq!(frame.lighting(pad(13)), Some(Lighting::Rgb(127, 0, 64)));
+    }
+
+    #[test]
+    fn forgetting() {
+        let pad = |number| Pad::try_from(number).unwrap();
+        let mut frame = Framebuffer::new();
+        frame.draw(FrameLayer::Base, pad(11), Static(5)).unwrap();
+        frame.draw(FrameLayer::Base, pad(12), Static(17)).unwrap();
+        assert_eq!(frame.flush().count(), 2);
+
+        frame.forget(pad(12));
+        assert_eq!(frame.flush().collect::<Vec<_>>(), vec![(12, Static(17))]);
+
+        // A pad not drawn is not sent, even after the LPX is replugged
+        frame.forget_all();
+        assert_eq!(
+            frame.flush().collect::<Vec<_>>(),
+            vec![(11, Static(5)), (12, Static(17))]
+        );
+
+        // What is not taken from the iterator is still to be sent
+        frame
+            .draw(FrameLayer::Pressed, pad(11), Static(50))
+            .unwrap();
+        frame
+            .draw(FrameLayer::Pressed, pad(12), Static(50))
+            .unwrap();
+        assert_eq!(frame.flush().next(), Some((11, Static(50))));
+        assert_eq!(frame.flush().collect::<Vec<_>>(), vec![(12, Static(50))]);
+    }
+}
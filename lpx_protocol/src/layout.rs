@@ -0,0 +1,124 @@
+//! The layout the Launchpad X's grid is in (page seven of the
+//! Programmer's Reference)
+use crate::{sysex, sysex_data, ProtocolError, LAYOUT_COMMAND};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Only selectable in DAW mode
+    Session,
+    Note,
+    /// Drum Rack by factory default
+    Custom1,
+    /// Keys by factory default
+    Custom2,
+    /// Lighting mode in Drum Rack layout by factory default
+    Custom3,
+    /// Lighting mode in Session layout by factory default
+    Custom4,
+    /// Only selectable in DAW mode
+    DawFaders,
+    Programmer,
+}
+
+impl Layout {
+    /// Every layout, in the order of their numbers
+    pub const ALL: [Layout; 8] = [
+        Layout::Session,
+        Layout::Note,
+        Layout::Custom1,
+        Layout::Custom2,
+        Layout::Custom3,
+        Layout::Custom4,
+        Layout::DawFaders,
+        Layout::Programmer,
+    ];
+
+    /// The number the LPX knows it by
+    pub fn number(self) -> u8 {
+        match self {
+            Layout::Session => 0x00,
+            Layout::Note => 0x01,
+            Layout::Custom1 => 0x04,
+            Layout::Custom2 => 0x05,
+            Layout::Custom3 => 0x06,
+            Layout::Custom4 => 0x07,
+            Layout::DawFaders => 0x0D,
+            Layout::Programmer => 0x7F,
+        }
+    }
+}
+
+impl TryFrom<u8> for Layout {
+    type Error = ProtocolError;
+    fn try_from(number: u8) -> Result<Self, Self::Error> {
+        Layout::ALL
+            .into_iter()
+            .find(|layout| layout.number() == number)
+            .ok_or(ProtocolError::BadLayout(number))
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Layout::Session => "Session (only selectable in DAW mode)",
+            Layout::Note => "Note mode",
+            Layout::Custom1 => "Custom mode 1 (Drum Rack by factory default)",
+            Layout::Custom2 => "Custom mode 2 (Keys by factory default)",
+            Layout::Custom3 => {
+                "Custom mode 3 (Lighting mode in Drum Rack layout by factory default)"
+            }
+            Layout::Custom4 => "Custom mode 4 (Lighting mode in Session layout by factory default)",
+            Layout::DawFaders => "DAW Faders (only selectable in DAW mode)",
+            Layout::Programmer => "Programmer mode",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Put the LPX in `layout`
+pub fn select_layout(layout: Layout) -> Vec<u8> {
+    sysex(LAYOUT_COMMAND, &[layout.number()])
+}
+
+/// Ask the LPX what layout it is in.  It answers with a message that
+/// `layout_reply` reads
+pub fn query_layout() -> Vec<u8> {
+    sysex(LAYOUT_COMMAND, &[])
+}
+
+/// The layout in the LPX's answer to `query_layout`.  `None` if
+/// `bytes` is something else
+pub fn layout_reply(bytes: &[u8]) -> Option<Layout> {
+    match sysex_data(LAYOUT_COMMAND, bytes) {
+        Some(&[layout]) => Layout::try_from(layout).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts() {
+        assert_eq!(
+            select_layout(Layout::Programmer),
+            vec![240, 0, 32, 41, 2, 12, 0, 127, 247]
+        );
+        assert_eq!(query_layout(), vec![240, 0, 32, 41, 2, 12, 0, 247]);
+        assert_eq!(Layout::try_from(5), Ok(Layout::Custom2));
+        assert_eq!(Layout::try_from(2), Err(ProtocolError::BadLayout(2)));
+        for layout in Layout::ALL {
+            assert_eq!(layout_reply(&select_layout(layout)), Some(layout));
+        }
+        // Not a layout reply
+        assert_eq!(
+            layout_reply(&[240, 0, 32, 41, 2, 12, 3, 0, 11, 5, 247]),
+            None
+        );
+        assert_eq!(layout_reply(&[240, 126, 127, 6, 2, 247]), None);
+        assert_eq!(layout_reply(&[240, 0, 32, 41, 2, 12, 0, 2, 247]), None);
+    }
+}
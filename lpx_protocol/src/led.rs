@@ -0,0 +1,296 @@
+//! Lighting pads (page twelve of the Programmer's Reference).  One
+//! LED message (command 03h) is a list of colour specs, each a
+//! lighting type, a pad and its colour.  Specs of different types can
+//! be mixed, and one message can light every pad on the surface.
+use crate::{sysex, Pad, ProtocolError, LED_COMMAND};
+
+/// The most colour specs in one message, one for each pad
+pub const MAX_SPECS: usize = 81;
+
+/// The lighting types
+const STATIC: u8 = 0;
+const FLASH: u8 = 1;
+const PULSE: u8 = 2;
+const RGB: u8 = 3;
+
+/// The highest palette colour, and the brightest red, green or blue
+const MAX_LEVEL: u8 = 0x7F;
+
+/// `pad` if it is on the LPX: 11 to 99, with no column 0.  See `Pad`
+pub fn check_pad(pad: u8) -> Result<u8, ProtocolError> {
+    Pad::try_from(pad).map(Pad::number)
+}
+
+/// `colour` if it is a palette colour: 0 to 127
+pub fn check_colour(colour: u8) -> Result<u8, ProtocolError> {
+    if colour <= MAX_LEVEL {
+        Ok(colour)
+    } else {
+        Err(ProtocolError::BadColour(colour))
+    }
+}
+
+/// How a pad is lit: the four lighting types.  Flashing and pulsing
+/// keep time with the MIDI clock sent to the LPX, or 120 BPM without
+/// one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lighting {
+    /// A palette colour
+    Static(u8),
+    /// Flashing from palette colour A to palette colour B and back
+    Flash(u8, u8),
+    /// Pulsing a palette colour, brighter and dimmer
+    Pulse(u8),
+    /// Red, green and blue, each 0 to 127.  Higher is taken as 127
+    Rgb(u8, u8, u8),
+}
+
+impl Lighting {
+    /// Not lit
+    pub const OFF: Lighting = Lighting::Static(0);
+
+    /// Red, green and blue, each clamped to 0 to 127
+    pub fn rgb(red: u8, green: u8, blue: u8) -> Lighting {
+        Lighting::Rgb(
+            red.min(MAX_LEVEL),
+            green.min(MAX_LEVEL),
+            blue.min(MAX_LEVEL),
+        )
+    }
+
+    /// The same lighting with its palette colours checked and its red,
+    /// green and blue clamped
+    pub fn checked(self) -> Result<Lighting, ProtocolError> {
+        Ok(match self {
+            Lighting::Static(colour) => Lighting::Static(check_colour(colour)?),
+            Lighting::Flash(a, b) => Lighting::Flash(check_colour(a)?, check_colour(b)?),
+            Lighting::Pulse(colour) => Lighting::Pulse(check_colour(colour)?),
+            Lighting::Rgb(red, green, blue) => Lighting::rgb(red, green, blue),
+        })
+    }
+
+    /// The colour spec for `pad`, on the end of `data`
+    fn spec(self, pad: u8, data: &mut Vec<u8>) {
+        match self {
+            Lighting::Static(colour) => data.extend_from_slice(&[STATIC, pad, colour]),
+            // Colour B comes first
+            Lighting::Flash(a, b) => data.extend_from_slice(&[FLASH, pad, b, a]),
+            Lighting::Pulse(colour) => data.extend_from_slice(&[PULSE, pad, colour]),
+            Lighting::Rgb(red, green, blue) => {
+                data.extend_from_slice(&[RGB, pad, red, green, blue])
+            }
+        }
+    }
+}
+
+/// Build an LED message a pad at a time:
+///
+/// ```
+/// # use lpx_protocol::{LedMessage, Lighting};
+/// let message = LedMessage::new()
+///     .colour(11, 5)?
+///     .light(12, Lighting::Flash(5, 17))?
+///     .rgb(13, 127, 0, 64)?
+///     .build();
+/// # Ok::<(), lpx_protocol::ProtocolError>(())
+/// ```
+///
+/// Lighting a pad that is already in the message replaces how it was
+/// lit, so there is never more than one spec for a pad and every pad
+/// fits in one message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LedMessage {
+    // Each pad's lighting, checked, in the order the pads were first
+    // lit
+    specs: Vec<(u8, Lighting)>,
+}
+
+impl LedMessage {
+    pub fn new() -> LedMessage {
+        LedMessage::default()
+    }
+
+    /// Light `pad` with `lighting`.  Its palette colours must be 0 to
+    /// 127, and its red, green and blue are clamped to that
+    pub fn light(&mut self, pad: u8, lighting: Lighting) -> Result<&mut Self, ProtocolError> {
+        let pad = check_pad(pad)?;
+        let lighting = lighting.checked()?;
+        match self.specs.iter_mut().find(|(lit, _)| *lit == pad) {
+            Some((_, earlier)) => *earlier = lighting,
+            None => self.specs.push((pad, lighting)),
+        }
+        Ok(self)
+    }
+
+    /// Light each pad, from pairs of pad and lighting
+    pub fn lights<I>(&mut self, lights: I) -> Result<&mut Self, ProtocolError>
+    where
+        I: IntoIterator<Item = (u8, Lighting)>,
+    {
+        for (pad, lighting) in lights {
+            self.light(pad, lighting)?;
+        }
+        Ok(self)
+    }
+
+    /// Light `pad` with a palette `colour`
+    pub fn colour(&mut self, pad: u8, colour: u8) -> Result<&mut Self, ProtocolError> {
+        self.light(pad, Lighting::Static(colour))
+    }
+
+    /// Light each pad with its palette colour, from pairs of pad and
+    /// colour
+    pub fn colours<I>(&mut self, colours: I) -> Result<&mut Self, ProtocolError>
+    where
+        I: IntoIterator<Item = (u8, u8)>,
+    {
+        self.lights(
+            colours
+                .into_iter()
+                .map(|(pad, colour)| (pad, Lighting::Static(colour))),
+        )
+    }
+
+    /// Flash `pad` between palette colours `a` and `b`
+    pub fn flash(&mut self, pad: u8, a: u8, b: u8) -> Result<&mut Self, ProtocolError> {
+        self.light(pad, Lighting::Flash(a, b))
+    }
+
+    /// Pulse `pad` in a palette `colour`
+    pub fn pulse(&mut self, pad: u8, colour: u8) -> Result<&mut Self, ProtocolError> {
+        self.light(pad, Lighting::Pulse(colour))
+    }
+
+    /// Light `pad` with a mix of `red`, `green` and `blue`, each
+    /// clamped to 0 to 127
+    pub fn rgb(
+        &mut self,
+        pad: u8,
+        red: u8,
+        green: u8,
+        blue: u8,
+    ) -> Result<&mut Self, ProtocolError> {
+        self.light(pad, Lighting::Rgb(red, green, blue))
+    }
+
+    /// The number of pads lit
+    pub fn len(&self) -> usize {
+        self.specs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.specs.is_empty()
+    }
+
+    /// The SysEx message
+    pub fn build(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.specs.len() * 5);
+        for &(pad, lighting) in self.specs.iter() {
+            lighting.spec(pad, &mut data);
+        }
+        sysex(LED_COMMAND, &data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn led_messages() {
+        assert_eq!(
+            LedMessage::new().colour(11, 5).unwrap().build(),
+            vec![240, 0, 32, 41, 2, 12, 3, 0, 11, 5, 247]
+        );
+        assert_eq!(
+            LedMessage::new()
+                .pulse(19, 21)
+                .unwrap()
+                .rgb(99, 127, 0, 72)
+                .unwrap()
+                .build(),
+            vec![240, 0, 32, 41, 2, 12, 3, 2, 19, 21, 3, 99, 127, 0, 72, 247]
+        );
+        let mut message = LedMessage::new();
+        assert_eq!(
+            message.colour(100, 5).err(),
+            Some(ProtocolError::BadPad(100))
+        );
+        assert_eq!(message.colour(10, 5).err(), Some(ProtocolError::BadPad(10)));
+        assert_eq!(
+            message.colour(11, 200).err(),
+            Some(ProtocolError::BadColour(200))
+        );
+        assert_eq!(
+            message.flash(11, 5, 128).err(),
+            Some(ProtocolError::BadColour(128))
+        );
+        assert_eq!(
+            message.pulse(11, 255).err(),
+            Some(ProtocolError::BadColour(255))
+        );
+        // Nothing was added
+        assert!(message.is_empty());
+    }
+
+    #[test]
+    fn lighting_types() {
+        let pad = 45;
+        let spec = |lighting: Lighting| {
+            let bytes = LedMessage::new().light(pad, lighting).unwrap().build();
+            bytes[7..bytes.len() - 1].to_vec()
+        };
+        assert_eq!(spec(Lighting::Static(5)), vec![0, 45, 5]);
+        assert_eq!(spec(Lighting::OFF), vec![0, 45, 0]);
+        // Colour B first, then A
+        assert_eq!(spec(Lighting::Flash(5, 17)), vec![1, 45, 17, 5]);
+        assert_eq!(spec(Lighting::Pulse(87)), vec![2, 45, 87]);
+        assert_eq!(spec(Lighting::Rgb(1, 2, 3)), vec![3, 45, 1, 2, 3]);
+
+        // Red, green and blue are clamped, palette colours are not
+        assert_eq!(Lighting::rgb(200, 127, 128), Lighting::Rgb(127, 127, 127));
+        assert_eq!(spec(Lighting::Rgb(255, 0, 128)), vec![3, 45, 127, 0, 127]);
+        assert_eq!(
+            LedMessage::new().rgb(11, 0, 128, 0).unwrap().build()[7..12],
+            [3, 11, 0, 127, 0]
+        );
+        assert_eq!(
+            Lighting::Rgb(0, 200, 0).checked(),
+            Ok(Lighting::Rgb(0, 127, 0))
+        );
+        assert_eq!(
+            Lighting::Flash(128, 5).checked(),
+            Err(ProtocolError::BadColour(128))
+        );
+    }
+
+    #[test]
+    fn whole_surface() {
+        // Every pad, with all four types, in one message
+        let mut message = LedMessage::new();
+        message
+            .colours(Pad::grid().map(|pad| (pad.number(), 17)))
+            .unwrap();
+        for pad in Pad::top_row() {
+            message.flash(pad.number(), 5, 0).unwrap();
+        }
+        for pad in Pad::right_column() {
+            message.pulse(pad.number(), 87).unwrap();
+        }
+        message.rgb(99, 0, 0, 127).unwrap();
+        assert_eq!(message.len(), MAX_SPECS);
+        let bytes = message.build();
+        assert_eq!(bytes.len(), 8 + 64 * 3 + 8 * 4 + 8 * 3 + 5);
+        assert_eq!(&bytes[6..10], &[3, 0, 11, 17]);
+        assert!(bytes.windows(4).any(|spec| spec == [1, 91, 0, 5]));
+
+        // Lighting a pad again replaces it, in its place
+        message.colour(11, 5).unwrap();
+        assert_eq!(message.len(), MAX_SPECS);
+        assert_eq!(&message.build()[6..10], &[3, 0, 11, 5]);
+        assert_eq!(
+            message.colours([(11, 5), (100, 5)]).err(),
+            Some(ProtocolError::BadPad(100))
+        );
+    }
+}
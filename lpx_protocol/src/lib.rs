@@ -0,0 +1,73 @@
+//! The Launchpad X's SysEx commands, as Novation's Programmer's
+//! Reference describes them.  Each command we use has a builder that
+//! checks its arguments are in range (a pad that is on the LPX, a
+//! colour in the palette) and returns the bytes to send to the LPX's
+//! DAW port ("MIDI 1").
+//!
+//! * `select_layout`, `query_layout` and `layout_reply`: The layout
+//!   (Session, Note, Custom, Programmer...) (command 00h)
+//!
+//! * `LedMessage`: Light pads (command 03h), each with a `Lighting`:
+//!   static, flashing, pulsing or RGB
+//!
+//! * `programmer_mode`: Switch between Programmer and Live mode
+//!   (command 0Eh)
+//!
+//! * `daw_mode`: Switch DAW mode on or off (command 10h)
+//!
+//! `Pad` names the pads, and converts between their numbers in the
+//! different layouts.
+//!
+//! `Framebuffer` remembers what the pads should show, in layers, and
+//! what they have been sent, so only changes need sending.
+//!
+//! Nothing here sends MIDI.  That is left to `midi_connection`.
+mod error;
+mod framebuffer;
+mod layout;
+mod led;
+mod mode;
+mod pad;
+
+pub use error::ProtocolError;
+pub use framebuffer::{Flush, FrameLayer, Framebuffer};
+pub use layout::{layout_reply, query_layout, select_layout, Layout};
+pub use led::{check_colour, check_pad, LedMessage, Lighting, MAX_SPECS};
+pub use mode::{daw_mode, programmer_mode};
+pub use pad::{Pad, PadKind};
+
+/// The manufacturer (Novation) and device (Launchpad X) bytes that
+/// follow 0xF0 in every Launchpad X SysEx message
+pub const HEADER: [u8; 5] = [0x00, 0x20, 0x29, 0x02, 0x0C];
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+
+/// The commands, the byte after `HEADER`
+const LAYOUT_COMMAND: u8 = 0x00;
+const LED_COMMAND: u8 = 0x03;
+const PROGRAMMER_COMMAND: u8 = 0x0E;
+const DAW_COMMAND: u8 = 0x10;
+
+/// A Launchpad X SysEx message: `command` followed by `data`
+fn sysex(command: u8, data: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER.len() + data.len() + 3);
+    message.push(SYSEX_START);
+    message.extend_from_slice(&HEADER);
+    message.push(command);
+    message.extend_from_slice(data);
+    message.push(SYSEX_END);
+    message
+}
+
+/// The data of a Launchpad X SysEx message for `command`, if that is
+/// what `bytes` is
+fn sysex_data(command: u8, bytes: &[u8]) -> Option<&[u8]> {
+    match bytes {
+        [SYSEX_START, rest @ .., SYSEX_END] => match rest.strip_prefix(&HEADER) {
+            Some([c, data @ ..]) if *c == command => Some(data),
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,35 @@
+//! Programmer/Live mode and DAW mode (pages seven and eight of the
+//! Programmer's Reference)
+use crate::{sysex, DAW_COMMAND, PROGRAMMER_COMMAND};
+
+/// Put the LPX in Programmer mode, where every pad and button sends
+/// its own note or CC and is lit only by us.  `false` goes back to
+/// Live mode, the layout it was in before
+pub fn programmer_mode(on: bool) -> Vec<u8> {
+    sysex(PROGRAMMER_COMMAND, &[on as u8])
+}
+
+/// Turn DAW mode on, or off to go back to standalone.  The Session
+/// and DAW Faders layouts can only be selected in DAW mode
+pub fn daw_mode(on: bool) -> Vec<u8> {
+    sysex(DAW_COMMAND, &[on as u8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes() {
+        assert_eq!(
+            programmer_mode(true),
+            vec![240, 0, 32, 41, 2, 12, 14, 1, 247]
+        );
+        assert_eq!(
+            programmer_mode(false),
+            vec![240, 0, 32, 41, 2, 12, 14, 0, 247]
+        );
+        assert_eq!(daw_mode(true), vec![240, 0, 32, 41, 2, 12, 16, 1, 247]);
+        assert_eq!(daw_mode(false), vec![240, 0, 32, 41, 2, 12, 16, 0, 247]);
+    }
+}
@@ -0,0 +1,257 @@
+//! The pads of the Launchpad X, by row and column.  Rows are
+//! numbered 1 to 9 from the bottom, and columns 1 to 9 from the
+//! left.  The 8x8 grid is rows and columns 1 to 8.  Row 9 is the top
+//! row of buttons, column 9 the right hand column of buttons, and the
+//! logo is in the top right corner.
+//!
+//! In Programmer mode a pad's number is its row then its column
+//! (e.g. 11 bottom left, 88 top right of the grid).  Grid pads send
+//! that number as a note, the buttons send it as a CC, and the LEDs
+//! are lit by it.
+use crate::ProtocolError;
+use std::fmt;
+
+/// The rows of a Note mode layout are a fourth, five semitones,
+/// apart
+const NOTE_MODE_ROW: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pad {
+    row: u8,
+    column: u8,
+}
+
+/// The parts of the surface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PadKind {
+    /// The 8x8 grid, that sends notes
+    Grid,
+    /// The buttons along the top, that send CCs
+    TopRow,
+    /// The buttons down the right hand side, that send CCs
+    RightColumn,
+    /// Can be lit, but is not a button
+    Logo,
+}
+
+impl Pad {
+    pub const LOGO: Pad = Pad { row: 9, column: 9 };
+
+    /// `row` and `column` are 1 to 9
+    pub fn new(row: u8, column: u8) -> Result<Pad, ProtocolError> {
+        if (1..=9).contains(&row) && (1..=9).contains(&column) {
+            Ok(Pad { row, column })
+        } else {
+            Err(ProtocolError::BadPad(
+                row.saturating_mul(10).saturating_add(column),
+            ))
+        }
+    }
+
+    pub fn row(self) -> u8 {
+        self.row
+    }
+
+    pub fn column(self) -> u8 {
+        self.column
+    }
+
+    /// The Programmer mode number, used for notes, CCs and lighting
+    pub fn number(self) -> u8 {
+        self.row * 10 + self.column
+    }
+
+    pub fn kind(self) -> PadKind {
+        match (self.row, self.column) {
+            (9, 9) => PadKind::Logo,
+            (9, _) => PadKind::TopRow,
+            (_, 9) => PadKind::RightColumn,
+            _ => PadKind::Grid,
+        }
+    }
+
+    pub fn is_grid(self) -> bool {
+        self.kind() == PadKind::Grid
+    }
+
+    pub fn is_top_row(self) -> bool {
+        self.kind() == PadKind::TopRow
+    }
+
+    pub fn is_right_column(self) -> bool {
+        self.kind() == PadKind::RightColumn
+    }
+
+    pub fn is_logo(self) -> bool {
+        self.kind() == PadKind::Logo
+    }
+
+    /// The note a grid pad sends in Programmer mode
+    pub fn programmer_note(self) -> Option<u8> {
+        self.is_grid().then(|| self.number())
+    }
+
+    /// The grid pad that sends `note` in Programmer mode
+    pub fn from_programmer_note(note: u8) -> Option<Pad> {
+        Pad::try_from(note).ok().filter(|pad| pad.is_grid())
+    }
+
+    /// The CC a button sends in Programmer mode
+    pub fn cc(self) -> Option<u8> {
+        matches!(self.kind(), PadKind::TopRow | PadKind::RightColumn).then(|| self.number())
+    }
+
+    /// The button that sends `cc` in Programmer mode
+    pub fn from_cc(cc: u8) -> Option<Pad> {
+        Pad::try_from(cc).ok().filter(|pad| pad.cc().is_some())
+    }
+
+    /// How many semitones a grid pad plays above the bottom left pad,
+    /// in Note mode's layout: along a row a semitone a pad, and each
+    /// row a fourth above the one below it
+    pub fn note_mode_offset(self) -> Option<u8> {
+        self.is_grid()
+            .then(|| (self.row - 1) * NOTE_MODE_ROW + self.column - 1)
+    }
+
+    /// The note a grid pad plays in Note mode, when the bottom left
+    /// pad plays `lowest`
+    pub fn note_mode_note(self, lowest: u8) -> Option<u8> {
+        self.note_mode_offset()
+            .and_then(|offset| offset.checked_add(lowest))
+            .filter(|note| *note < 0x80)
+    }
+
+    /// The other grid pad that plays the same note in Note mode's
+    /// layout.  The three right hand pads of a row play the same
+    /// notes as the three left hand pads of the row above
+    pub fn note_mode_twin(self) -> Option<Pad> {
+        if !self.is_grid() {
+            None
+        } else if self.column > NOTE_MODE_ROW && self.row < 8 {
+            Some(Pad {
+                row: self.row + 1,
+                column: self.column - NOTE_MODE_ROW,
+            })
+        } else if self.column <= 8 - NOTE_MODE_ROW && self.row > 1 {
+            Some(Pad {
+                row: self.row - 1,
+                column: self.column + NOTE_MODE_ROW,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The 64 grid pads, a row at a time from the bottom, each from
+    /// the left
+    pub fn grid() -> impl Iterator<Item = Pad> {
+        (1..9).flat_map(|row| (1..9).map(move |column| Pad { row, column }))
+    }
+
+    /// The buttons along the top, from the left
+    pub fn top_row() -> impl Iterator<Item = Pad> {
+        (1..9).map(|column| Pad { row: 9, column })
+    }
+
+    /// The buttons down the right, from the bottom
+    pub fn right_column() -> impl Iterator<Item = Pad> {
+        (1..9).map(|row| Pad { row, column: 9 })
+    }
+
+    /// All 81, the logo too, a row at a time from the bottom
+    pub fn all() -> impl Iterator<Item = Pad> {
+        (1..10).flat_map(|row| (1..10).map(move |column| Pad { row, column }))
+    }
+}
+
+impl TryFrom<u8> for Pad {
+    type Error = ProtocolError;
+    /// From a Programmer mode number: 11 to 99, with no column 0
+    fn try_from(number: u8) -> Result<Self, Self::Error> {
+        Pad::new(number / 10, number % 10).map_err(|_| ProtocolError::BadPad(number))
+    }
+}
+
+impl From<Pad> for u8 {
+    fn from(pad: Pad) -> u8 {
+        pad.number()
+    }
+}
+
+impl fmt::Display for Pad {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.number())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_and_kinds() {
+        let pad = Pad::try_from(34).unwrap();
+        assert_eq!((pad.row(), pad.column()), (3, 4));
+        assert_eq!(u8::from(pad), 34);
+        assert_eq!(pad.kind(), PadKind::Grid);
+        assert_eq!(Pad::try_from(100), Err(ProtocolError::BadPad(100)));
+        assert_eq!(Pad::try_from(20), Err(ProtocolError::BadPad(20)));
+        assert_eq!(Pad::try_from(5), Err(ProtocolError::BadPad(5)));
+        assert!(Pad::new(0, 1).is_err());
+        assert_eq!(Pad::try_from(95).unwrap().kind(), PadKind::TopRow);
+        assert_eq!(Pad::try_from(49).unwrap().kind(), PadKind::RightColumn);
+        assert!(Pad::try_from(99).unwrap().is_logo());
+
+        assert_eq!(
+            Pad::from_programmer_note(88).unwrap().programmer_note(),
+            Some(88)
+        );
+        assert_eq!(Pad::from_programmer_note(19), None);
+        assert_eq!(Pad::from_cc(19).unwrap().cc(), Some(19));
+        assert_eq!(Pad::from_cc(91).unwrap().cc(), Some(91));
+        assert_eq!(Pad::from_cc(99), None);
+        assert_eq!(Pad::from_cc(7), None);
+        assert_eq!(Pad::LOGO.cc(), None);
+    }
+
+    #[test]
+    fn iterators() {
+        assert_eq!(Pad::grid().count(), 64);
+        assert!(Pad::grid().all(Pad::is_grid));
+        assert_eq!(Pad::grid().next().unwrap().number(), 11);
+        assert_eq!(Pad::grid().last().unwrap().number(), 88);
+        assert_eq!(
+            Pad::top_row().map(Pad::number).collect::<Vec<u8>>(),
+            vec![91, 92, 93, 94, 95, 96, 97, 98]
+        );
+        assert_eq!(
+            Pad::right_column().map(Pad::number).collect::<Vec<u8>>(),
+            vec![19, 29, 39, 49, 59, 69, 79, 89]
+        );
+        assert_eq!(Pad::all().count(), 81);
+    }
+
+    #[test]
+    fn note_mode() {
+        let pad = |number| Pad::try_from(number).unwrap();
+        assert_eq!(pad(11).note_mode_note(36), Some(36));
+        assert_eq!(pad(18).note_mode_note(36), Some(43));
+        assert_eq!(pad(21).note_mode_note(36), Some(41));
+        assert_eq!(pad(88).note_mode_offset(), Some(42));
+        assert_eq!(pad(88).note_mode_note(100), None);
+        assert_eq!(pad(19).note_mode_note(36), None);
+        // Every pad's twin plays the same note, and has it as its twin
+        for grid in Pad::grid() {
+            if let Some(twin) = grid.note_mode_twin() {
+                assert_eq!(twin.note_mode_offset(), grid.note_mode_offset());
+                assert_eq!(twin.note_mode_twin(), Some(grid));
+            }
+        }
+        assert_eq!(pad(16).note_mode_twin(), Some(pad(21)));
+        assert_eq!(pad(14).note_mode_twin(), None);
+        assert_eq!(pad(86).note_mode_twin(), None);
+        let twins = Pad::grid().filter(|p| p.note_mode_twin().is_some()).count();
+        assert_eq!(twins, 2 * 3 * 7);
+    }
+}
@@ -1,25 +1,51 @@
-use midi_connection::MIDICommunicator;
+use lpx_protocol::{FrameLayer, Framebuffer, Lighting, Pad};
+use midi_connection::crossbeam_channel::{self, select};
+use midi_connection::{
+    client_name_from_args, config_client_name, exit_status, on_exit_from_args, ConnectionEvent,
+    Direction, FanOut, Layer, LedPipeline, Logger, LpxPorts, LpxRestore, MIDICommunicator,
+    MidiMessage, Midir, OnExit, RouteConfig, Router, Shutdown, Tap,
+};
 use std::env;
 use std::fs::File;
 //use std::io::stdin;
 //use std::collections::BTreeMap;
 use std::io::{self, BufRead};
-use std::thread;
-use std::time::Duration;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 //use std::path::Path;
 
 //use std::env;
 // use midir;
 use std::error::Error;
 
+/// How a pad is lit while it is held down: blue.  It could as well
+/// flash (`Lighting::Flash`) or pulse
+const PRESSED: Lighting = Lighting::Static(50);
+
 struct Adapter {
     // Adapter changes the MIDI note and sends it to the synthesiser
     // and sends colour change messages to the LPX
-    midi_out_synth: MIDICommunicator<()>,
-    midi_out_lpx: MIDICommunicator<()>,
+    // The synthesisers, each with its own channel, transpose and
+    // velocity
+    synths: FanOut,
+    midi_out_lpx: MIDICommunicator,
+    // Pad colours go to the LPX through this, so they never hold up
+    // notes
+    leds: LedPipeline,
+    // What the pads show: the scale in the base layer and the pads
+    // held down over it.  Only the pads that change are sent
+    frame: Framebuffer,
+    // Set by the main thread when the LPX is plugged back in.  Every
+    // pad is sent again with the next pad pressed, so the callback
+    // never waits for the main thread
+    repaint: Arc<AtomicBool>,
+    // Errors are written out by another thread, so they never hold
+    // up notes either
+    log: Logger,
     midi_map: [usize; 99], // key is MIDI from LPX value MIDI to synth
     scale: Vec<usize>,     // At most 12 unique intergers in 1..12 inclusive
-    midi_note_to_pads: Vec<(Option<usize>, Option<usize>)>, // Each note at most 2 pads
+    midi_note_to_pads: Vec<(Option<Pad>, Option<Pad>)>, // Each note at most 2 pads
     root_note: usize,
 }
 impl std::fmt::Debug for Adapter {
@@ -36,7 +62,7 @@ impl Adapter {
     /// others cream(113)
     fn pad_colour(&self, pad_in: usize) -> Option<usize> {
         const PALLET: [usize; 12] = [5, 12, 13, 20, 21, 29, 37, 44, 45, 52, 53, 61];
-        if pad_in % 10 > 0 && pad_in % 10 < 9 {
+        if Pad::from_programmer_note(pad_in as u8).is_some() {
             let pad_out = self.adapt(pad_in);
 
             let diff_12 = ((self.root_note as i16 - pad_out as i16).abs() % 12) as usize;
@@ -65,9 +91,57 @@ impl Adapter {
         }
     }
 
+    /// A pad on the LPX has been pressed, or released if `velocity`
+    /// is 0.  Send the note to the synthesiser and change the colour
+    /// of the pads that play it
+    fn press(&mut self, channel: u8, pad_in: usize, velocity: u8) {
+        if Pad::from_programmer_note(pad_in as u8).is_none() {
+            // Not a MIDI key
+            return;
+        }
+        // A key press, adapt it (translate the position on the LPX
+        // represented by `pad_in` into a MIDI note)
+        let midi_note_out: u8 = self.adapt(pad_in).try_into().unwrap();
+        let note_out = MidiMessage::NoteOn {
+            channel,
+            note: midi_note_out,
+            velocity,
+        };
+        // eprintln!("pad_in({}) note_out({:?})", &pad_in, &note_out);
+        match self.synths.send_message(&note_out) {
+            Ok(()) => (),
+            Err(err) => self.log.error("Sending note", err),
+        };
+
+        // The key that is pressed, light it with `PRESSED` while it
+        // is held.  When released its standard colour shows again.
+        // There are possibly two pads that play the note
+        let pads = self.midi_note_to_pads[midi_note_out as usize];
+        for pad in [pads.0, pads.1].into_iter().flatten() {
+            if velocity == 0 {
+                self.frame.erase(FrameLayer::Pressed, pad);
+            } else if let Err(err) = self.frame.draw(FrameLayer::Pressed, pad, PRESSED) {
+                self.log.error("Lighting pad", err.into());
+            }
+        }
+        self.show();
+    }
+
+    /// Send the pads that have changed.  All of them if the LPX has
+    /// been plugged back in, as its colours are lost
+    fn show(&mut self) {
+        if self.repaint.swap(false, Ordering::Relaxed) {
+            self.frame.forget_all();
+        }
+        if let Err(err) = self.leds.paint(self.frame.flush()) {
+            self.log.error("Lighting pads", err);
+        }
+    }
+
     fn new(
-        midi_out_synth: MIDICommunicator<()>,
-        midi_out_lpx: MIDICommunicator<()>,
+        synths: FanOut,
+        midi_out_lpx: MIDICommunicator,
+        leds: LedPipeline,
         scale: &Vec<usize>,
         root_note: usize, // Where the scale is rooted.  The MIDI note
     ) -> Self {
@@ -75,7 +149,7 @@ impl Adapter {
 
         let mut midi_note_to_pads = (0..120)
             .map(|_| (None, None))
-            .collect::<Vec<(Option<usize>, Option<usize>)>>();
+            .collect::<Vec<(Option<Pad>, Option<Pad>)>>();
         // The middle key in this scheme is 34.  Middle C is MIDI 60
         // So adjustment...
         //        println!("root_note({})", root_note);
@@ -96,99 +170,85 @@ impl Adapter {
         let scale_up = scale.iter().map(|x| x - 1).collect::<Vec<usize>>();
         // eprintln!("scale_down({:?})", scale_down);
         // eprintln!("scale_up({:?})", scale_up);
-        let midi_index = |row: usize, col: usize| {
-            if col > 5 {
-                row * 5 + col % 5
-            } else {
-                (row - 1) * 5 + col
-            }
-        };
-        let root_note_midi_index = midi_index(5, 4);
-
-        for i in 11..100 {
-            // `i` is the number for a pad.  No pads 10, 20,... and
-            // pads 19, 29,... are control pads
-            let row = i / 10; //  5
-            let col = i % 10; //  4
-            if col == 0 {
-                continue;
-            }
-            if row < 9 && col > 0 && col < 9 {
-                // 1 3 5 6 8 10 11
-                // Use distance in "midi_index" terms from the root
-                // note, and the scale, to determine the MIDI note to
-                // play
-
-                // Where this pad is in the sequence of MIDI notes (notes on the scale)
-                let this_midi_index = midi_index(row, col); // 23
-
-                // How far away from the root note is this pad
-                let diff_midi_index: isize = // 24 - 23 
-                    this_midi_index as isize - root_note_midi_index as isize; // -1
-
-                // How many octaves away from the root note is this
-                // pad.
-                let diff_octave: isize = diff_midi_index / scale.len() as isize;
-
-                let diff_due_to_octave = diff_octave * 12;
-
-                let midi_modulus = diff_midi_index.unsigned_abs() % scale.len();
-
-                let midi_note = root_note as isize
-                    + diff_due_to_octave
-                    + if diff_midi_index < 0 {
-                        -1 * scale_down[midi_modulus] as isize
-                    } else {
-                        scale_up[midi_modulus] as isize
-                    }; // 5
-
-                // eprintln!(
-                //     "(({}/{})\ti({})\tmidi_note({}) diff_due_to_octave({}) diff_midi_index({}) midi_modulus({})",
-                // row, col, i, midi_note, diff_due_to_octave, diff_midi_index, midi_modulus, );
-
-                // Choosing a root note that is too small will violate
-                // this asertion.  Need a guard once the root note and
-                // scale are known.
-                assert!(midi_note > 0);
-
-                midi_map[i as usize] = midi_note as usize;
-
-                // This function returns the other pad that emits this
-                // note if there is one
-                let f = |p| {
-                    if p < 80 && col > 5 && col < 9 {
-                        // Not in top row, and in right hand
-                        // three columns.  Pads here have a
-                        // duplicate in the row above
-                        Some((row + 1) * 10 + col - 6)
-                    } else if p > 20 && col < 4 {
-                        // Not in bottom row and in left hand columns.
-                        // Pads here have
-                        // a duplicate in the row below
-                        Some((row - 1) * 10 + col + 5)
-                    } else {
-                        None
-                    }
-                };
+        // Pads are in sequence as in the LPX's Note mode, each row
+        // five pads on from the one below
+        let midi_index = |pad: Pad| pad.note_mode_offset().unwrap() as usize;
+        let root_note_midi_index = midi_index(Pad::new(5, 4).unwrap());
+
+        for pad in Pad::grid() {
+            // `i` is the number for a pad
+            let i = pad.number() as usize;
+            // 1 3 5 6 8 10 11
+            // Use distance in "midi_index" terms from the root
+            // note, and the scale, to determine the MIDI note to
+            // play
+
+            // Where this pad is in the sequence of MIDI notes (notes on the scale)
+            let this_midi_index = midi_index(pad); // 23
+
+            // How far away from the root note is this pad
+            let diff_midi_index: isize = // 24 - 23 
+                this_midi_index as isize - root_note_midi_index as isize; // -1
+
+            // How many octaves away from the root note is this
+            // pad.
+            let diff_octave: isize = diff_midi_index / scale.len() as isize;
+
+            let diff_due_to_octave = diff_octave * 12;
+
+            let midi_modulus = diff_midi_index.unsigned_abs() % scale.len();
+
+            let midi_note = root_note as isize
+                + diff_due_to_octave
+                + if diff_midi_index < 0 {
+                    -(scale_down[midi_modulus] as isize)
+                } else {
+                    scale_up[midi_modulus] as isize
+                }; // 5
 
-                let pads: (Option<usize>, Option<usize>) = (Some(i), f(i));
-                midi_note_to_pads[midi_note as usize] = pads;
+            // eprintln!(
+            //     "(({}/{})\ti({})\tmidi_note({}) diff_due_to_octave({}) diff_midi_index({}) midi_modulus({})",
+            // pad.row(), pad.column(), i, midi_note, diff_due_to_octave, diff_midi_index, midi_modulus, );
 
-                // eprintln!(
-                //     "i({}) midi_note({}) row/col (({}/{}) pads({:?})",
-                //     i, midi_note, row, col, pads
-                // );
-            }
+            // Choosing a root note that is too small will violate
+            // this asertion.  Need a guard once the root note and
+            // scale are known.
+            assert!(midi_note > 0);
+
+            midi_map[i] = midi_note as usize;
+
+            // The other pad that emits this note, if there is one
+            let twin = pad.note_mode_twin();
+
+            let pads: (Option<Pad>, Option<Pad>) = (Some(pad), twin);
+            midi_note_to_pads[midi_note as usize] = pads;
+
+            // eprintln!(
+            //     "i({}) midi_note({}) row/col (({}/{}) pads({:?})",
+            //     i, midi_note, pad.row(), pad.column(), pads
+            // );
         }
         //eprintln!("End of Adapter::new");
-        Self {
-            midi_out_synth: midi_out_synth,
+        let mut adapter = Self {
+            synths,
             midi_out_lpx: midi_out_lpx,
+            leds,
+            frame: Framebuffer::new(),
+            repaint: Arc::new(AtomicBool::new(false)),
+            log: Logger::new(),
             midi_map: midi_map,
             scale: scale.to_vec(),
             midi_note_to_pads: midi_note_to_pads,
             root_note: root_note,
+        };
+        for pad in Pad::grid() {
+            let colour = adapter.pad_colour(pad.number() as usize).unwrap() as u8;
+            adapter
+                .frame
+                .draw(FrameLayer::Base, pad, Lighting::Static(colour))
+                .expect("Pad colours are in the palette");
         }
+        adapter
     }
 }
 
@@ -203,18 +263,38 @@ struct DeviceNames {
     midi_sink_lpx: String,
     midi_sink_lpx_120: String,
 
-    midi_sink_synth: String,
+    /// Replies from the LPX to the commands.  Needed to restore its
+    /// layout on exit
+    midi_source_lpx_daw: Option<String>,
+
+    /// Each synthesiser, with its `Layer` settings
+    midi_sink_synth: Vec<String>,
     midi_sink_synth_120: String,
+
+    /// Other connections, from the routing file named by `routes:`
+    /// (see `RouteConfig`)
+    routes: Option<RouteConfig>,
+    /// The routes' connections are named from this
+    client_name: String,
 }
 
 impl DeviceNames {
-    fn new(cfg_fn: &str) -> io::Result<DeviceNames> {
+    /// Read `cfg_fn`.  `client_name`, from the command line, is used
+    /// before one in the file
+    fn new(cfg_fn: &str, client_name: Option<String>) -> Result<DeviceNames, Box<dyn Error>> {
         // panic!("Unfinished.");
 
         // Read a configuration file for midi_source_lpx, midi_sink_lpx, midi_sink_synth
         let mut midi_source_lpx = "".to_string(); //"Launchpad X:Launchpad X MIDI 2".to_string();
         let mut midi_sink_lpx = "".to_string();
-        let mut midi_sink_synth = "".to_string();
+        let mut midi_sink_synth: Vec<String> = Vec::new();
+        let mut midi_source_lpx_daw: Option<String> = None;
+        // The names of our end of each connection
+        let mut cfg_client_name: Option<String> = None;
+        let mut routes: Option<RouteConfig> = None;
+        let mut midi_source_lpx_120: Option<String> = None;
+        let mut midi_sink_lpx_120: Option<String> = None;
+        let mut midi_sink_synth_120: Option<String> = None;
 
         let file = File::open(cfg_fn)?;
         let lines = io::BufReader::new(file).lines();
@@ -223,31 +303,116 @@ impl DeviceNames {
                 // `l` is the line
                 if l.starts_with("MIDI_Connections") || l.starts_with("#") {
                     continue;
+                } else if let Some(name) = l.strip_prefix("client_name:") {
+                    cfg_client_name = Some(name.to_string());
+                } else if let Some(name) = l.strip_prefix("midi_source_lpx_120:") {
+                    midi_source_lpx_120 = Some(name.to_string());
+                } else if let Some(name) = l.strip_prefix("midi_sink_lpx_120:") {
+                    midi_sink_lpx_120 = Some(name.to_string());
+                } else if let Some(name) = l.strip_prefix("midi_sink_synth_120:") {
+                    midi_sink_synth_120 = Some(name.to_string());
+                } else if let Some(name) = l.strip_prefix("midi_source_lpx_daw:") {
+                    midi_source_lpx_daw = Some(name.to_string());
+                } else if let Some(path) = l.strip_prefix("routes:") {
+                    routes = Some(RouteConfig::load(path)?);
                 } else if l.starts_with("midi_source_lpx:") {
                     midi_source_lpx = l.strip_prefix("midi_source_lpx:").unwrap().to_string();
                 } else if l.starts_with("midi_sink_lpx:") {
                     midi_sink_lpx = l.strip_prefix("midi_sink_lpx:").unwrap().to_string();
                 } else if l.starts_with("midi_sink_synth:") {
-                    midi_sink_synth = l.strip_prefix("midi_sink_synth:").unwrap().to_string();
+                    midi_sink_synth.push(l.strip_prefix("midi_sink_synth:").unwrap().to_string());
                 } else {
                     panic!("{} misunderstood", &l);
                 }
             }
         }
+        if midi_sink_synth.is_empty() {
+            return Err(format!("{}: No midi_sink_synth", cfg_fn).into());
+        }
+
+        let client_name = client_name
+            .or(cfg_client_name)
+            .unwrap_or_else(|| config_client_name(cfg_fn));
+
+        // The LPX ports are optional.  Without them look for the LPX
+        if midi_source_lpx.is_empty() || midi_sink_lpx.is_empty() {
+            let lpx = LpxPorts::find_as(client_name.as_str())?;
+            if midi_source_lpx.is_empty() {
+                midi_source_lpx = lpx.midi_in;
+            }
+            if midi_sink_lpx.is_empty() {
+                midi_sink_lpx = lpx.daw_out;
+            }
+            if midi_source_lpx_daw.is_none() {
+                midi_source_lpx_daw = lpx.daw_in;
+            }
+        }
         Ok(DeviceNames {
             midi_source_lpx: midi_source_lpx, //"Launchpad X:Launchpad X MIDI 2",
-            midi_source_lpx_120: "120-Proof-MIDI-In-LPX".to_string(),
+            midi_source_lpx_120: midi_source_lpx_120
+                .unwrap_or_else(|| format!("{}-LPX-In", client_name)),
 
             midi_sink_lpx: midi_sink_lpx, //"Launchpad X:Launchpad X MIDI 1".to_string(),
-            midi_sink_lpx_120: "120-Proof-MIDI-Out-LPX".to_string(),
+            midi_sink_lpx_120: midi_sink_lpx_120
+                .unwrap_or_else(|| format!("{}-LPX-Out", client_name)),
+            midi_source_lpx_daw,
 
             midi_sink_synth: midi_sink_synth, //"Pure Data:Pure Data Midi-In 2".to_string(),
-            midi_sink_synth_120: "120-Proof-MIDI-Out-PD".to_string(),
+            midi_sink_synth_120: midi_sink_synth_120
+                .unwrap_or_else(|| format!("{}-Synth", client_name)),
+            routes,
+            client_name,
         })
     }
 }
-fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
+/// Ask the LPX what layout it is in, so `lpx_restore` can put it back
+fn remember_layout(lpx_restore: &mut LpxRestore, device_names: &DeviceNames) {
+    let daw_in = match device_names.midi_source_lpx_daw.as_ref() {
+        Some(daw_in) => daw_in,
+        None => {
+            eprintln!(
+                "No midi_source_lpx_daw in the configuration.  The LPX layout cannot be restored"
+            );
+            return;
+        }
+    };
+    let client_name = format!("{}-Layout", device_names.midi_sink_lpx_120);
+    match lpx_restore.remember_layout(daw_in.as_str(), client_name.as_str(), Arc::new(Midir)) {
+        Ok(Some(_)) => (),
+        Ok(None) => eprintln!("The LPX did not say what layout it is in.  It cannot be restored"),
+        Err(err) => eprintln!("Asking the LPX its layout: {}", err),
+    }
+}
+
+/// A message from the LPX, for the adapter.  Pads play notes, and
+/// anything else goes back to the LPX
+fn from_lpx(_stamp: u64, message: &[u8], adapter: &mut Adapter) {
+    // eprintln!("midi_in stamp({:?}) message({:?})", &_stamp, &message);
+    match MidiMessage::decode(message) {
+        Ok(MidiMessage::NoteOn {
+            channel,
+            note,
+            velocity,
+        }) => adapter.press(channel, note as usize, velocity),
+        Ok(MidiMessage::NoteOff { channel, note, .. }) => adapter.press(channel, note as usize, 0),
+        Ok(message) => match adapter.midi_out_lpx.send_message(&message) {
+            Ok(()) => (),
+            Err(err) => adapter.log.error("Random message(?)", err),
+        },
+        Err(err) => adapter.log.error("From LPX", err),
+    };
+}
+
+/// Returns the signal that stopped it, if one did
+fn run() -> Result<Option<i32>, Box<dyn Error>> {
+    let mut args: Vec<String> = env::args().collect();
+    let client_name = client_name_from_args(&mut args)?;
+    let on_exit = on_exit_from_args(&mut args)?;
+    // `--tap FILE` records the MIDI in and out
+    let tap = Tap::from_args(&mut args)?;
+
+    // From here a signal stops the programme cleanly
+    let shutdown = Shutdown::new()?;
 
     // This is the scale.  Should be able to pass this in on the command line.
     let scale: Vec<usize>;
@@ -278,157 +443,192 @@ fn main() -> Result<(), Box<dyn Error>> {
     //     cfg_fn, root_note, scale
     // );
 
-    let device_names = DeviceNames::new(cfg_fn).unwrap();
-    let midi_out_synth: MIDICommunicator<()> = MIDICommunicator::new(
-        device_names.midi_sink_synth.as_str(),
-        device_names.midi_sink_synth_120.as_str(),
-        |_, _, _| {},
-        (),
-        2,
-    )?;
+    let device_names = DeviceNames::new(cfg_fn, client_name)?;
+    // Every synthesiser plays each note.  The first has the usual
+    // client name, the others are numbered
+    let mut synths = FanOut::new();
+    for (i, spec) in device_names.midi_sink_synth.iter().enumerate() {
+        let (port, layer) = Layer::parse_sink(spec)?;
+        let client_name = match i {
+            0 => device_names.midi_sink_synth_120.clone(),
+            _ => format!("{}-{}", device_names.midi_sink_synth_120, i + 1),
+        };
+        let midi_out_synth = MIDICommunicator::builder(port.as_str(), client_name.as_str())
+            .direction(Direction::Output)
+            .reconnect(true)
+            .tap(tap.as_ref())
+            .on_event(move |event| eprintln!("Synthesiser {}: {:?}", port, event))
+            .build()?;
+        synths.add(midi_out_synth, layer);
+    }
 
-    let midi_out_lpx: MIDICommunicator<()> = MIDICommunicator::new(
+    let midi_out_lpx: MIDICommunicator = MIDICommunicator::builder(
         device_names.midi_sink_lpx.as_str(),
         device_names.midi_sink_lpx_120.as_str(),
-        |_, _, _| {},
-        (),
-        2,
-    )?;
+    )
+    .direction(Direction::Output)
+    .reconnect(true)
+    .tap(tap.as_ref())
+    .build()?;
+
+    // The pad colours have a connection of their own, so messages
+    // passed back to the LPX never wait behind them.  Its events are
+    // passed to the main thread, that repaints the pads when the LPX
+    // is plugged back in
+    let (lpx_event_tx, lpx_event_rx) = crossbeam_channel::unbounded::<ConnectionEvent>();
+    let midi_out_leds: MIDICommunicator = MIDICommunicator::builder(
+        device_names.midi_sink_lpx.as_str(),
+        format!("{}-LEDs", device_names.midi_sink_lpx_120).as_str(),
+    )
+    .direction(Direction::Output)
+    .reconnect(true)
+    .tap(tap.as_ref())
+    .on_event(move |event| {
+        let _ = lpx_event_tx.send(event);
+    })
+    .build()?;
+
+    // What is dropped last is done last.  On the way out the input
+    // is closed first, then held notes are turned off, then the
+    // colours waiting are sent, and then the LPX is put back
+    let mut lpx_restore = LpxRestore::new(midi_out_lpx.clone(), on_exit);
+    if on_exit == OnExit::Restore {
+        remember_layout(&mut lpx_restore, &device_names);
+    }
+    let leds = LedPipeline::new(midi_out_leds);
+    let _notes_off = synths.release_on_drop();
+
+    let mut adapter = Adapter::new(synths, midi_out_lpx, leds, &scale, root_note);
 
-    let mut adapter = Adapter::new(midi_out_synth, midi_out_lpx, &scale, root_note);
     // Initialise LPX colours
-    for i in 11..90 {
-        if i % 10 > 0 && i % 10 < 9 {
-            // let ten_millis = Duration::from_millis(100);
-            // thread::sleep(ten_millis);
-
-            let colour = adapter.pad_colour(i as usize).unwrap() as u8;
-            let out_message_colour_change: [u8; 11] = [240, 0, 32, 41, 2, 12, 3, 0, i, colour, 247];
-
-            match adapter.midi_out_lpx.send(&out_message_colour_change) {
-                Ok(()) => {
-                    // eprintln!(
-                    //     "Colour: {} Pad: {} Sent: {:?}",
-                    //     &colour, i, &out_message_colour_change
-                    // )
-                }
-                Err(err) => eprintln!("Initialising colours: Failed send: {:?}", err),
-            };
-        }
-    }
+    adapter.show();
+
+    // The adapter belongs to the callback.  The main thread only
+    // asks it to paint the LPX again if it is replugged
+    let repaint = adapter.repaint.clone();
 
     // The process that listens
 
-    let _midi_in: MIDICommunicator<Adapter> = MIDICommunicator::new(
+    let _midi_in: MIDICommunicator = MIDICommunicator::builder(
         device_names.midi_source_lpx.as_str(),
         device_names.midi_source_lpx_120.as_str(),
-        |_stamp, message, adapter| {
-            // eprintln!("midi_in stamp({:?}) message({:?})", &_stamp, &message);
-
-            let pad_in = message[1] as usize;
-            let velocity = message[2] as u8;
-            // eprintln!("pad_in({}) velocity({})", pad_in, velocity);
-            // Send note to synthesiser
-            match message[0] {
-                144 => {
-                    if pad_in % 10 > 0 && pad_in % 10 < 9 {
-                        // A key press, adapt it (translate the position
-                        // on the LPX represented by `pad_in` into a MIDI
-                        // note)
-                        let midi_note_out: u8 = adapter.adapt(pad_in).try_into().unwrap();
-                        let out_message_midi_note = [144, midi_note_out, velocity];
-                        // eprintln!("pad_in({}) midi_note_out({})", &pad_in, &midi_note_out,);
-                        match adapter.midi_out_synth.send(&out_message_midi_note) {
-                            Ok(()) => (),
-                            Err(err) => eprintln!("Sending note: Failed send: {:?}", err),
-                        };
-
-                        // The key that is pressed, flash it blue(50) as it is
-                        // pressed.  It's standard colour otherwise
-                        let pad_colour: usize = match velocity {
-                            0 =>
-                            // Key up.  Return to unpressed colour
-                            {
-                                let colour = adapter.pad_colour(pad_in).unwrap(); // Safe as pad_in is filtered;
-                                                                                  // eprintln!("Pad({}) up. Colour({})", pad_in, &colour);
-                                colour
-                            }
-                            _ => 50,
-                        };
-
-                        // There are possibly two pads to adjust colour of
-                        let pads = adapter.midi_note_to_pads[midi_note_out as usize];
-                        if let Some(p) = pads.0 {
-                            let out_message_colour_change: [u8; 11] = [
-                                240,
-                                0,
-                                32,
-                                41,
-                                2,
-                                12,
-                                3,
-                                0,
-                                p.try_into().unwrap(),
-                                pad_colour.try_into().unwrap(),
-                                247,
-                            ];
-                            // eprintln!(
-                            //     "out_message_colour_change({:?})",
-                            //     &out_message_colour_change
-                            // );
-                            match adapter.midi_out_lpx.send(&out_message_colour_change) {
-                                Ok(()) => (),
-                                Err(err) => {
-                                    eprintln!("Press colour change: Failed send: {:?}", err)
-                                }
-                            };
-                        }
-                        if let Some(p) = pads.1 {
-                            let out_message_colour_change: [u8; 11] = [
-                                240,
-                                0,
-                                32,
-                                41,
-                                2,
-                                12,
-                                3,
-                                0,
-                                p.try_into().unwrap(),
-                                pad_colour.try_into().unwrap(),
-                                247,
-                            ];
-                            // eprintln!(
-                            //     "out_message_colour_change({:?})",
-                            //     &out_message_colour_change
-                            // );
-                            match adapter.midi_out_lpx.send(&out_message_colour_change) {
-                                Ok(()) => (),
-                                Err(err) => {
-                                    eprintln!("Press colour change(2) : Failed send: {:?}", err)
-                                }
-                            };
-                        }
+    )
+    .direction(Direction::Input)
+    .reconnect(true)
+    .tap(tap.as_ref())
+    .on_event(|event| eprintln!("LPX input: {:?}", event))
+    .callback(from_lpx, adapter)
+    .build()?;
+
+    // The connections in the routing file run beside the adapter
+    let _router = match device_names.routes.as_ref() {
+        Some(routes) => Some(Router::start(routes, device_names.client_name.as_str())?),
+        None => None,
+    };
+
+    // Wait for the LPX to be replugged.  Its colours are lost so
+    // paint them again.  Until a signal to stop
+    loop {
+        select! {
+            recv(lpx_event_rx) -> event => match event {
+                Ok(event) => {
+                    eprintln!("LPX output: {:?}", event);
+                    if let ConnectionEvent::Connected { .. } = event {
+                        repaint.store(true, Ordering::Relaxed);
                     }
                 }
-                _ => match adapter.midi_out_lpx.send(&[
-                    message[0],
-                    pad_in.try_into().unwrap(),
-                    velocity,
-                ]) {
-                    Ok(()) => (),
-                    Err(err) => eprintln!("Random message(?): Failed send: {:?}", err),
-                },
-            };
-        },
-        adapter,
-        1,
-    )?;
+                Err(_) => return Ok(None),
+            },
+            recv(shutdown.receiver()) -> signal => return Ok(signal.ok()),
+        }
+    }
+}
 
-    // let mut input: String = String::new();
-    // input.clear();
-    // stdin().read_line(&mut input)?; // wait for next enter key press
-    loop {
-        thread::sleep(Duration::from_millis(100));
+fn main() {
+    process::exit(match run() {
+        Ok(None) => 0,
+        Ok(Some(signal)) => exit_status(signal),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            1
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lpx_protocol::LedMessage;
+    use midi_connection::Loopback;
+
+    #[test]
+    fn pads_play_the_scale() {
+        let loopback = Loopback::new(&["LPX MIDI", "LPX DAW", "Synth"]);
+        let output = |port: &str| {
+            MIDICommunicator::builder(port, "120-Proof-Test")
+                .direction(Direction::Output)
+                .transport(Arc::new(loopback.clone()))
+                .build()
+                .unwrap()
+        };
+        let mut synths = FanOut::new();
+        synths.add(output("Synth"), Layer::default());
+        let leds = LedPipeline::new(output("LPX DAW"));
+        let mut adapter = Adapter::new(
+            synths,
+            output("LPX DAW"),
+            leds.clone(),
+            &vec![1, 3, 5, 6, 8, 10, 12],
+            60,
+        );
+        adapter.show();
+        let repaint = adapter.repaint.clone();
+        let _midi_in = MIDICommunicator::builder("LPX MIDI", "120-Proof-Test")
+            .direction(Direction::Input)
+            .transport(Arc::new(loopback.clone()))
+            .callback(from_lpx, adapter)
+            .build()
+            .unwrap();
+        leds.flush();
+        // Every pad in the grid is coloured
+        let first = loopback.take_sent("LPX DAW");
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].len(), 8 + 64 * 3);
+
+        // The root note is pressed and released
+        let root = Pad::new(5, 4).unwrap();
+        let pads: Vec<Pad> = [Some(root), root.note_mode_twin()]
+            .into_iter()
+            .flatten()
+            .collect();
+        let lit = |lighting| {
+            LedMessage::new()
+                .lights(pads.iter().map(|pad| (pad.number(), lighting)))
+                .unwrap()
+                .build()
+        };
+        loopback.inject("LPX MIDI", &[0x90, root.number(), 100]);
+        leds.flush();
+        assert_eq!(loopback.take_sent("LPX DAW"), vec![lit(PRESSED)]);
+        loopback.inject("LPX MIDI", &[0x80, root.number(), 0]);
+        leds.flush();
+        assert_eq!(
+            loopback.take_sent("LPX DAW"),
+            vec![lit(Lighting::Static(5))]
+        );
+        assert_eq!(
+            loopback.take_sent("Synth"),
+            vec![vec![0x90, 60, 100], vec![0x90, 60, 0]]
+        );
+
+        // Replugged: the next pad pressed sends every pad again
+        repaint.store(true, Ordering::Relaxed);
+        leds.flush();
+        assert!(loopback.take_sent("LPX DAW").is_empty());
+        loopback.inject("LPX MIDI", &[0x90, root.number(), 100]);
+        leds.flush();
+        let repainted = loopback.take_sent("LPX DAW");
+        assert_eq!(repainted.len(), 1);
+        assert_eq!(repainted[0].len(), 8 + 64 * 3);
     }
-    // Ok(())
 }
@@ -0,0 +1,354 @@
+//! How long a pad press takes to reach the synthesiser, and how many
+//! heap allocations it makes on the way.
+//!
+//! The set up is `lpx_manager`'s: a note from the LPX is sent to the
+//! synthesisers through a `FanOut`, and the pad's colour is changed
+//! through a `LedPipeline`.  The LPX's DAW port is slow, as USB is, so
+//! the LED thread is busy while notes are played.  Each press is
+//! handed to the input callback on this thread, as the MIDI system
+//! would, and timed until the synthesiser's connection is sent it,
+//! and until the callback returns and the next message can be
+//! handled.
+//!
+//! Run with `cargo bench -p midi_connection --bench note_path`.  With
+//! `-- before` the colours go through `LockedLeds`, the way they did
+//! before the note path was made to not allocate or wait on a lock,
+//! to compare
+use lpx_protocol::LedMessage;
+use midi_connection::{
+    Direction, FanOut, InputCallback, InputConnection, Layer, LedPipeline, MIDICommunicator,
+    MIDIError, MidiMessage, OutputConnection, PortLister, Transport,
+};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PRESSES: usize = 20_000;
+
+/// How long the LPX takes to take an LED message
+const USB_DELAY: Duration = Duration::from_micros(200);
+
+/// Counts the allocations made by each thread
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn allocations() -> u64 {
+    ALLOCATIONS.with(|a| a.get())
+}
+
+/// The nanoseconds since `start` when the synthesiser was last sent
+/// something
+struct Clock {
+    start: Instant,
+    synth_sent: AtomicU64,
+}
+
+/// Ports that cost nothing but the time they are told to
+struct BenchTransport {
+    clock: Arc<Clock>,
+    callback: Arc<Mutex<Option<InputCallback>>>,
+}
+
+/// The same ports as `BenchTransport`
+struct BenchLister;
+impl PortLister for BenchLister {
+    fn input_ports(&mut self) -> Result<Vec<String>, MIDIError> {
+        Ok(vec!["LPX MIDI".to_string()])
+    }
+
+    fn output_ports(&mut self) -> Result<Vec<String>, MIDIError> {
+        Ok(vec!["LPX DAW".to_string(), "Synth".to_string()])
+    }
+}
+
+struct BenchInput;
+impl InputConnection for BenchInput {}
+
+struct SynthOut(Arc<Clock>);
+impl OutputConnection for SynthOut {
+    fn send(&mut self, _msg: &[u8]) -> Result<(), MIDIError> {
+        let now = self.0.start.elapsed().as_nanos() as u64;
+        self.0.synth_sent.store(now, Ordering::Release);
+        Ok(())
+    }
+}
+
+struct DawOut;
+impl OutputConnection for DawOut {
+    fn send(&mut self, _msg: &[u8]) -> Result<(), MIDIError> {
+        let start = Instant::now();
+        while start.elapsed() < USB_DELAY {
+            std::hint::spin_loop();
+        }
+        Ok(())
+    }
+}
+
+impl Transport for BenchTransport {
+    fn input_ports(&self, _client_name: &str) -> Result<Vec<String>, MIDIError> {
+        Ok(vec!["LPX MIDI".to_string()])
+    }
+
+    fn output_ports(&self, _client_name: &str) -> Result<Vec<String>, MIDIError> {
+        Ok(vec!["LPX DAW".to_string(), "Synth".to_string()])
+    }
+
+    fn port_lister(&self, _client_name: &str) -> Result<Box<dyn PortLister>, MIDIError> {
+        Ok(Box::new(BenchLister))
+    }
+
+    fn connect_input(
+        &self,
+        _client_name: &str,
+        _port_name: &str,
+        callback: InputCallback,
+    ) -> Result<Box<dyn InputConnection>, MIDIError> {
+        *self.callback.lock().unwrap() = Some(callback);
+        Ok(Box::new(BenchInput))
+    }
+
+    fn connect_output(
+        &self,
+        _client_name: &str,
+        port_name: &str,
+    ) -> Result<Box<dyn OutputConnection>, MIDIError> {
+        match port_name {
+            "Synth" => Ok(Box::new(SynthOut(self.clock.clone()))),
+            _ => Ok(Box::new(DawOut)),
+        }
+    }
+
+    fn create_virtual_input(
+        &self,
+        _client_name: &str,
+        _port_name: &str,
+        _callback: InputCallback,
+    ) -> Result<Box<dyn InputConnection>, MIDIError> {
+        Err(MIDIError::Unsupported("virtual ports"))
+    }
+
+    fn create_virtual_output(
+        &self,
+        _client_name: &str,
+        _port_name: &str,
+    ) -> Result<Box<dyn OutputConnection>, MIDIError> {
+        Err(MIDIError::Unsupported("virtual ports"))
+    }
+}
+
+/// The LED pipeline as it was: the colours waiting in a map behind
+/// the same lock the LED thread takes to send them, and notes sent
+/// as a newly allocated message
+#[derive(Default)]
+struct LockedState {
+    pending: BTreeMap<u8, u8>,
+    stop: bool,
+}
+
+#[derive(Clone)]
+struct LockedLeds {
+    shared: Arc<(Mutex<LockedState>, Condvar)>,
+}
+
+impl LockedLeds {
+    fn new(mut lpx_out: MIDICommunicator) -> LockedLeds {
+        let shared = Arc::new((Mutex::new(LockedState::default()), Condvar::new()));
+        let thread_shared = shared.clone();
+        thread::spawn(move || {
+            let (state, changed) = &*thread_shared;
+            let mut state = state.lock().unwrap();
+            loop {
+                if state.pending.is_empty() {
+                    if state.stop {
+                        return;
+                    }
+                    state = changed.wait(state).unwrap();
+                    continue;
+                }
+                let mut message = LedMessage::new();
+                let _ = message.colours(state.pending.iter().map(|(&pad, &colour)| (pad, colour)));
+                state.pending.clear();
+                let _ = lpx_out.send(&message.build());
+            }
+        });
+        LockedLeds { shared }
+    }
+
+    fn set(&self, pad: u8, colour: u8) {
+        let (state, changed) = &*self.shared;
+        state.lock().unwrap().pending.insert(pad, colour);
+        changed.notify_all();
+    }
+
+    fn stop(&self) {
+        let (state, changed) = &*self.shared;
+        state.lock().unwrap().stop = true;
+        changed.notify_all();
+    }
+}
+
+enum Leds {
+    Pipeline(LedPipeline),
+    Locked(LockedLeds),
+}
+
+struct Adapter {
+    synths: FanOut,
+    // The synthesiser, to send to it directly as before
+    synth: MIDICommunicator,
+    leds: Leds,
+}
+
+fn main() -> Result<(), MIDIError> {
+    let before = std::env::args().any(|arg| arg == "before");
+    let clock = Arc::new(Clock {
+        start: Instant::now(),
+        synth_sent: AtomicU64::new(0),
+    });
+    let bench = BenchTransport {
+        clock: clock.clone(),
+        callback: Arc::new(Mutex::new(None)),
+    };
+    let callback = bench.callback.clone();
+    let transport: Arc<dyn Transport> = Arc::new(bench);
+    let output = |port: &str| {
+        MIDICommunicator::builder(port, "120-Proof-Bench")
+            .direction(Direction::Output)
+            .transport(transport.clone())
+            .reconnect(true)
+            .build()
+    };
+    let mut synths = FanOut::new();
+    let synth = output("Synth")?;
+    synths.add(synth.clone(), Layer::default());
+    let leds = match before {
+        false => Leds::Pipeline(LedPipeline::new(output("LPX DAW")?)),
+        true => Leds::Locked(LockedLeds::new(output("LPX DAW")?)),
+    };
+    let locked = match &leds {
+        Leds::Locked(locked) => Some(locked.clone()),
+        Leds::Pipeline(_) => None,
+    };
+    let _midi_in = MIDICommunicator::builder("LPX MIDI", "120-Proof-Bench")
+        .direction(Direction::Input)
+        .transport(transport.clone())
+        .reconnect(true)
+        .callback(
+            |_, message, adapter: &mut Adapter| {
+                if let Ok(MidiMessage::NoteOn {
+                    channel,
+                    note,
+                    velocity,
+                }) = MidiMessage::decode(message)
+                {
+                    let note_out = MidiMessage::NoteOn {
+                        channel,
+                        note: note + 24,
+                        velocity,
+                    };
+                    let colour = if velocity > 0 { 50 } else { 17 };
+                    match &adapter.leds {
+                        Leds::Pipeline(leds) => {
+                            let _ = adapter.synths.send_message(&note_out);
+                            let _ = leds.set(note, colour);
+                        }
+                        Leds::Locked(leds) => {
+                            let _ = adapter.synth.send(&note_out.encode());
+                            leds.set(note, colour);
+                        }
+                    }
+                }
+            },
+            Adapter {
+                synths,
+                synth,
+                leds,
+            },
+        )
+        .build()?;
+    let mut callback = callback.lock().unwrap().take().expect("Not connected");
+
+    let mut to_synth: Vec<u64> = Vec::with_capacity(PRESSES);
+    let mut handled: Vec<u64> = Vec::with_capacity(PRESSES);
+    let mut allocated = 0;
+    for i in 0..PRESSES {
+        // Across the grid, on and off
+        let pad = 11 + (i / 2 % 8) as u8 * 10 + (i / 16 % 8) as u8;
+        let velocity = if i % 2 == 0 { 100 } else { 0 };
+        let before = allocations();
+        let start = clock.start.elapsed().as_nanos() as u64;
+        callback(i as u64, &[0x90, pad, velocity]);
+        let end = clock.start.elapsed().as_nanos() as u64;
+        let sent = clock.synth_sent.load(Ordering::Acquire);
+        allocated += allocations() - before;
+        to_synth.push(sent.saturating_sub(start));
+        handled.push(end - start);
+        // Presses come a little apart
+        let gap = Instant::now();
+        while gap.elapsed() < Duration::from_micros(50) {
+            std::hint::spin_loop();
+        }
+    }
+
+    if let Some(locked) = locked {
+        locked.stop();
+    }
+
+    println!(
+        "{} pad presses{}",
+        PRESSES,
+        if before { ", as before" } else { "" }
+    );
+    println!(
+        "  allocations per press: {:.2}",
+        allocated as f64 / PRESSES as f64
+    );
+    println!("  {:>6}  {:>10}  {:>10}", "", "to synth", "handled");
+    to_synth.sort_unstable();
+    handled.sort_unstable();
+    let percentile = |latencies: &[u64], p: f64| {
+        let nanos = latencies[((latencies.len() - 1) as f64 * p) as usize];
+        nanos as f64 / 1000.0
+    };
+    for (name, p) in [
+        ("min", 0.0),
+        ("50%", 0.5),
+        ("99%", 0.99),
+        ("99.9%", 0.999),
+        ("max", 1.0),
+    ] {
+        println!(
+            "  {:>6}  {:>7.2} µs  {:>7.2} µs",
+            name,
+            percentile(&to_synth, p),
+            percentile(&handled, p)
+        );
+    }
+    Ok(())
+}
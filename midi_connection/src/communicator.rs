@@ -0,0 +1,785 @@
+//! `MIDICommunicator` owns the connections to one device.  If asked
+//! to it watches for the device's ports disappearing (the Launchpad
+//! unplugged, the synth restarted) and reconnects when they come
+//! back.
+use crate::port::PortMatch;
+use crate::sysex::SysExAssembler;
+use crate::tap::Tap;
+use crate::transport::{
+    InputCallback, InputConnection, Midir, OutputConnection, PortLister, Transport,
+};
+use crate::{default_client_name, Direction, MIDIError, MidiMessage};
+use arc_swap::{ArcSwapOption, Guard};
+use crossbeam_channel::Receiver;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the ports are checked when reconnecting is on.  They are
+/// listed through the same `PortLister` each time, so looking is
+/// cheap.  A device unplugged and plugged back in is gone for longer
+/// than this (a Launchpad takes about a second to start), so it is
+/// seen to go even if it comes back with the same port name
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reported to the `on_event` callback when a connection is lost or
+/// made again, or badly framed SysEx arrives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// The connection to `port` was (re)made
+    Connected { direction: Direction, port: String },
+    /// `port` has gone away
+    Disconnected { direction: Direction, port: String },
+    /// SysEx from `port` was dropped because it was not framed
+    /// correctly
+    BadSysEx { port: String, reason: String },
+}
+
+type EventCallback = Box<dyn FnMut(ConnectionEvent) + Send>;
+
+/// An incoming MIDI message delivered by the receiver from
+/// `MIDICommunicatorBuilder::build_with_receiver`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedMessage {
+    /// The timestamp, in microseconds, from the MIDI system
+    pub stamp: u64,
+    /// When the message was handed to us
+    pub received: Instant,
+    pub bytes: Vec<u8>,
+}
+
+impl TimedMessage {
+    /// The message decoded
+    pub fn message(&self) -> Result<MidiMessage, MIDIError> {
+        MidiMessage::decode(&self.bytes)
+    }
+}
+
+/// What a communicator connects to
+enum Endpoint {
+    /// An existing port, chosen by matching its name
+    Port(PortMatch),
+    /// A port, with this name, that we make for others to connect to
+    Virtual(String),
+}
+
+/// An open output connection, and the name of the port it is to
+struct Output {
+    port: Arc<str>,
+    // Locked only to send.  Taken, closing it, when the port goes
+    // away
+    connection: Mutex<Option<Box<dyn OutputConnection>>>,
+}
+
+impl Output {
+    fn new(port: &str, connection: Box<dyn OutputConnection>) -> Output {
+        Output {
+            port: Arc::from(port),
+            connection: Mutex::new(Some(connection)),
+        }
+    }
+}
+
+/// The output connection, if there is one.  Senders read it without
+/// a lock, so a note is never held up by the thread watching the
+/// ports, nor by other threads sending to other ports.  An output
+/// swapped out is closed at once, and freed when the last sender
+/// looking at it is done
+#[derive(Default)]
+struct OutputSlot {
+    current: ArcSwapOption<Output>,
+}
+
+impl OutputSlot {
+    fn get(&self) -> Guard<Option<Arc<Output>>> {
+        self.current.load()
+    }
+
+    /// Put `output` in the slot.  The output that was there is closed
+    fn swap(&self, output: Option<Output>) {
+        if let Some(old) = self.current.swap(output.map(Arc::new)) {
+            // Closed after the lock is released
+            let closed = old.connection.lock().unwrap().take();
+            drop(closed);
+        }
+    }
+}
+
+/// What is needed to make, and remake, the connections
+struct Shared {
+    transport: Arc<dyn Transport>,
+    endpoint: Endpoint,
+    this_name: String,
+    direction: Direction,
+    // Shared with every input connection made, so it survives
+    // reconnecting
+    callback: Option<Arc<Mutex<InputCallback>>>,
+    // Only SysEx starting with this is delivered
+    sysex_header: Option<Vec<u8>>,
+    // Records messages in and out
+    tap: Option<Tap>,
+    // The open input connection, and the name of the port it is to
+    input: Mutex<Option<(String, Box<dyn InputConnection>)>>,
+    output: OutputSlot,
+    // Shared with the input connections, that report bad SysEx
+    on_event: Arc<Mutex<Option<EventCallback>>>,
+}
+
+impl Shared {
+    /// The name of the virtual port called `port_name` for
+    /// `direction`.  Made for both directions the two ports get their
+    /// own names, `NAME-In` and `NAME-Out`, so what is sent does not
+    /// come back in
+    fn virtual_name(&self, port_name: &str, direction: Direction) -> String {
+        match (self.direction, direction) {
+            (Direction::Both, Direction::Input) => format!("{}-In", port_name),
+            (Direction::Both, _) => format!("{}-Out", port_name),
+            _ => port_name.to_string(),
+        }
+    }
+
+    /// Connect to the output port, from those `lister` lists
+    fn connect_output(&self, lister: &mut dyn PortLister) -> Result<Output, MIDIError> {
+        let port_match = match &self.endpoint {
+            Endpoint::Port(port_match) => port_match,
+            Endpoint::Virtual(port_name) => {
+                let port_name = self.virtual_name(port_name, Direction::Output);
+                let connection = self
+                    .transport
+                    .create_virtual_output(self.this_name.as_str(), port_name.as_str())?;
+                return Ok(Output::new(port_name.as_str(), connection));
+            }
+        };
+        let port_name = port_match.select(&lister.output_ports()?, Direction::Output)?;
+        let connection = self
+            .transport
+            .connect_output(self.this_name.as_str(), port_name.as_str())?;
+        Ok(Output::new(port_name.as_str(), connection))
+    }
+
+    /// The callback for an input connection to `port_name`.  Each
+    /// connection gets its own `SysExAssembler`, so a SysEx message
+    /// cut off by the port going away is not joined to the next one
+    fn input_callback(&self, port_name: &str) -> Result<InputCallback, MIDIError> {
+        let callback = self.callback.clone().ok_or(MIDIError::MissingCallback)?;
+        let mut assembler = match &self.sysex_header {
+            Some(header) => SysExAssembler::with_header(header),
+            None => SysExAssembler::new(),
+        };
+        let on_event = self.on_event.clone();
+        let tap = self.tap.clone();
+        let port_name: Arc<str> = Arc::from(port_name);
+        Ok(Box::new(move |stamp, chunk| {
+            let mut callback = callback.lock().unwrap();
+            let delivered = assembler.push(chunk, |message| {
+                if let Some(tap) = tap.as_ref() {
+                    tap.record(Direction::Input, &port_name, message);
+                }
+                (callback)(stamp, message)
+            });
+            if let Err(err) = delivered {
+                if let Some(on_event) = on_event.lock().unwrap().as_mut() {
+                    on_event(ConnectionEvent::BadSysEx {
+                        port: port_name.to_string(),
+                        reason: err.to_string(),
+                    });
+                }
+            }
+        }))
+    }
+
+    /// Connect to the input port, from those `lister` lists
+    fn connect_input(
+        &self,
+        lister: &mut dyn PortLister,
+    ) -> Result<(String, Box<dyn InputConnection>), MIDIError> {
+        let port_match = match &self.endpoint {
+            Endpoint::Port(port_match) => port_match,
+            Endpoint::Virtual(port_name) => {
+                let port_name = self.virtual_name(port_name, Direction::Input);
+                let connection = self.transport.create_virtual_input(
+                    self.this_name.as_str(),
+                    port_name.as_str(),
+                    self.input_callback(port_name.as_str())?,
+                )?;
+                return Ok((port_name, connection));
+            }
+        };
+        let port_name = port_match.select(&lister.input_ports()?, Direction::Input)?;
+        let callback = self.input_callback(port_name.as_str())?;
+        let connection =
+            self.transport
+                .connect_input(self.this_name.as_str(), port_name.as_str(), callback)?;
+        Ok((port_name, connection))
+    }
+
+    fn report(&self, event: ConnectionEvent) {
+        if let Some(on_event) = self.on_event.lock().unwrap().as_mut() {
+            on_event(event);
+        }
+    }
+
+    /// Check the ports are still there.  Drop connections to ports
+    /// that have gone and try to remake connections that were
+    /// dropped.  The input is only locked to look at it and to swap
+    /// it, never while talking to the MIDI system, and the output is
+    /// swapped without a lock, so sending is not held up
+    fn check_connections(&self, lister: &mut dyn PortLister) {
+        let mut events: Vec<ConnectionEvent> = Vec::new();
+        if self.direction.has_output() {
+            let ports = lister.output_ports().unwrap_or_default();
+            match self.output.get().as_ref().map(|output| output.port.clone()) {
+                Some(port) => {
+                    if !ports.iter().any(|p| **p == *port) {
+                        self.output.swap(None);
+                        events.push(ConnectionEvent::Disconnected {
+                            direction: Direction::Output,
+                            port: port.to_string(),
+                        });
+                    }
+                }
+                None => {
+                    if let Ok(output) = self.connect_output(lister) {
+                        events.push(ConnectionEvent::Connected {
+                            direction: Direction::Output,
+                            port: output.port.to_string(),
+                        });
+                        self.output.swap(Some(output));
+                    }
+                }
+            }
+        }
+        if self.direction.has_input() {
+            let ports = lister.input_ports().unwrap_or_default();
+            let connected = self
+                .input
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|(port, _)| port.clone());
+            match connected {
+                Some(port) => {
+                    if !ports.contains(&port) {
+                        // Dropped after the lock is released
+                        let _gone = self.input.lock().unwrap().take();
+                        events.push(ConnectionEvent::Disconnected {
+                            direction: Direction::Input,
+                            port,
+                        });
+                    }
+                }
+                None => {
+                    if let Ok((port, connection)) = self.connect_input(lister) {
+                        events.push(ConnectionEvent::Connected {
+                            direction: Direction::Input,
+                            port: port.clone(),
+                        });
+                        *self.input.lock().unwrap() = Some((port, connection));
+                    }
+                }
+            }
+        }
+        // Report after the connections are unlocked, so the callback
+        // can send
+        for event in events {
+            self.report(event);
+        }
+    }
+}
+
+/// Builds a `MIDICommunicator`.  Get one from
+/// `MIDICommunicator::builder`
+pub struct MIDICommunicatorBuilder {
+    other_name: String,
+    port_match: Option<PortMatch>,
+    virtual_port: bool,
+    this_name: String,
+    direction: Direction,
+    callback: Option<InputCallback>,
+    transport: Arc<dyn Transport>,
+    reconnect: bool,
+    on_event: Option<EventCallback>,
+    sysex_header: Option<Vec<u8>>,
+    tap: Option<Tap>,
+}
+
+impl MIDICommunicatorBuilder {
+    /// Whether to make an incoming, outgoing, or bidirectional
+    /// connection.  The default is `Direction::Both`
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// `callback` is passed to the input connection to be called
+    /// with each incoming MIDI message.  `data` is passed to
+    /// `callback` in the third parameter.  Required if the direction
+    /// includes input
+    pub fn callback<F, T>(mut self, mut callback: F, mut data: T) -> Self
+    where
+        F: FnMut(u64, &[u8], &mut T) + Send + 'static,
+        T: Send + 'static,
+    {
+        self.callback = Some(Box::new(move |stamp, message| {
+            callback(stamp, message, &mut data)
+        }));
+        self
+    }
+
+    /// How to choose the port to connect to.  This replaces
+    /// `other_name`, that is otherwise parsed as a `PortMatch`
+    pub fn port_match(mut self, port_match: PortMatch) -> Self {
+        self.port_match = Some(port_match);
+        self
+    }
+
+    /// If `virtual_port` is true do not connect to an existing port.
+    /// Instead make ports called `other_name` that synthesisers,
+    /// DAWs and patchbays can connect to, in any order.  An
+    /// `other_name` of the form `virtual:NAME` does the same.  For
+    /// `Direction::Both` the ports are called `NAME-In` and
+    /// `NAME-Out`
+    pub fn virtual_port(mut self, virtual_port: bool) -> Self {
+        self.virtual_port = virtual_port;
+        self
+    }
+
+    /// The MIDI system to connect through.  The default is `Midir`,
+    /// real devices.  Use a `Loopback` to run without hardware
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// If `reconnect` is true watch the ports in the background.
+    /// When a port goes away the connection is dropped, and when it
+    /// comes back it is reconnected.  Off by default
+    pub fn reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// `on_event` is called, from the thread watching the ports,
+    /// each time a connection is lost or remade, if `reconnect` is
+    /// on.  It is also called, from the MIDI thread, when badly
+    /// framed SysEx is dropped
+    pub fn on_event<F>(mut self, on_event: F) -> Self
+    where
+        F: FnMut(ConnectionEvent) + Send + 'static,
+    {
+        self.on_event = Some(Box::new(on_event));
+        self
+    }
+
+    /// Deliver only SysEx messages whose bytes after the 0xF0 start
+    /// with `header`.  Other SysEx is dropped.  Messages that are not
+    /// SysEx are not affected.  Use `LPX_HEADER` for the Launchpad X
+    pub fn sysex_header(mut self, header: &[u8]) -> Self {
+        self.sysex_header = Some(header.to_vec());
+        self
+    }
+
+    /// Record every message delivered and sent in `tap`.  The same
+    /// tap can be given to several communicators.  `None`, for a
+    /// tap that is optional, records nothing
+    pub fn tap<'a>(mut self, tap: impl Into<Option<&'a Tap>>) -> Self {
+        self.tap = tap.into().cloned();
+        self
+    }
+
+    /// Make the connections, with incoming messages delivered over a
+    /// channel instead of to a callback.  This replaces any
+    /// `callback`.  The receiver can be used in a `select!` with
+    /// timers and other channels, and iterating over it blocks until
+    /// each message arrives.  It ends when the communicator is
+    /// dropped
+    pub fn build_with_receiver(
+        mut self,
+    ) -> Result<(MIDICommunicator, Receiver<TimedMessage>), MIDIError> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        self.callback = Some(Box::new(move |stamp, message| {
+            // If the receiver has gone nobody is listening
+            let _ = sender.send(TimedMessage {
+                stamp,
+                received: Instant::now(),
+                bytes: message.to_vec(),
+            });
+        }));
+        Ok((self.build()?, receiver))
+    }
+
+    /// Make the connections
+    pub fn build(self) -> Result<MIDICommunicator, MIDIError> {
+        let endpoint = if let Some(port_name) = self.other_name.strip_prefix("virtual:") {
+            Endpoint::Virtual(port_name.to_string())
+        } else if self.virtual_port {
+            Endpoint::Virtual(self.other_name)
+        } else {
+            match self.port_match {
+                Some(port_match) => Endpoint::Port(port_match),
+                None => Endpoint::Port(self.other_name.parse()?),
+            }
+        };
+        // Virtual ports never go away, there is nothing to watch
+        let reconnect = self.reconnect && matches!(endpoint, Endpoint::Port(_));
+        let shared = Arc::new(Shared {
+            transport: self.transport,
+            endpoint,
+            this_name: self.this_name,
+            direction: self.direction,
+            callback: self.callback.map(|c| Arc::new(Mutex::new(c))),
+            sysex_header: self.sysex_header,
+            tap: self.tap,
+            input: Mutex::new(None),
+            output: OutputSlot::default(),
+            on_event: Arc::new(Mutex::new(self.on_event)),
+        });
+
+        // The ports are listed through this, now and by the watcher
+        let mut lister = shared.transport.port_lister(shared.this_name.as_str())?;
+
+        // if the caller asked for it make an outgoing connection
+        if shared.direction.has_output() {
+            shared
+                .output
+                .swap(Some(shared.connect_output(lister.as_mut())?));
+        }
+
+        // Make the incoming connection if asked for
+        if shared.direction.has_input() {
+            *shared.input.lock().unwrap() = Some(shared.connect_input(lister.as_mut())?);
+        }
+
+        if reconnect {
+            // The watcher stops when the last handle to the
+            // communicator is dropped
+            let weak: Weak<Shared> = Arc::downgrade(&shared);
+            thread::spawn(move || loop {
+                thread::sleep(RECONNECT_INTERVAL);
+                match weak.upgrade() {
+                    Some(shared) => shared.check_connections(lister.as_mut()),
+                    None => break,
+                }
+            });
+        }
+        Ok(MIDICommunicator { shared })
+    }
+}
+
+/// A connection to a MIDI device.  Clones are handles to the same
+/// connections
+#[derive(Clone)]
+pub struct MIDICommunicator {
+    shared: Arc<Shared>,
+}
+impl std::fmt::Debug for MIDICommunicator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("MIDICommunicator").finish()
+    }
+}
+impl MIDICommunicator {
+    /// Start building a MIDICommunicator.  `other_name` is the
+    /// device that will be connected to, as a `PortMatch`, or
+    /// `virtual:NAME` to make a virtual port.  `this_name` is the
+    /// device that this creates that other devices connect to.
+    ///
+    /// ```no_run
+    /// use midi_connection::{Direction, MIDICommunicator};
+    /// let midi_out = MIDICommunicator::builder("Launchpad X:Launchpad X MIDI 1", "120-Proof-1")
+    ///     .direction(Direction::Output)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn builder(other_name: &str, this_name: &str) -> MIDICommunicatorBuilder {
+        MIDICommunicatorBuilder {
+            other_name: other_name.to_string(),
+            port_match: None,
+            virtual_port: false,
+            this_name: this_name.to_string(),
+            direction: Direction::Both,
+            callback: None,
+            transport: Arc::new(Midir),
+            reconnect: false,
+            on_event: None,
+            sysex_header: None,
+            tap: None,
+        }
+    }
+
+    /// Send `msg`.  Only threads sending to the same port wait for
+    /// each other
+    pub fn send(&mut self, msg: &[u8]) -> Result<(), MIDIError> {
+        let output = self.shared.output.get();
+        let output = output
+            .as_ref()
+            .ok_or(MIDIError::NotConnected(Direction::Output))?;
+        match output.connection.lock().unwrap().as_mut() {
+            Some(midi_out_conn) => midi_out_conn.send(msg)?,
+            // The port went away while we looked
+            None => return Err(MIDIError::NotConnected(Direction::Output)),
+        }
+        if let Some(tap) = self.shared.tap.as_ref() {
+            tap.record(Direction::Output, &output.port, msg);
+        }
+        Ok(())
+    }
+
+    /// Send a decoded message.  Notes and other short messages are
+    /// sent without allocating
+    pub fn send_message(&mut self, message: &MidiMessage) -> Result<(), MIDIError> {
+        match message.short_bytes() {
+            Some((bytes, length)) => self.send(&bytes[..length]),
+            None => self.send(&message.encode()),
+        }
+    }
+
+    /// True if the connections asked for are all currently made
+    pub fn is_connected(&self) -> bool {
+        (!self.shared.direction.has_input() || self.shared.input.lock().unwrap().is_some())
+            && (!self.shared.direction.has_output() || self.shared.output.get().is_some())
+    }
+
+    // Lists midi devices that can be used as inputs
+    pub fn get_midi_inputs() -> Result<Vec<String>, MIDIError> {
+        Midir.input_ports(default_client_name().as_str())
+    }
+    // Lists midi devices that can be used as outputs
+    pub fn get_midi_outputs() -> Result<Vec<String>, MIDIError> {
+        Midir.output_ports(default_client_name().as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::loopback_output;
+    use crate::{Loopback, Transport, LPX_HEADER};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    #[test]
+    fn virtual_port_receives_connections() {
+        // A synthesiser connects to our virtual port after it is made
+        let loopback = Loopback::new(&[]);
+        let mut midi_out = loopback_output(&loopback, "virtual:120-Proof-Synth");
+        let (tx, rx) = mpsc::channel();
+        let _synth = loopback
+            .connect_input(
+                "synth",
+                "120-Proof-Synth",
+                Box::new(move |_, message| tx.send(message.to_vec()).unwrap()),
+            )
+            .unwrap();
+        midi_out.send(&[144, 60, 100]).unwrap();
+        assert_eq!(rx.recv().unwrap(), vec![144, 60, 100]);
+    }
+
+    #[test]
+    fn swapped_out_outputs_are_freed() {
+        let loopback = Loopback::new(&["Synth"]);
+        let connect = || {
+            let connection = loopback.connect_output("120-Proof-Test", "Synth").unwrap();
+            Output::new("Synth", connection)
+        };
+        let slot = OutputSlot::default();
+        slot.swap(Some(connect()));
+        let sending = slot.get();
+        let first = Arc::downgrade(sending.as_ref().unwrap());
+        slot.swap(Some(connect()));
+        // Closed at once, but kept while a sender looks at it
+        assert!(sending
+            .as_ref()
+            .unwrap()
+            .connection
+            .lock()
+            .unwrap()
+            .is_none());
+        assert!(first.upgrade().is_some());
+        drop(sending);
+        assert!(first.upgrade().is_none());
+        assert!(slot.get().is_some());
+    }
+
+    #[test]
+    fn virtual_ports_both_ways() {
+        let loopback = Loopback::new(&[]);
+        let (tx, rx) = mpsc::channel();
+        let mut midi = MIDICommunicator::builder("virtual:120-Proof-Echo", "120-Proof-Test")
+            .transport(Arc::new(loopback.clone()))
+            .callback(
+                move |_, message, tx: &mut mpsc::Sender<Vec<u8>>| {
+                    tx.send(message.to_vec()).unwrap()
+                },
+                tx,
+            )
+            .build()
+            .unwrap();
+        // What is sent goes out, and does not come back in
+        midi.send(&[144, 60, 100]).unwrap();
+        assert_eq!(
+            loopback.sent("120-Proof-Echo-Out"),
+            vec![vec![144, 60, 100]]
+        );
+        assert!(rx.try_recv().is_err());
+        loopback.inject("120-Proof-Echo-In", &[144, 61, 100]);
+        assert_eq!(rx.try_recv().unwrap(), vec![144, 61, 100]);
+    }
+
+    #[test]
+    fn receiver_delivers_messages() {
+        let loopback = Loopback::new(&["LPX"]);
+        let (midi_in, receiver) = MIDICommunicator::builder("LPX", "120-Proof-Test")
+            .direction(Direction::Input)
+            .transport(Arc::new(loopback.clone()))
+            .build_with_receiver()
+            .unwrap();
+        loopback.inject("LPX", &[144, 11, 100]);
+        loopback.inject("LPX", &[144, 11, 0]);
+        let received: Vec<Vec<u8>> = receiver.try_iter().map(|m| m.bytes).collect();
+        assert_eq!(received, vec![vec![144, 11, 100], vec![144, 11, 0]]);
+
+        // Dropping the communicator ends the receiver
+        drop(midi_in);
+        assert!(receiver.recv().is_err());
+    }
+
+    #[test]
+    fn sysex_is_reassembled_and_filtered() {
+        let loopback = Loopback::new(&["LPX"]);
+        let (tx, rx) = mpsc::channel();
+        let (_midi_in, receiver) = MIDICommunicator::builder("LPX", "120-Proof-Test")
+            .direction(Direction::Input)
+            .transport(Arc::new(loopback.clone()))
+            .sysex_header(&LPX_HEADER)
+            .on_event(move |event| tx.send(event).unwrap())
+            .build_with_receiver()
+            .unwrap();
+        // A mode reply in two pieces, a reply from something else and
+        // a SysEx cut short by a note
+        loopback.inject("LPX", &[240, 0, 32, 41]);
+        loopback.inject("LPX", &[2, 12, 0, 1, 247]);
+        loopback.inject("LPX", &[240, 126, 127, 6, 2, 247]);
+        loopback.inject("LPX", &[240, 0, 32]);
+        loopback.inject("LPX", &[144, 11, 100]);
+        let received: Vec<Vec<u8>> = receiver.try_iter().map(|m| m.bytes).collect();
+        assert_eq!(
+            received,
+            vec![vec![240, 0, 32, 41, 2, 12, 0, 1, 247], vec![144, 11, 100]]
+        );
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ConnectionEvent::BadSysEx { .. })
+        ));
+    }
+
+    #[test]
+    fn reconnects_when_port_returns() {
+        let loopback = Loopback::new(&["Synth"]);
+        let (tx, rx) = mpsc::channel();
+        let mut midi_out = MIDICommunicator::builder("Synth", "120-Proof-Test")
+            .direction(Direction::Output)
+            .transport(Arc::new(loopback.clone()))
+            .reconnect(true)
+            .on_event(move |event| tx.send(event).unwrap())
+            .build()
+            .unwrap();
+
+        loopback.remove_port("Synth");
+        assert_eq!(
+            rx.recv().unwrap(),
+            ConnectionEvent::Disconnected {
+                direction: Direction::Output,
+                port: "Synth".to_string()
+            }
+        );
+        assert!(midi_out.send(&[144, 60, 100]).is_err());
+
+        loopback.add_port("Synth");
+        assert_eq!(
+            rx.recv().unwrap(),
+            ConnectionEvent::Connected {
+                direction: Direction::Output,
+                port: "Synth".to_string()
+            }
+        );
+        midi_out.send(&[144, 60, 100]).unwrap();
+        assert_eq!(loopback.sent("Synth"), vec![vec![144, 60, 100]]);
+    }
+
+    /// A `Loopback` that counts the times the ports are listed
+    /// without a `PortLister`, each of which would be a new ALSA client
+    struct Counting {
+        loopback: Loopback,
+        listed: Arc<AtomicUsize>,
+    }
+
+    impl Transport for Counting {
+        fn input_ports(&self, client_name: &str) -> Result<Vec<String>, MIDIError> {
+            self.listed.fetch_add(1, Ordering::SeqCst);
+            self.loopback.input_ports(client_name)
+        }
+        fn output_ports(&self, client_name: &str) -> Result<Vec<String>, MIDIError> {
+            self.listed.fetch_add(1, Ordering::SeqCst);
+            self.loopback.output_ports(client_name)
+        }
+        fn port_lister(&self, client_name: &str) -> Result<Box<dyn PortLister>, MIDIError> {
+            self.loopback.port_lister(client_name)
+        }
+        fn connect_input(
+            &self,
+            client_name: &str,
+            port_name: &str,
+            callback: InputCallback,
+        ) -> Result<Box<dyn InputConnection>, MIDIError> {
+            self.loopback
+                .connect_input(client_name, port_name, callback)
+        }
+        fn connect_output(
+            &self,
+            client_name: &str,
+            port_name: &str,
+        ) -> Result<Box<dyn OutputConnection>, MIDIError> {
+            self.loopback.connect_output(client_name, port_name)
+        }
+        fn create_virtual_input(
+            &self,
+            client_name: &str,
+            port_name: &str,
+            callback: InputCallback,
+        ) -> Result<Box<dyn InputConnection>, MIDIError> {
+            self.loopback
+                .create_virtual_input(client_name, port_name, callback)
+        }
+        fn create_virtual_output(
+            &self,
+            client_name: &str,
+            port_name: &str,
+        ) -> Result<Box<dyn OutputConnection>, MIDIError> {
+            self.loopback.create_virtual_output(client_name, port_name)
+        }
+    }
+
+    #[test]
+    fn watches_through_one_lister() {
+        let loopback = Loopback::new(&["LPX"]);
+        let listed = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = mpsc::channel();
+        let _lpx = MIDICommunicator::builder("LPX", "120-Proof-Test")
+            .transport(Arc::new(Counting {
+                loopback: loopback.clone(),
+                listed: listed.clone(),
+            }))
+            .callback(|_, _, _| (), ())
+            .reconnect(true)
+            .on_event(move |event| tx.send(event).unwrap())
+            .build()
+            .unwrap();
+        loopback.remove_port("LPX");
+        rx.recv().unwrap();
+        rx.recv().unwrap();
+        loopback.add_port("LPX");
+        rx.recv().unwrap();
+        rx.recv().unwrap();
+        assert_eq!(listed.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,388 @@
+//! Finding a Launchpad X without being told its port names.  The
+//! names differ between ALSA, JACK bridges and firmware, so they are
+//! guessed with `lpx_interface` and then checked by asking the device
+//! who it is (a Universal Device Inquiry, sent to the DAW interface).
+//!
+//! If one Launchpad X is found by name it is used even if it does
+//! not answer (it may be busy starting up), and `firmware` is
+//! `None`.  With more than one the ports are paired by ALSA client,
+//! and only one of them may answer.  Then the ports are matched by
+//! ALSA numbers, as the names are the same.
+//!
+//! To choose between Launchpads that all answer, or skip looking,
+//! the programmes take `--lpx-in PORT` and `--lpx-out PORT` (see
+//! `LpxArgs`).
+use crate::transport::Transport;
+use crate::{
+    alsa_id, default_client_name, lpx_interface, strip_alsa_id, Direction, LpxInterface, MIDIError,
+    Midir,
+};
+use std::time::Duration;
+
+/// Asks every device on the port to identify itself
+pub const DEVICE_INQUIRY: [u8; 6] = [0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7];
+
+/// How long to wait for the Launchpad X to answer
+const IDENTITY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The ports of a Launchpad X.  Each is a `PortMatch` (`exact:NAME`
+/// or `alsa:CLIENT:PORT`) that can be given to
+/// `MIDICommunicator::builder`, or written in `midi.cfg`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LpxPorts {
+    /// Notes from the pads come in here
+    pub midi_in: String,
+    /// Lighting and mode SysEx go out here
+    pub daw_out: String,
+    /// Replies to SysEx come in here, if it was found
+    pub daw_in: Option<String>,
+    /// The application version from the device's answer.  `None` if
+    /// it did not answer
+    pub firmware: Option<[u8; 4]>,
+}
+
+/// The firmware version, if `reply` is a Launchpad X's answer to a
+/// `DEVICE_INQUIRY`
+pub fn lpx_identity(reply: &[u8]) -> Option<[u8; 4]> {
+    match reply {
+        // Novation, family 0x03 (Launchpad X), any member (the
+        // bootloader answers too)
+        [0xF0, 0x7E, _, 0x06, 0x02, 0x00, 0x20, 0x29, 0x03, _, _, _, version @ .., 0xF7]
+            if version.len() == 4 =>
+        {
+            Some([version[0], version[1], version[2], version[3]])
+        }
+        _ => None,
+    }
+}
+
+/// How to match `port_name` among `ports`.  By name, without the
+/// ALSA numbers that change when it is replugged, unless another port
+/// has the same name
+fn port_spec(port_name: &str, ports: &[String]) -> String {
+    let name = strip_alsa_id(port_name);
+    let same_name = ports.iter().filter(|p| strip_alsa_id(p) == name).count();
+    match alsa_id(port_name) {
+        Some((client, port)) if same_name > 1 => format!("alsa:{}:{}", client, port),
+        _ => format!("exact:{}", name),
+    }
+}
+
+/// Possible Launchpads, from the port names
+fn candidates(inputs: &[String], outputs: &[String]) -> Vec<LpxPorts> {
+    let of = |ports: &[String], interface| -> Vec<String> {
+        ports
+            .iter()
+            .filter(|p| lpx_interface(p) == Some(interface))
+            .cloned()
+            .collect()
+    };
+    let daw_outs = of(outputs, LpxInterface::Daw);
+    let daw_ins = of(inputs, LpxInterface::Daw);
+    let midi_ins = of(inputs, LpxInterface::Midi);
+    if let ([daw_out], [midi_in]) = (daw_outs.as_slice(), midi_ins.as_slice()) {
+        return vec![LpxPorts {
+            midi_in: midi_in.clone(),
+            daw_out: daw_out.clone(),
+            daw_in: match daw_ins.as_slice() {
+                [daw_in] => Some(daw_in.clone()),
+                _ => None,
+            },
+            firmware: None,
+        }];
+    }
+
+    // More than one.  The ports of each are on the same ALSA client
+    daw_outs
+        .iter()
+        .filter_map(|daw_out| {
+            let client = alsa_id(daw_out)?.0;
+            let same = |port: &&String| alsa_id(port).map(|id| id.0) == Some(client);
+            Some(LpxPorts {
+                midi_in: midi_ins.iter().find(same)?.clone(),
+                daw_out: daw_out.clone(),
+                daw_in: daw_ins.iter().find(same).cloned(),
+                firmware: None,
+            })
+        })
+        .collect()
+}
+
+/// Take `flag PORT` out of `args`, and return `PORT`
+fn port_from_args(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, MIDIError> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) if i + 1 < args.len() => {
+            let port = args.remove(i + 1);
+            args.remove(i);
+            Ok(Some(port))
+        }
+        Some(_) => Err(MIDIError::BadArgument {
+            argument: flag.to_string(),
+            reason: "expected the name of a port after it".to_string(),
+        }),
+        None => Ok(None),
+    }
+}
+
+/// `--lpx-in PORT` and `--lpx-out PORT`, taken out of the arguments
+/// so the rest can be checked before the Launchpad X is looked for.
+/// Each `PORT` is a `PortMatch`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LpxArgs {
+    pub midi_in: Option<String>,
+    pub daw_out: Option<String>,
+}
+
+impl LpxArgs {
+    /// Take `--lpx-in PORT` and `--lpx-out PORT` out of `args`
+    pub fn from_args(args: &mut Vec<String>) -> Result<LpxArgs, MIDIError> {
+        Ok(LpxArgs {
+            midi_in: port_from_args(args, "--lpx-in")?,
+            daw_out: port_from_args(args, "--lpx-out")?,
+        })
+    }
+
+    /// The Launchpad X's ports, looking for it only if one of them
+    /// was not given.  With both given, `daw_in` is taken to have the
+    /// same name as `daw_out`, as it does in ALSA
+    pub fn find(self, client_name: &str) -> Result<LpxPorts, MIDIError> {
+        self.find_with(&Midir, client_name, IDENTITY_TIMEOUT)
+    }
+
+    /// `find`, through `transport`
+    pub fn find_with(
+        self,
+        transport: &dyn Transport,
+        client_name: &str,
+        timeout: Duration,
+    ) -> Result<LpxPorts, MIDIError> {
+        match (self.midi_in, self.daw_out) {
+            (Some(midi_in), Some(daw_out)) => Ok(LpxPorts {
+                midi_in,
+                daw_in: Some(daw_out.clone()),
+                daw_out,
+                firmware: None,
+            }),
+            (midi_in, daw_out) => {
+                let found = LpxPorts::find_with(transport, client_name, timeout)?;
+                Ok(LpxPorts {
+                    midi_in: midi_in.unwrap_or(found.midi_in),
+                    daw_out: daw_out.unwrap_or(found.daw_out),
+                    ..found
+                })
+            }
+        }
+    }
+}
+
+/// Send a `DEVICE_INQUIRY` to `candidate` and wait for it to answer
+fn identify(
+    transport: &dyn Transport,
+    client_name: &str,
+    candidate: &LpxPorts,
+    timeout: Duration,
+) -> Result<Option<[u8; 4]>, MIDIError> {
+    let daw_in = match candidate.daw_in.as_ref() {
+        Some(daw_in) => daw_in,
+        None => return Ok(None),
+    };
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    let _input = transport.connect_input(
+        client_name,
+        daw_in,
+        Box::new(move |_, message| {
+            if let Some(firmware) = lpx_identity(message) {
+                let _ = sender.try_send(firmware);
+            }
+        }),
+    )?;
+    transport
+        .connect_output(client_name, candidate.daw_out.as_str())?
+        .send(&DEVICE_INQUIRY)?;
+    Ok(receiver.recv_timeout(timeout).ok())
+}
+
+impl LpxPorts {
+    /// Find the Launchpad X plugged in to this computer
+    pub fn find() -> Result<LpxPorts, MIDIError> {
+        Self::find_as(default_client_name().as_str())
+    }
+
+    /// Take `--lpx-in PORT` (`midi_in`) and `--lpx-out PORT`
+    /// (`daw_out`) out of `args`, and find the Launchpad X only if
+    /// one of them is not there.  See `LpxArgs`
+    pub fn from_args(args: &mut Vec<String>, client_name: &str) -> Result<LpxPorts, MIDIError> {
+        LpxArgs::from_args(args)?.find(client_name)
+    }
+
+    /// `from_args`, finding the Launchpad X through `transport`
+    pub fn from_args_with(
+        args: &mut Vec<String>,
+        transport: &dyn Transport,
+        client_name: &str,
+        timeout: Duration,
+    ) -> Result<LpxPorts, MIDIError> {
+        LpxArgs::from_args(args)?.find_with(transport, client_name, timeout)
+    }
+
+    /// Find the Launchpad X, known to the MIDI system as
+    /// `client_name` while looking
+    pub fn find_as(client_name: &str) -> Result<LpxPorts, MIDIError> {
+        Self::find_with(&Midir, client_name, IDENTITY_TIMEOUT)
+    }
+
+    /// Find a Launchpad X through `transport`, waiting up to
+    /// `timeout` for each one to answer
+    pub fn find_with(
+        transport: &dyn Transport,
+        client_name: &str,
+        timeout: Duration,
+    ) -> Result<LpxPorts, MIDIError> {
+        let client_name = format!("{}-Detect", client_name);
+        let client_name = client_name.as_str();
+        let inputs = transport.input_ports(client_name)?;
+        let outputs = transport.output_ports(client_name)?;
+        let mut found = candidates(&inputs, &outputs);
+        if found.is_empty() {
+            return Err(MIDIError::NoMatchingPort {
+                direction: Direction::Output,
+                requested: "a Launchpad X".to_string(),
+                available: outputs,
+            });
+        }
+        for candidate in found.iter_mut() {
+            // A port that cannot be opened just does not answer
+            candidate.firmware =
+                identify(transport, client_name, candidate, timeout).unwrap_or(None);
+        }
+        if found.len() > 1 {
+            found.retain(|candidate| candidate.firmware.is_some());
+        }
+        match found.len() {
+            1 => {
+                let mut lpx = found.remove(0);
+                lpx.midi_in = port_spec(lpx.midi_in.as_str(), &inputs);
+                lpx.daw_out = port_spec(lpx.daw_out.as_str(), &outputs);
+                lpx.daw_in = lpx.daw_in.map(|daw_in| port_spec(daw_in.as_str(), &inputs));
+                Ok(lpx)
+            }
+            0 => Err(MIDIError::NoMatchingPort {
+                direction: Direction::Output,
+                requested: "a Launchpad X that answers".to_string(),
+                available: outputs,
+            }),
+            _ => Err(MIDIError::AmbiguousPort {
+                direction: Direction::Output,
+                requested: "a Launchpad X".to_string(),
+                matched: found.into_iter().map(|lpx| lpx.daw_out).collect(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Loopback;
+
+    const REPLY: [u8; 17] = [
+        0xF0, 0x7E, 0x00, 0x06, 0x02, 0x00, 0x20, 0x29, 0x03, 0x01, 0x00, 0x00, 0x00, 0x04, 0x05,
+        0x02, 0xF7,
+    ];
+
+    /// A Launchpad that answers inquiries on `daw_port`
+    fn answer(loopback: &Loopback, daw_port: &'static str) -> Box<dyn crate::InputConnection> {
+        let device = loopback.clone();
+        loopback
+            .connect_input(
+                "LPX",
+                daw_port,
+                Box::new(move |_, message| {
+                    if message == DEVICE_INQUIRY {
+                        device.inject(daw_port, &REPLY);
+                    }
+                }),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn identity() {
+        assert_eq!(lpx_identity(&REPLY), Some([0, 4, 5, 2]));
+        assert_eq!(lpx_identity(&REPLY[..16]), None);
+        assert_eq!(lpx_identity(&DEVICE_INQUIRY), None);
+    }
+
+    #[test]
+    fn finds_the_one_that_answers() {
+        let loopback = Loopback::new(&[
+            "Midi Through:Midi Through Port-0 14:0",
+            "Launchpad X:Launchpad X MIDI 1 24:0",
+            "Launchpad X:Launchpad X MIDI 2 24:1",
+            "Launchpad X:Launchpad X MIDI 1 28:0",
+            "Launchpad X:Launchpad X MIDI 2 28:1",
+        ]);
+        // Only the second one answers
+        let _lpx = answer(&loopback, "Launchpad X:Launchpad X MIDI 1 28:0");
+        let lpx =
+            LpxPorts::find_with(&loopback, "120-Proof-Test", Duration::from_millis(200)).unwrap();
+        assert_eq!(lpx.daw_out, "alsa:28:0");
+        assert_eq!(lpx.midi_in, "alsa:28:1");
+        assert_eq!(lpx.firmware, Some([0, 4, 5, 2]));
+    }
+
+    #[test]
+    fn one_by_name() {
+        let loopback = Loopback::new(&["LPX DAW Out", "LPX MIDI In"]);
+        let lpx =
+            LpxPorts::find_with(&loopback, "120-Proof-Test", Duration::from_millis(20)).unwrap();
+        assert_eq!(lpx.midi_in, "exact:LPX MIDI In");
+        assert_eq!(lpx.daw_out, "exact:LPX DAW Out");
+        assert_eq!(lpx.firmware, None);
+        assert!(matches!(
+            LpxPorts::find_with(&Loopback::new(&["Synth"]), "120-Proof-Test", Duration::ZERO),
+            Err(MIDIError::NoMatchingPort { .. })
+        ));
+    }
+
+    #[test]
+    fn ports_from_args() {
+        let args = |line: &str| -> Vec<String> { line.split(' ').map(String::from).collect() };
+        // Two that do not answer can only be told apart by the user
+        let loopback = Loopback::new(&[
+            "Launchpad X:Launchpad X MIDI 1 24:0",
+            "Launchpad X:Launchpad X MIDI 2 24:1",
+            "Launchpad X:Launchpad X MIDI 1 28:0",
+            "Launchpad X:Launchpad X MIDI 2 28:1",
+        ]);
+        let find = |args: &mut Vec<String>| {
+            LpxPorts::from_args_with(args, &loopback, "120-Proof-Test", Duration::from_millis(20))
+        };
+        assert!(find(&mut args("lpx_mode 127")).is_err());
+        let mut both = args("lpx_mode --lpx-in alsa:28:1 127 --lpx-out alsa:28:0");
+        let lpx = find(&mut both).unwrap();
+        assert_eq!(both, args("lpx_mode 127"));
+        assert_eq!(lpx.midi_in, "alsa:28:1");
+        assert_eq!(lpx.daw_out, "alsa:28:0");
+        assert_eq!(lpx.daw_in.as_deref(), Some("alsa:28:0"));
+
+        // The other is looked for
+        let loopback = Loopback::new(&["LPX DAW Out", "LPX MIDI In"]);
+        let mut one = args("lpx_colour --lpx-out exact:Other 11 5");
+        let lpx = LpxPorts::from_args_with(&mut one, &loopback, "120-Proof-Test", Duration::ZERO)
+            .unwrap();
+        assert_eq!(lpx.midi_in, "exact:LPX MIDI In");
+        assert_eq!(lpx.daw_out, "exact:Other");
+        assert!(matches!(
+            find(&mut args("lpx_mode --lpx-in")),
+            Err(MIDIError::BadArgument { .. })
+        ));
+
+        // Taken out without looking, so the rest can be checked first
+        let mut many = args("lpx_mode --lpx-out alsa:28:0 127 1");
+        let lpx = LpxArgs::from_args(&mut many).unwrap();
+        assert_eq!(many, args("lpx_mode 127 1"));
+        assert_eq!(lpx.midi_in, None);
+        assert_eq!(lpx.daw_out.as_deref(), Some("alsa:28:0"));
+    }
+}
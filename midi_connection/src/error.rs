@@ -0,0 +1,266 @@
+//! The errors `MIDICommunicator` can report.  They carry enough
+//! detail (port names, the ports that were available) that a user
+//! with a slightly wrong `midi.cfg` can see what to fix.
+use lpx_protocol::ProtocolError;
+use std::error::Error;
+use std::fmt;
+
+/// Which way MIDI flows through a connection.  `Input` is MIDI
+/// coming from the other device into us, `Output` is MIDI we send to
+/// the other device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+    Both,
+}
+
+impl Direction {
+    /// True if this direction includes an incoming connection
+    pub fn has_input(&self) -> bool {
+        matches!(self, Direction::Input | Direction::Both)
+    }
+
+    /// True if this direction includes an outgoing connection
+    pub fn has_output(&self) -> bool {
+        matches!(self, Direction::Output | Direction::Both)
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Input => write!(f, "input"),
+            Direction::Output => write!(f, "output"),
+            Direction::Both => write!(f, "input/output"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MIDIError {
+    /// The MIDI system could not be initialised for the client
+    /// `client_name`.  Usually the ALSA sequencer is not available
+    Init { client_name: String, reason: String },
+
+    /// No port called `requested` could be found.  `available` is
+    /// every port that was there to choose from
+    NoMatchingPort {
+        direction: Direction,
+        requested: String,
+        available: Vec<String>,
+    },
+
+    /// More than one port matched `requested`
+    AmbiguousPort {
+        direction: Direction,
+        requested: String,
+        matched: Vec<String>,
+    },
+
+    /// `spec` is not a valid port match
+    BadPortMatch { spec: String, reason: String },
+
+    /// `spec` is not a valid set of `Layer` settings
+    BadLayer { spec: String, reason: String },
+
+    /// A command line argument cannot be understood
+    BadArgument { argument: String, reason: String },
+
+    /// Signals to stop cannot be caught
+    Signal(String),
+
+    /// A Launchpad X command was asked for with an argument out of
+    /// range
+    Protocol(ProtocolError),
+
+    /// The port exists but connecting to it failed
+    Connect {
+        direction: Direction,
+        port: String,
+        reason: String,
+    },
+
+    /// The name of a port could not be read
+    PortInfo(String),
+
+    /// An incoming connection was asked for without a callback to
+    /// handle the messages
+    MissingCallback,
+
+    /// Tried to send on a communicator that has no output connection
+    NotConnected(Direction),
+
+    /// The message could not be sent
+    Send(String),
+
+    /// `bytes` is not a MIDI message
+    BadMessage {
+        bytes: Vec<u8>,
+        reason: &'static str,
+    },
+
+    /// The MIDI system cannot do what was asked
+    Unsupported(&'static str),
+
+    /// Reading or writing a file failed
+    Io(String),
+
+    /// Line `line` of a recorded session cannot be read
+    BadRecord { line: usize, reason: String },
+
+    /// Line `line` of a routing configuration cannot be understood
+    BadRoute { line: usize, reason: String },
+
+    /// Replayed output is not the same as the golden output.
+    /// `index` is the first record that differs
+    GoldenMismatch {
+        index: usize,
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+}
+
+impl fmt::Display for MIDIError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MIDIError::Init {
+                client_name,
+                reason,
+            } => write!(
+                f,
+                "Cannot initialise MIDI for client \"{}\": {}",
+                client_name, reason
+            ),
+            MIDIError::NoMatchingPort {
+                direction,
+                requested,
+                available,
+            } => {
+                write!(f, "No MIDI {} port matches \"{}\".", direction, requested)?;
+                if available.is_empty() {
+                    write!(f, "  There are no MIDI {} ports", direction)
+                } else {
+                    write!(f, "  Available {} ports:", direction)?;
+                    for port in available {
+                        write!(f, "\n    {}", port)?;
+                    }
+                    Ok(())
+                }
+            }
+            MIDIError::AmbiguousPort {
+                direction,
+                requested,
+                matched,
+            } => {
+                write!(
+                    f,
+                    "More than one MIDI {} port matches \"{}\":",
+                    direction, requested
+                )?;
+                for port in matched {
+                    write!(f, "\n    {}", port)?;
+                }
+                Ok(())
+            }
+            MIDIError::BadPortMatch { spec, reason } => {
+                write!(f, "Cannot understand port \"{}\": {}", spec, reason)
+            }
+            MIDIError::BadLayer { spec, reason } => {
+                write!(f, "Cannot understand settings \"{}\": {}", spec, reason)
+            }
+            MIDIError::BadArgument { argument, reason } => {
+                write!(f, "Cannot understand \"{}\": {}", argument, reason)
+            }
+            MIDIError::Signal(reason) => write!(f, "Cannot catch signals: {}", reason),
+            MIDIError::Connect {
+                direction,
+                port,
+                reason,
+            } => write!(
+                f,
+                "Cannot make {} connection to \"{}\": {}",
+                direction, port, reason
+            ),
+            MIDIError::PortInfo(reason) => {
+                write!(f, "Cannot read MIDI port name: {}", reason)
+            }
+            MIDIError::MissingCallback => {
+                write!(f, "An input connection needs a callback")
+            }
+            MIDIError::NotConnected(direction) => {
+                write!(f, "There is no {} connection", direction)
+            }
+            MIDIError::Send(reason) => write!(f, "Failed to send MIDI: {}", reason),
+            MIDIError::BadMessage { bytes, reason } => {
+                write!(f, "Bad MIDI message {:?}: {}", bytes, reason)
+            }
+            MIDIError::Protocol(err) => write!(f, "{}", err),
+            MIDIError::Unsupported(reason) => write!(f, "Not supported: {}", reason),
+            MIDIError::Io(reason) => write!(f, "File error: {}", reason),
+            MIDIError::BadRecord { line, reason } => {
+                write!(f, "Cannot read recorded MIDI line {}: {}", line, reason)
+            }
+            MIDIError::BadRoute { line, reason } => {
+                write!(f, "Cannot understand routing line {}: {}", line, reason)
+            }
+            MIDIError::GoldenMismatch {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Output differs from golden at record {}:\n    expected: {}\n    actual:   {}",
+                index,
+                expected.as_deref().unwrap_or("(nothing)"),
+                actual.as_deref().unwrap_or("(nothing)")
+            ),
+        }
+    }
+}
+
+impl Error for MIDIError {}
+
+impl From<midir::PortInfoError> for MIDIError {
+    fn from(err: midir::PortInfoError) -> Self {
+        MIDIError::PortInfo(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for MIDIError {
+    fn from(err: std::io::Error) -> Self {
+        MIDIError::Io(err.to_string())
+    }
+}
+
+impl From<ProtocolError> for MIDIError {
+    fn from(err: ProtocolError) -> Self {
+        MIDIError::Protocol(err)
+    }
+}
+
+impl From<midir::SendError> for MIDIError {
+    fn from(err: midir::SendError) -> Self {
+        MIDIError::Send(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn no_matching_port_lists_candidates() {
+        let err = MIDIError::NoMatchingPort {
+            direction: Direction::Output,
+            requested: "Launchpad X:Launchpad X MIDI 3".to_string(),
+            available: vec![
+                "Midi Through:Midi Through Port-0 14:0".to_string(),
+                "Launchpad X:Launchpad X MIDI 1 24:0".to_string(),
+            ],
+        };
+        let msg = err.to_string();
+        assert!(msg.contains("\"Launchpad X:Launchpad X MIDI 3\""));
+        assert!(msg.contains("\n    Midi Through:Midi Through Port-0 14:0"));
+        assert!(msg.contains("\n    Launchpad X:Launchpad X MIDI 1 24:0"));
+    }
+}
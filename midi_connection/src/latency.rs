@@ -0,0 +1,420 @@
+//! Measuring how long a pad press takes, to tell whether lag comes
+//! from the LPX, from us, or from the synthesiser.
+//!
+//! Each message from the MIDI system comes with a timestamp (in
+//! microseconds) from when it arrived.  That clock is not ours, and
+//! it starts again with each connection, so a `StampClock` finds the
+//! difference between them: the smallest gap seen between a stamp
+//! and our clock is taken as a message delivered at once.  After
+//! that the time a message waited before it was handled is known,
+//! as well as how long it took to handle.
+//!
+//! Two times are measured, and kept in a `Histogram`:
+//!
+//! * From the pad's message arriving until the note is sent to the
+//!   synthesisers
+//!
+//! * With the synthesiser port looped back to an input, from the pad's
+//!   message arriving until the note comes back.  The whole round
+//!   trip through the MIDI system
+//!
+//! Recording a time does not allocate or lock, so it can be done on
+//! the note path.
+use crate::MIDIError;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Below this each microsecond has its own bucket
+const LINEAR: u64 = 16;
+
+/// Above `LINEAR` each power of two is split in `1 << SUB_BITS`
+/// buckets, so a time is known to within an eighth
+const SUB_BITS: u32 = 3;
+
+/// Longer times (about nineteen hours) go in the last bucket
+const LONGEST: u64 = (1 << 36) - 1;
+
+const BUCKETS: usize = LINEAR as usize + (36 - 4) * (1 << SUB_BITS);
+
+/// If a stamp seems to have waited longer than this (microseconds)
+/// the MIDI system's clock has started again, as it does when a port
+/// is reconnected
+const CLOCK_RESTARTED: i64 = 1_000_000;
+
+/// The bucket that `micros` goes in
+fn bucket(micros: u64) -> usize {
+    let micros = micros.min(LONGEST);
+    if micros < LINEAR {
+        micros as usize
+    } else {
+        let msb = 63 - micros.leading_zeros();
+        let sub = (micros >> (msb - SUB_BITS)) & ((1 << SUB_BITS) - 1);
+        LINEAR as usize + (msb as usize - 4) * (1 << SUB_BITS) + sub as usize
+    }
+}
+
+/// The shortest time in bucket `index`
+fn lowest(index: usize) -> u64 {
+    if index < LINEAR as usize {
+        index as u64
+    } else {
+        let msb = (index - LINEAR as usize) as u32 / (1 << SUB_BITS) + 4;
+        let sub = (index - LINEAR as usize) as u64 % (1 << SUB_BITS);
+        ((1 << SUB_BITS) + sub) << (msb - SUB_BITS)
+    }
+}
+
+/// A time for people: microseconds, milliseconds or seconds
+fn human(micros: u64) -> String {
+    if micros < 1_000 {
+        format!("{}µs", micros)
+    } else if micros < 1_000_000 {
+        format!("{:.1}ms", micros as f64 / 1_000.0)
+    } else {
+        format!("{:.2}s", micros as f64 / 1_000_000.0)
+    }
+}
+
+/// Counts of times, in microseconds.  Displayed it is a bar for each
+/// power of two, and the percentiles
+#[derive(Debug)]
+pub struct Histogram {
+    counts: Vec<AtomicU64>,
+    count: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            counts: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, micros: u64) {
+        self.counts[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// How many times have been recorded
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    /// The time that `fraction` (e.g. 0.99) of times are no longer
+    /// than.  It is the longest time in its bucket, so may be up to
+    /// an eighth more than the real one.  0 if nothing is recorded
+    pub fn percentile(&self, fraction: f64) -> u64 {
+        let rank = ((self.count() as f64 * fraction).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count.load(Ordering::Relaxed);
+            if seen >= rank {
+                return (lowest(index + 1) - 1).min(self.max());
+            }
+        }
+        self.max()
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.count() == 0 {
+            return writeln!(f, "  Nothing measured");
+        }
+        // Add up the buckets for each power of two.  Row `n` is the
+        // times `n` bits long
+        let mut rows = [0_u64; 37];
+        for (index, count) in self.counts.iter().enumerate() {
+            let bits = 64 - lowest(index).leading_zeros() as usize;
+            rows[bits] += count.load(Ordering::Relaxed);
+        }
+        let first = rows.iter().position(|&c| c > 0).unwrap_or(0);
+        let last = rows.iter().rposition(|&c| c > 0).unwrap_or(0);
+        let most = rows.iter().max().copied().unwrap_or(1);
+        for (bits, &count) in rows.iter().enumerate().take(last + 1).skip(first) {
+            let (from, to) = match bits {
+                0 => (0, 1),
+                _ => (1 << (bits - 1), 1 << bits),
+            };
+            writeln!(
+                f,
+                "  {:>7} - {:<7} {:>8} {}",
+                human(from),
+                human(to),
+                count,
+                "#".repeat((count * 40).div_ceil(most) as usize)
+            )?;
+        }
+        writeln!(
+            f,
+            "  {} measured.  50%: {}  90%: {}  99%: {}  99.9%: {}  max: {}",
+            self.count(),
+            human(self.percentile(0.5)),
+            human(self.percentile(0.9)),
+            human(self.percentile(0.99)),
+            human(self.percentile(0.999)),
+            human(self.max())
+        )
+    }
+}
+
+/// Turns the timestamps of one input connection into our time.  Each
+/// connection needs its own
+#[derive(Debug, Default)]
+pub struct StampClock {
+    // Our time less the stamp, for the message that waited least
+    offset: Option<i64>,
+}
+
+impl StampClock {
+    pub fn new() -> StampClock {
+        StampClock { offset: None }
+    }
+
+    /// When a message with `stamp` arrived, in our time.  `now` is
+    /// our time
+    pub fn arrived(&mut self, stamp: u64, now: u64) -> u64 {
+        let offset = now as i64 - stamp as i64;
+        match self.offset {
+            Some(least) if offset >= least && offset - least < CLOCK_RESTARTED => (),
+            _ => self.offset = Some(offset),
+        }
+        (stamp as i64 + self.offset.unwrap()).clamp(0, now as i64) as u64
+    }
+}
+
+/// What is measured, from the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Measure {
+    Off,
+    /// From a pad's message arriving to the note being sent
+    Adapter,
+    /// As well, the round trip until the note comes back in on this
+    /// port
+    RoundTrip(String),
+}
+
+/// Take `--latency` and `--latency-loopback PORT` out of `args`.
+/// `PORT` is an input the synthesiser's port is connected to
+pub fn latency_from_args(args: &mut Vec<String>) -> Result<Measure, MIDIError> {
+    let mut measure = Measure::Off;
+    if let Some(i) = args.iter().position(|arg| arg == "--latency") {
+        args.remove(i);
+        measure = Measure::Adapter;
+    }
+    match args.iter().position(|arg| arg == "--latency-loopback") {
+        Some(i) if i + 1 < args.len() => {
+            measure = Measure::RoundTrip(args.remove(i + 1));
+            args.remove(i);
+        }
+        Some(_) => {
+            return Err(MIDIError::BadArgument {
+                argument: "--latency-loopback".to_string(),
+                reason: "expected the name of a port after it".to_string(),
+            })
+        }
+        None => (),
+    }
+    Ok(measure)
+}
+
+/// The measurements.  Clones share them
+#[derive(Debug, Clone)]
+pub struct Latency {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    start: Instant,
+    adapter: Histogram,
+    round_trip: Histogram,
+    // For each note, when the pad press it came from arrived, plus
+    // one.  0 if it is not on its way back
+    on_the_way: Vec<AtomicU64>,
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Latency {
+    pub fn new() -> Latency {
+        Latency {
+            inner: Arc::new(Inner {
+                start: Instant::now(),
+                adapter: Histogram::new(),
+                round_trip: Histogram::new(),
+                on_the_way: (0..128).map(|_| AtomicU64::new(0)).collect(),
+            }),
+        }
+    }
+
+    /// Our time, microseconds since this was made
+    pub fn now(&self) -> u64 {
+        self.inner.start.elapsed().as_micros() as u64
+    }
+
+    /// When the message with `stamp`, from the connection `clock` is
+    /// for, arrived
+    pub fn arrived(&self, clock: &mut StampClock, stamp: u64) -> u64 {
+        clock.arrived(stamp, self.now())
+    }
+
+    /// `notes` have been sent, for a pad press that `arrived`.  They
+    /// are the notes as the synthesisers were sent them, after their
+    /// layers (`FanOut::notes`), so whichever comes back is known
+    pub fn sent<I: IntoIterator<Item = u8>>(&self, arrived: u64, notes: I) {
+        self.inner
+            .adapter
+            .record(self.now().saturating_sub(arrived));
+        for note in notes {
+            self.inner.on_the_way[note as usize & 0x7F].store(arrived + 1, Ordering::Relaxed);
+        }
+    }
+
+    /// `note` has come back, on the loopback port, at `arrived`.
+    /// Only the first copy of a note counts
+    pub fn returned(&self, arrived: u64, note: u8) {
+        let sent = self.inner.on_the_way[note as usize & 0x7F].swap(0, Ordering::Relaxed);
+        if sent > 0 {
+            self.inner
+                .round_trip
+                .record(arrived.saturating_sub(sent - 1));
+        }
+    }
+
+    /// Pad to note sent
+    pub fn adapter(&self) -> &Histogram {
+        &self.inner.adapter
+    }
+
+    /// Pad to note back through the loopback port
+    pub fn round_trip(&self) -> &Histogram {
+        &self.inner.round_trip
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "From a pad's message arriving to the note being sent:")?;
+        write!(f, "{}", self.adapter())?;
+        if self.round_trip().count() > 0 {
+            writeln!(f, "From a pad's message arriving to the note coming back:")?;
+            write!(f, "{}", self.round_trip())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        for micros in [0, 1, 15, 16, 17, 31, 32, 100, 1_000, 123_456, LONGEST] {
+            let index = bucket(micros);
+            assert!(lowest(index) <= micros, "{}", micros);
+            assert!(micros < lowest(index + 1), "{}", micros);
+            // Within an eighth
+            assert!(micros - lowest(index) <= micros / 8, "{}", micros);
+        }
+        assert_eq!(bucket(LONGEST), BUCKETS - 1);
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn percentiles() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.percentile(0.5), 0);
+        for micros in 1..=1000 {
+            histogram.record(micros);
+        }
+        assert_eq!(histogram.count(), 1000);
+        assert_eq!(histogram.max(), 1000);
+        let median = histogram.percentile(0.5);
+        assert!((500..=500 + 500 / 8).contains(&median), "{}", median);
+        let p99 = histogram.percentile(0.99);
+        assert!((990..=1000).contains(&p99), "{}", p99);
+        assert_eq!(histogram.percentile(1.0), 1000);
+        assert!(histogram.to_string().contains("99%"));
+    }
+
+    #[test]
+    fn stamp_clock() {
+        let mut clock = StampClock::new();
+        // The first message sets the offset
+        assert_eq!(clock.arrived(100, 1_100), 1_100);
+        // Handled 50µs after it arrived
+        assert_eq!(clock.arrived(200, 1_250), 1_200);
+        // Delivered quicker than the first, so that is the offset
+        assert_eq!(clock.arrived(300, 1_290), 1_290);
+        assert_eq!(clock.arrived(400, 1_400), 1_390);
+        // The connection's clock started again
+        assert_eq!(clock.arrived(10, 5_000_000), 5_000_000);
+    }
+
+    #[test]
+    fn round_trip() {
+        let latency = Latency::new();
+        let arrived = latency.now();
+        latency.sent(arrived, [60]);
+        latency.returned(arrived + 300, 60);
+        // A second copy, from another synthesiser, does not count
+        latency.returned(arrived + 400, 60);
+        // Nor a note that was not sent
+        latency.returned(arrived + 400, 61);
+        assert_eq!(latency.adapter().count(), 1);
+        assert_eq!(latency.round_trip().count(), 1);
+        assert_eq!(latency.round_trip().max(), 300);
+
+        // A bass an octave down comes back transposed
+        latency.sent(arrived, [62, 50]);
+        latency.returned(arrived + 500, 50);
+        assert_eq!(latency.adapter().count(), 2);
+        assert_eq!(latency.round_trip().count(), 2);
+        assert_eq!(latency.round_trip().max(), 500);
+    }
+
+    #[test]
+    fn arguments() {
+        let mut args: Vec<String> = ["lpx_manager", "--latency", "midi.cfg", "60"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(latency_from_args(&mut args).unwrap(), Measure::Adapter);
+        assert_eq!(args, vec!["lpx_manager", "midi.cfg", "60"]);
+        let mut args: Vec<String> = ["lpx_manager", "--latency-loopback", "Through", "midi.cfg"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            latency_from_args(&mut args).unwrap(),
+            Measure::RoundTrip("Through".to_string())
+        );
+        assert_eq!(args, vec!["lpx_manager", "midi.cfg"]);
+        let mut args = vec!["lpx_manager".to_string(), "--latency-loopback".to_string()];
+        assert!(latency_from_args(&mut args).is_err());
+        let mut args = vec!["lpx_manager".to_string()];
+        assert_eq!(latency_from_args(&mut args).unwrap(), Measure::Off);
+    }
+}
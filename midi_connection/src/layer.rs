@@ -0,0 +1,286 @@
+//! Playing the same notes on several synthesisers at once, e.g. a pad
+//! sound on one and a bass an octave down on another.  Each
+//! synthesiser has a `Layer`, that changes the channel, transposes
+//! and scales the velocity of what it is sent.  A `FanOut` sends
+//! each message to every synthesiser through its layer.
+//!
+//! Layer settings are written after the port name, separated by `;`:
+//!
+//! `yoshimi-Yoshimi02:input;channel=2;transpose=-12;velocity=0.8`
+//!
+//! * `channel=N` Send on channel `N` (1 to 16) instead of the
+//!   channel played
+//! * `transpose=N` Add `N` semitones to each note.  Notes moved off
+//!   the end of the MIDI range are not sent
+//! * `velocity=X` Multiply note on velocities by `X`, more than 0.
+//!   A note on is never turned into a note off, the least velocity
+//!   is 1
+//!
+//! A `FanOut` remembers the notes it has left on, so they can be
+//! turned off when the programme stops (`release_on_drop`).
+use crate::{HeldNotes, MIDICommunicator, MIDIError, MidiMessage};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// How the messages to one synthesiser are changed: its channel,
+/// transpose and velocity.  The default changes nothing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layer {
+    /// Send on this channel (0 to 15) instead of the one played
+    pub channel: Option<u8>,
+    /// Semitones added to each note
+    pub transpose: i8,
+    /// Note on velocities are multiplied by this
+    pub velocity: f32,
+}
+
+impl Default for Layer {
+    /// Send messages unchanged
+    fn default() -> Self {
+        Layer {
+            channel: None,
+            transpose: 0,
+            velocity: 1.0,
+        }
+    }
+}
+
+impl Layer {
+    /// `message` as it is sent through this layer.  `None` if it is
+    /// not sent
+    pub fn apply(&self, message: &MidiMessage) -> Option<MidiMessage> {
+        let mut message = message.transposed(self.transpose as i16)?;
+        if let Some(channel) = self.channel {
+            message.set_channel(channel);
+        }
+        if let MidiMessage::NoteOn { velocity, .. } = &mut message {
+            if *velocity > 0 {
+                *velocity = (*velocity as f32 * self.velocity).round().clamp(1.0, 127.0) as u8;
+            }
+        }
+        Some(message)
+    }
+
+    /// Split a port name with settings after it, as in `midi.cfg`,
+    /// into the name and the layer
+    pub fn parse_sink(spec: &str) -> Result<(String, Layer), MIDIError> {
+        match spec.split_once(';') {
+            Some((name, settings)) => Ok((name.to_string(), settings.parse()?)),
+            None => Ok((spec.to_string(), Layer::default())),
+        }
+    }
+}
+
+impl FromStr for Layer {
+    type Err = MIDIError;
+
+    /// Settings separated by `;`, e.g. `channel=2;transpose=-12`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = |reason: &str| MIDIError::BadLayer {
+            spec: s.to_string(),
+            reason: reason.to_string(),
+        };
+        let mut layer = Layer::default();
+        for setting in s.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| bad("expected KEY=VALUE"))?;
+            match key.trim() {
+                "channel" => match value.trim().parse::<u8>() {
+                    Ok(channel @ 1..=16) => layer.channel = Some(channel - 1),
+                    _ => return Err(bad("channel must be 1 to 16")),
+                },
+                "transpose" => {
+                    layer.transpose = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| bad("transpose must be a number of semitones"))?
+                }
+                "velocity" => match value.trim().parse::<f32>() {
+                    Ok(velocity) if velocity.is_finite() && velocity > 0.0 => {
+                        layer.velocity = velocity
+                    }
+                    _ => return Err(bad("velocity must be a number more than 0")),
+                },
+                _ => return Err(bad("expected channel, transpose or velocity")),
+            }
+        }
+        Ok(layer)
+    }
+}
+
+/// Sends to several synthesisers.  Clones send to the same ones, and
+/// share the notes held
+#[derive(Debug, Clone, Default)]
+pub struct FanOut {
+    sinks: Vec<(MIDICommunicator, Layer)>,
+    held: Arc<HeldNotes>,
+}
+
+impl FanOut {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send to `midi_out` through `layer`
+    pub fn add(&mut self, midi_out: MIDICommunicator, layer: Layer) {
+        self.sinks.push((midi_out, layer));
+    }
+
+    /// The number of synthesisers
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    /// The note each synthesiser is sent for `note`, after its
+    /// layer.  Notes its layer moves off the MIDI range are left out
+    pub fn notes(&self, note: u8) -> impl Iterator<Item = u8> + '_ {
+        let note_on = MidiMessage::NoteOn {
+            channel: 0,
+            note,
+            velocity: 1,
+        };
+        self.sinks
+            .iter()
+            .filter_map(move |(_, layer)| layer.apply(&note_on)?.note())
+    }
+
+    /// Send `message` to every synthesiser.  One failing does not
+    /// stop the others being sent to.  The first error is returned.
+    /// Notes are sent without allocating
+    pub fn send_message(&mut self, message: &MidiMessage) -> Result<(), MIDIError> {
+        self.held.track(message);
+        let mut result = Ok(());
+        for (midi_out, layer) in self.sinks.iter_mut() {
+            if let Some(message) = layer.apply(message) {
+                let sent = midi_out.send_message(&message);
+                if result.is_ok() {
+                    result = sent;
+                }
+            }
+        }
+        result
+    }
+
+    /// Turn off every note that is held, then send All Notes Off on
+    /// the channels that were played on
+    pub fn release_all(&mut self) -> Result<(), MIDIError> {
+        let messages = self.held.release();
+        let mut result = Ok(());
+        for message in messages {
+            let sent = self.send_message(&message);
+            if result.is_ok() {
+                result = sent;
+            }
+        }
+        result
+    }
+
+    /// Keep this until the programme stops.  When it is dropped the
+    /// notes still held are turned off
+    pub fn release_on_drop(&self) -> ReleaseOnDrop {
+        ReleaseOnDrop(self.clone())
+    }
+}
+
+/// Turns off the notes a `FanOut` has left on when dropped
+#[derive(Debug)]
+pub struct ReleaseOnDrop(FanOut);
+
+impl Drop for ReleaseOnDrop {
+    fn drop(&mut self) {
+        // Nothing to be done about it now
+        let _ = self.0.release_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::loopback_output;
+    use crate::Loopback;
+
+    #[test]
+    fn settings() {
+        let (name, layer) =
+            Layer::parse_sink("yoshimi:input;channel=2; transpose=-12;velocity=0.5").unwrap();
+        assert_eq!(name, "yoshimi:input");
+        assert_eq!(
+            layer,
+            Layer {
+                channel: Some(1),
+                transpose: -12,
+                velocity: 0.5
+            }
+        );
+        assert_eq!(Layer::parse_sink("Synth").unwrap().1, Layer::default());
+        assert!(matches!(
+            Layer::parse_sink("Synth;channel=17"),
+            Err(MIDIError::BadLayer { .. })
+        ));
+        assert!(matches!(
+            Layer::parse_sink("Synth;octave=1"),
+            Err(MIDIError::BadLayer { .. })
+        ));
+        // Every note would be played at velocity 1
+        assert!(matches!(
+            Layer::parse_sink("Synth;velocity=0"),
+            Err(MIDIError::BadLayer { .. })
+        ));
+    }
+
+    #[test]
+    fn fans_out_through_layers() {
+        let loopback = Loopback::new(&["Pad", "Bass"]);
+        let mut fan_out = FanOut::new();
+        for (port, layer) in [
+            ("Pad", Layer::default()),
+            (
+                "Bass",
+                "channel=2;transpose=-24;velocity=0.5".parse().unwrap(),
+            ),
+        ] {
+            let midi_out = loopback_output(&loopback, port);
+            fan_out.add(midi_out, layer);
+        }
+        let note = |note, velocity| MidiMessage::NoteOn {
+            channel: 0,
+            note,
+            velocity,
+        };
+        fan_out.send_message(&note(60, 101)).unwrap();
+        fan_out.send_message(&note(60, 1)).unwrap();
+        fan_out.send_message(&note(60, 0)).unwrap();
+        // Too low for the bass
+        fan_out.send_message(&note(20, 100)).unwrap();
+        assert_eq!(fan_out.notes(60).collect::<Vec<u8>>(), vec![60, 36]);
+        assert_eq!(fan_out.notes(20).collect::<Vec<u8>>(), vec![20]);
+        assert_eq!(
+            loopback.sent("Pad"),
+            vec![
+                vec![0x90, 60, 101],
+                vec![0x90, 60, 1],
+                vec![0x90, 60, 0],
+                vec![0x90, 20, 100]
+            ]
+        );
+        assert_eq!(
+            loopback.sent("Bass"),
+            vec![vec![0x91, 36, 51], vec![0x91, 36, 1], vec![0x91, 36, 0]]
+        );
+
+        // The note 20 is still held.  The bass was never sent it
+        loopback.take_sent("Pad");
+        loopback.take_sent("Bass");
+        drop(fan_out.release_on_drop());
+        assert_eq!(
+            loopback.take_sent("Pad"),
+            vec![vec![0x80, 20, 0], vec![0xB0, 123, 0]]
+        );
+        assert_eq!(loopback.take_sent("Bass"), vec![vec![0xB1, 123, 0]]);
+    }
+}
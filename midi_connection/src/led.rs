@@ -0,0 +1,367 @@
+//! Lighting the Launchpad X's pads without getting in the way of
+//! notes.  Colour changes go into a `LedPipeline` and are sent by
+//! its own, low priority, thread.  Changes to a pad that has not been
+//! sent yet replace the earlier change, and all the changes waiting
+//! are sent as one SysEx message.  No more than one message is sent
+//! each `min_interval`, so fast playing does not flood the USB link.
+//!
+//! Notes go to the synthesiser on their own connection, and never
+//! wait for the LEDs.  Setting a colour, from the thread handling
+//! notes, stores it in the pad's slot and wakes the LED thread: it
+//! does not lock or allocate.
+use crate::{MIDICommunicator, MIDIError};
+use lpx_protocol::{LedMessage, Lighting, Pad, MAX_SPECS};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The default time between LED messages
+const MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The lighting in a pad's slot: `WAITING`, the type in the two bits
+/// above `TYPE_SHIFT`, and up to three colour bytes.  A slot of 0 has
+/// no change waiting
+const WAITING: u32 = 1 << 31;
+const TYPE_SHIFT: u32 = 24;
+
+/// `lighting` packed into a slot
+fn pack(lighting: Lighting) -> u32 {
+    let (kind, bytes) = match lighting {
+        Lighting::Static(colour) => (0, [0, 0, colour]),
+        Lighting::Flash(a, b) => (1, [0, a, b]),
+        Lighting::Pulse(colour) => (2, [0, 0, colour]),
+        Lighting::Rgb(r, g, b) => (3, [r, g, b]),
+    };
+    WAITING | kind << TYPE_SHIFT | u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]])
+}
+
+/// The lighting packed in a slot, if there is one
+fn unpack(slot: u32) -> Option<Lighting> {
+    if slot & WAITING == 0 {
+        return None;
+    }
+    let [_, x, y, z] = slot.to_be_bytes();
+    Some(match (slot >> TYPE_SHIFT) & 3 {
+        0 => Lighting::Static(z),
+        1 => Lighting::Flash(y, z),
+        2 => Lighting::Pulse(z),
+        _ => Lighting::Rgb(x, y, z),
+    })
+}
+
+struct Shared {
+    // The colour each pad is to become, by pad number
+    slots: [AtomicU32; 128],
+    // True from when changes are taken from `slots` until they are
+    // sent
+    sending: AtomicBool,
+    // True while the thread waits for a change.  Only then does it
+    // need waking, which costs a system call
+    idle: AtomicBool,
+    stop: AtomicBool,
+    // Signalled, with `sent` locked, after each message is sent.
+    // Only `flush` waits for it
+    sent: Mutex<()>,
+    sent_changed: Condvar,
+}
+
+impl Shared {
+    /// True if any pad has a change waiting
+    fn waiting(&self) -> bool {
+        self.slots
+            .iter()
+            .any(|slot| slot.load(Ordering::Acquire) != 0)
+    }
+}
+
+struct Inner {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Inner {
+    /// Send what is waiting, then stop
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Clones share the same queue and thread.  The thread stops when
+/// the last clone is dropped
+#[derive(Clone)]
+pub struct LedPipeline {
+    inner: Arc<Inner>,
+}
+
+impl std::fmt::Debug for LedPipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LedPipeline")
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+/// Lower the priority of this thread, so it gives way to MIDI
+#[cfg(target_os = "linux")]
+fn lower_priority() {
+    // SAFETY: On Linux `who` 0 is the calling thread, not the
+    // process.  If it fails the thread keeps normal priority
+    unsafe {
+        libc::setpriority(libc::PRIO_PROCESS as _, 0, 10);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lower_priority() {}
+
+/// One LED message for `lights`
+fn led_message(lights: &[(u8, Lighting)]) -> LedMessage {
+    let mut message = LedMessage::new();
+    message
+        .lights(lights.iter().copied())
+        .expect("Pads and colours are checked when they are stored");
+    message
+}
+
+impl LedPipeline {
+    /// Send colour changes to `lpx_out`, the Launchpad's DAW port
+    pub fn new(lpx_out: MIDICommunicator) -> LedPipeline {
+        Self::with_interval(lpx_out, MIN_INTERVAL)
+    }
+
+    /// Send no more than one message to `lpx_out` each
+    /// `min_interval`
+    pub fn with_interval(mut lpx_out: MIDICommunicator, min_interval: Duration) -> LedPipeline {
+        let shared = Arc::new(Shared {
+            slots: std::array::from_fn(|_| AtomicU32::new(0)),
+            sending: AtomicBool::new(false),
+            idle: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+            sent: Mutex::new(()),
+            sent_changed: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        let thread = thread::spawn(move || {
+            let shared = thread_shared;
+            lower_priority();
+            let mut last_send: Option<Instant> = None;
+            let mut lights: Vec<(u8, Lighting)> = Vec::with_capacity(MAX_SPECS);
+            loop {
+                if !shared.waiting() {
+                    if shared.stop.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    // Look again once `idle` is set, in case a change
+                    // came in before
+                    shared.idle.store(true, Ordering::SeqCst);
+                    if !shared.waiting() {
+                        thread::park();
+                    }
+                    shared.idle.store(false, Ordering::SeqCst);
+                    continue;
+                }
+                if let Some(wait) = last_send
+                    .map(|last| (last + min_interval).saturating_duration_since(Instant::now()))
+                    .filter(|wait| !wait.is_zero())
+                {
+                    // Too soon.  More changes may come in meanwhile
+                    thread::park_timeout(wait);
+                    continue;
+                }
+                // Set before the slots are emptied, so `flush` never
+                // sees nothing waiting and nothing being sent while
+                // there is
+                shared.sending.store(true, Ordering::SeqCst);
+                lights.clear();
+                for (pad, slot) in shared.slots.iter().enumerate() {
+                    if lights.len() == MAX_SPECS {
+                        break;
+                    }
+                    if let Some(lighting) = unpack(slot.swap(0, Ordering::AcqRel)) {
+                        lights.push((pad as u8, lighting));
+                    }
+                }
+
+                // The lights are not worth stopping for.  If the LPX
+                // has gone its communicator reports that
+                let message = led_message(&lights);
+                if !message.is_empty() {
+                    let _ = lpx_out.send(&message.build());
+                }
+                last_send = Some(Instant::now());
+
+                shared.sending.store(false, Ordering::SeqCst);
+                let _sent = shared.sent.lock().unwrap();
+                shared.sent_changed.notify_all();
+            }
+        });
+        LedPipeline {
+            inner: Arc::new(Inner {
+                shared,
+                thread: Some(thread),
+            }),
+        }
+    }
+
+    /// Store `lighting` in `pad`'s slot, if `pad` is on the LPX and
+    /// `lighting`'s palette colours are in the palette
+    fn store(&self, pad: u8, lighting: Lighting) -> Result<(), MIDIError> {
+        let pad = Pad::try_from(pad)?;
+        let lighting = lighting.checked()?;
+        self.inner.shared.slots[pad.number() as usize].store(pack(lighting), Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Wake the thread, if it is waiting for a change
+    fn wake(&self) {
+        if self.inner.shared.idle.swap(false, Ordering::SeqCst) {
+            if let Some(thread) = self.inner.thread.as_ref() {
+                thread.thread().unpark();
+            }
+        }
+    }
+
+    /// Light `pad` with `lighting`: static, flashing, pulsing or RGB.
+    /// An error if `pad` is not on the LPX (see `Pad`) or a palette
+    /// colour is over 127, and then nothing is sent
+    pub fn light(&self, pad: u8, lighting: Lighting) -> Result<(), MIDIError> {
+        self.store(pad, lighting)?;
+        self.wake();
+        Ok(())
+    }
+
+    /// Colour `pad` from the palette
+    pub fn set(&self, pad: u8, colour: u8) -> Result<(), MIDIError> {
+        self.light(pad, Lighting::Static(colour))
+    }
+
+    /// Colour `pad` by red, green and blue, each clamped to 0 to 127
+    pub fn set_rgb(&self, pad: u8, red: u8, green: u8, blue: u8) -> Result<(), MIDIError> {
+        self.light(pad, Lighting::rgb(red, green, blue))
+    }
+
+    /// Light many pads, from pairs of pad and lighting (a
+    /// `Framebuffer::flush`, say).  They are all stored before the
+    /// thread is woken, so they go out together in one message.  Pads
+    /// that `light` would refuse are left out, and the first is
+    /// returned as an error after the rest are sent
+    pub fn paint<I>(&self, lights: I) -> Result<(), MIDIError>
+    where
+        I: IntoIterator<Item = (u8, Lighting)>,
+    {
+        let mut refused = Ok(());
+        for (pad, lighting) in lights {
+            if let Err(err) = self.store(pad, lighting) {
+                refused = refused.and(Err(err));
+            }
+        }
+        self.wake();
+        refused
+    }
+
+    /// The number of pads waiting to be sent
+    pub fn pending(&self) -> usize {
+        self.inner
+            .shared
+            .slots
+            .iter()
+            .filter(|slot| slot.load(Ordering::Acquire) != 0)
+            .count()
+    }
+
+    /// Wait until every change so far has been sent
+    pub fn flush(&self) {
+        let shared = &self.inner.shared;
+        let mut sent = shared.sent.lock().unwrap();
+        while shared.waiting() || shared.sending.load(Ordering::SeqCst) {
+            sent = shared.sent_changed.wait(sent).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::loopback_output;
+    use crate::Loopback;
+    use lpx_protocol::ProtocolError;
+
+    #[test]
+    fn coalesces_and_batches() {
+        let loopback = Loopback::new(&["LPX"]);
+        let lpx_out = loopback_output(&loopback, "LPX");
+        let leds = LedPipeline::with_interval(lpx_out, Duration::from_millis(50));
+
+        // The first change goes at once, the rest wait for the
+        // interval and go together
+        leds.set(11, 5).unwrap();
+        leds.flush();
+        leds.set(12, 17).unwrap();
+        leds.set(11, 50).unwrap();
+        leds.set(12, 113).unwrap();
+        leds.set_rgb(13, 127, 0, 200).unwrap();
+        leds.light(14, Lighting::Flash(5, 17)).unwrap();
+        leds.light(15, Lighting::Pulse(87)).unwrap();
+        leds.flush();
+        assert_eq!(
+            loopback.take_sent("LPX"),
+            vec![
+                vec![240, 0, 32, 41, 2, 12, 3, 0, 11, 5, 247],
+                vec![
+                    240, 0, 32, 41, 2, 12, 3, 0, 11, 50, 0, 12, 113, 3, 13, 127, 0, 127, 1, 14, 17,
+                    5, 2, 15, 87, 247
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn paints_in_one_message() {
+        let loopback = Loopback::new(&["LPX"]);
+        let lpx_out = loopback_output(&loopback, "LPX");
+        let leds = LedPipeline::with_interval(lpx_out, Duration::from_millis(50));
+        leds.set(11, 5).unwrap();
+        leds.flush();
+        loopback.take_sent("LPX");
+
+        // Every pad, in the message after the first
+        let lights =
+            lpx_protocol::Pad::all().map(|pad| (pad.number(), Lighting::Static(pad.row())));
+        leds.paint(lights).unwrap();
+        leds.flush();
+        let sent = loopback.take_sent("LPX");
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].len(), 8 + 81 * 3);
+        assert_eq!(&sent[0][6..10], &[3, 0, 11, 1]);
+    }
+
+    #[test]
+    fn refuses_pads_not_on_the_lpx() {
+        let loopback = Loopback::new(&["LPX"]);
+        let lpx_out = loopback_output(&loopback, "LPX");
+        let leds = LedPipeline::new(lpx_out);
+        // 139 is not pad 11, 10 is in no column, 200 is not a colour
+        assert!(matches!(
+            leds.set(139, 5),
+            Err(MIDIError::Protocol(ProtocolError::BadPad(139)))
+        ));
+        assert!(leds.set(10, 5).is_err());
+        assert!(leds.light(11, Lighting::Flash(5, 200)).is_err());
+        assert_eq!(leds.pending(), 0);
+
+        // The good pads are painted all the same
+        assert!(leds
+            .paint([(12, Lighting::Static(5)), (100, Lighting::Static(5))])
+            .is_err());
+        leds.flush();
+        assert_eq!(
+            loopback.take_sent("LPX"),
+            vec![vec![240, 0, 32, 41, 2, 12, 3, 0, 12, 5, 247]]
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Reporting errors from the thread that handles notes.  Writing to
+//! stderr can block, and formatting the message allocates, so neither
+//! is done there.  A `Logger` passes the error to its own thread,
+//! that writes it out.
+//!
+//! If errors come faster than they can be written, and the queue is
+//! full, the extra ones are counted and the count reported instead.
+//!
+//! When the last clone is dropped it waits for the errors queued to
+//! be written, so they are not lost if the programme then exits.
+use crate::MIDIError;
+use crossbeam_channel::{Sender, TrySendError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// The most errors waiting to be written
+const QUEUE: usize = 256;
+
+/// Clones share the same thread.  It stops when the last clone is
+/// dropped, after writing what is waiting
+#[derive(Debug, Clone)]
+pub struct Logger {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    sender: Option<Sender<(&'static str, MIDIError)>>,
+    // Errors dropped because the queue was full
+    dropped: Arc<AtomicUsize>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Inner {
+    /// Write out what is waiting
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Logger {
+    pub fn new() -> Logger {
+        // Bounded, so sending never allocates
+        let (sender, receiver) = crossbeam_channel::bounded::<(&'static str, MIDIError)>(QUEUE);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let thread_dropped = dropped.clone();
+        let thread = thread::spawn(move || {
+            for (context, err) in receiver.iter() {
+                eprintln!("{}: {}", context, err);
+                let dropped = thread_dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    eprintln!("{} more errors not shown", dropped);
+                }
+            }
+        });
+        Logger {
+            inner: Arc::new(Inner {
+                sender: Some(sender),
+                dropped,
+                thread: Some(thread),
+            }),
+        }
+    }
+
+    /// Report `err`, that happened while doing `context`.  Never waits
+    pub fn error(&self, context: &'static str, err: MIDIError) {
+        if let Some(sender) = self.inner.sender.as_ref() {
+            if let Err(TrySendError::Full(_)) = sender.try_send((context, err)) {
+                self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
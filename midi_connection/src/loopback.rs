@@ -0,0 +1,340 @@
+//! An in-memory `Transport`.  It has a set of named ports that act as
+//! both inputs and outputs.  A test injects messages into a port,
+//! as if a Launchpad pad was pressed, and reads back what was sent
+//! to each port.  Anything sent to a port is also delivered to the
+//! inputs connected to that port, so output can be looped back.
+//!
+//! A callback can send to the port it is listening to, as a device
+//! answering a message would.  What it sends is delivered to it after
+//! it returns, on the same thread, instead of waiting for itself.
+use crate::transport::{InputCallback, InputConnection, OutputConnection, PortLister, Transport};
+use crate::{Direction, MIDIError};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// An input's callback, and what was sent to it while it was running
+/// on the thread that is running it
+struct Delivery {
+    callback: Mutex<InputCallback>,
+    pending: Mutex<VecDeque<(u64, Vec<u8>)>>,
+}
+
+struct InputEntry {
+    id: usize,
+    port: String,
+    delivery: Arc<Delivery>,
+}
+
+thread_local! {
+    // The inputs whose callbacks are running on this thread
+    static RUNNING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+struct LoopbackState {
+    ports: Vec<String>,
+    inputs: Vec<InputEntry>,
+    // Messages sent to each port, in order
+    sent: HashMap<String, Vec<Vec<u8>>>,
+    next_id: usize,
+}
+
+/// The in-memory transport.  Clones share the same ports
+#[derive(Clone)]
+pub struct Loopback {
+    state: Arc<Mutex<LoopbackState>>,
+    // Timestamps are microseconds since this
+    start: Instant,
+}
+
+impl std::fmt::Debug for Loopback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Loopback")
+            .field("ports", &self.state.lock().unwrap().ports)
+            .finish()
+    }
+}
+
+impl Loopback {
+    /// A loopback with ports called `ports`
+    pub fn new(ports: &[&str]) -> Self {
+        Loopback {
+            state: Arc::new(Mutex::new(LoopbackState {
+                ports: ports.iter().map(|p| p.to_string()).collect(),
+                inputs: Vec::new(),
+                sent: HashMap::new(),
+                next_id: 0,
+            })),
+            start: Instant::now(),
+        }
+    }
+
+    /// Add a port called `name`
+    pub fn add_port(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        if !state.ports.iter().any(|p| p == name) {
+            state.ports.push(name.to_string());
+        }
+    }
+
+    /// Remove the port called `name`.  Connections to it stay open
+    /// but nothing more arrives on them
+    pub fn remove_port(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.ports.retain(|p| p != name);
+        state.inputs.retain(|i| i.port != name);
+    }
+
+    /// Deliver `msg` to every input connected to `port`, as if the
+    /// device on `port` had sent it.  The callbacks run on this
+    /// thread before `inject` returns.  Except that a callback that
+    /// causes this, by sending to its own port, is sent `msg` after
+    /// it returns
+    pub fn inject(&self, port: &str, msg: &[u8]) {
+        // Do not hold the lock while calling back, the callback may
+        // well send on this loopback
+        let inputs: Vec<(usize, Arc<Delivery>)> = self
+            .state
+            .lock()
+            .unwrap()
+            .inputs
+            .iter()
+            .filter(|i| i.port == port)
+            .map(|i| (i.id, i.delivery.clone()))
+            .collect();
+        let stamp = self.start.elapsed().as_micros() as u64;
+        for (id, delivery) in inputs {
+            if RUNNING.with(|running| running.borrow().contains(&id)) {
+                // Its callback is further up this thread's stack
+                delivery
+                    .pending
+                    .lock()
+                    .unwrap()
+                    .push_back((stamp, msg.to_vec()));
+                continue;
+            }
+            let mut callback = delivery.callback.lock().unwrap();
+            RUNNING.with(|running| running.borrow_mut().push(id));
+            callback(stamp, msg);
+            loop {
+                let next = delivery.pending.lock().unwrap().pop_front();
+                match next {
+                    Some((stamp, msg)) => callback(stamp, &msg),
+                    None => break,
+                }
+            }
+            RUNNING.with(|running| running.borrow_mut().retain(|&r| r != id));
+        }
+    }
+
+    /// All the messages sent to `port` so far
+    pub fn sent(&self, port: &str) -> Vec<Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .sent
+            .get(port)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The messages sent to `port` since the last call to `take_sent`
+    pub fn take_sent(&self, port: &str) -> Vec<Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .sent
+            .remove(port)
+            .unwrap_or_default()
+    }
+
+    fn check_port(&self, port_name: &str, direction: Direction) -> Result<(), MIDIError> {
+        let state = self.state.lock().unwrap();
+        if state.ports.iter().any(|p| p == port_name) {
+            Ok(())
+        } else {
+            Err(MIDIError::NoMatchingPort {
+                direction,
+                requested: port_name.to_string(),
+                available: state.ports.clone(),
+            })
+        }
+    }
+}
+
+struct LoopbackInput {
+    id: usize,
+    loopback: Loopback,
+}
+impl InputConnection for LoopbackInput {}
+impl Drop for LoopbackInput {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.loopback.state.lock() {
+            state.inputs.retain(|i| i.id != self.id);
+        }
+    }
+}
+
+struct LoopbackOutput {
+    port: String,
+    loopback: Loopback,
+}
+impl OutputConnection for LoopbackOutput {
+    fn send(&mut self, msg: &[u8]) -> Result<(), MIDIError> {
+        self.loopback
+            .state
+            .lock()
+            .unwrap()
+            .sent
+            .entry(self.port.clone())
+            .or_default()
+            .push(msg.to_vec());
+        self.loopback.inject(self.port.as_str(), msg);
+        Ok(())
+    }
+}
+
+struct LoopbackLister(Loopback);
+impl PortLister for LoopbackLister {
+    fn input_ports(&mut self) -> Result<Vec<String>, MIDIError> {
+        Ok(self.0.state.lock().unwrap().ports.clone())
+    }
+
+    fn output_ports(&mut self) -> Result<Vec<String>, MIDIError> {
+        Ok(self.0.state.lock().unwrap().ports.clone())
+    }
+}
+
+impl Transport for Loopback {
+    fn input_ports(&self, _client_name: &str) -> Result<Vec<String>, MIDIError> {
+        Ok(self.state.lock().unwrap().ports.clone())
+    }
+
+    fn output_ports(&self, _client_name: &str) -> Result<Vec<String>, MIDIError> {
+        Ok(self.state.lock().unwrap().ports.clone())
+    }
+
+    fn port_lister(&self, _client_name: &str) -> Result<Box<dyn PortLister>, MIDIError> {
+        Ok(Box::new(LoopbackLister(self.clone())))
+    }
+
+    fn connect_input(
+        &self,
+        _client_name: &str,
+        port_name: &str,
+        callback: InputCallback,
+    ) -> Result<Box<dyn InputConnection>, MIDIError> {
+        self.check_port(port_name, Direction::Input)?;
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.inputs.push(InputEntry {
+            id,
+            port: port_name.to_string(),
+            delivery: Arc::new(Delivery {
+                callback: Mutex::new(callback),
+                pending: Mutex::new(VecDeque::new()),
+            }),
+        });
+        Ok(Box::new(LoopbackInput {
+            id,
+            loopback: self.clone(),
+        }))
+    }
+
+    fn connect_output(
+        &self,
+        _client_name: &str,
+        port_name: &str,
+    ) -> Result<Box<dyn OutputConnection>, MIDIError> {
+        self.check_port(port_name, Direction::Output)?;
+        Ok(Box::new(LoopbackOutput {
+            port: port_name.to_string(),
+            loopback: self.clone(),
+        }))
+    }
+
+    fn create_virtual_input(
+        &self,
+        client_name: &str,
+        port_name: &str,
+        callback: InputCallback,
+    ) -> Result<Box<dyn InputConnection>, MIDIError> {
+        self.add_port(port_name);
+        self.connect_input(client_name, port_name, callback)
+    }
+
+    fn create_virtual_output(
+        &self,
+        client_name: &str,
+        port_name: &str,
+    ) -> Result<Box<dyn OutputConnection>, MIDIError> {
+        self.add_port(port_name);
+        self.connect_output(client_name, port_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn sent_messages_loop_back() {
+        let loopback = Loopback::new(&["A"]);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let r = received.clone();
+        let _input = loopback
+            .connect_input(
+                "test",
+                "A",
+                Box::new(move |_, msg| r.lock().unwrap().push(msg.to_vec())),
+            )
+            .unwrap();
+        let mut output = loopback.connect_output("test", "A").unwrap();
+        output.send(&[144, 60, 100]).unwrap();
+        assert_eq!(loopback.sent("A"), vec![vec![144, 60, 100]]);
+        assert_eq!(*received.lock().unwrap(), vec![vec![144, 60, 100]]);
+    }
+
+    #[test]
+    fn dropped_input_stops_receiving() {
+        let loopback = Loopback::new(&["A"]);
+        let count = Arc::new(Mutex::new(0));
+        let c = count.clone();
+        let input = loopback
+            .connect_input("test", "A", Box::new(move |_, _| *c.lock().unwrap() += 1))
+            .unwrap();
+        loopback.inject("A", &[144, 60, 100]);
+        drop(input);
+        loopback.inject("A", &[144, 60, 0]);
+        assert_eq!(*count.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn callback_sends_to_its_own_port() {
+        // Echoes a note on as a note off, to the port it came from
+        let loopback = Loopback::new(&["A"]);
+        let mut output = loopback.connect_output("test", "A").unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let r = received.clone();
+        let device = loopback.clone();
+        let _input = loopback
+            .connect_input(
+                "test",
+                "A",
+                Box::new(move |_, msg| {
+                    r.lock().unwrap().push(msg.to_vec());
+                    if msg[0] == 0x90 {
+                        device.inject("A", &[0x80, msg[1], 0]);
+                    }
+                }),
+            )
+            .unwrap();
+        output.send(&[0x90, 60, 100]).unwrap();
+        // Delivered after the callback returned, before `send` did
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![vec![0x90, 60, 100], vec![0x80, 60, 0]]
+        );
+    }
+}
@@ -0,0 +1,481 @@
+//! MIDI messages by meaning rather than by byte offset.  Channels are
+//! counted from 0, so the channel that is called "1" on most devices
+//! is 0 here.
+//!
+//! `MidiMessage::decode` reads one complete message, which is what a
+//! `MIDICommunicator` callback is given.  `Parser` reads a raw byte
+//! stream, one byte at a time, and understands running status and
+//! real time bytes interleaved with other messages.
+use crate::MIDIError;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    /// A `NoteOn` with velocity 0 means the same as a `NoteOff`, and
+    /// that is what the Launchpad sends when a pad is released
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    /// `value` is 14 bits, 0x2000 is the centre
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+    /// All of the message, including the 0xF0 at the start and the
+    /// 0xF7 at the end
+    SysEx(Vec<u8>),
+    /// MIDI time code, song position, song select and tune request.
+    /// The status byte and its data
+    SystemCommon(Vec<u8>),
+    /// A single byte message, 0xF8 to 0xFF.  Clock, start, stop...
+    Realtime(u8),
+}
+
+/// The number of data bytes that follow `status`, or `None` if it is
+/// not a status byte with a fixed length
+fn data_length(status: u8) -> Option<usize> {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF => Some(2),
+        0xC0..=0xDF => Some(1),
+        0xF1 | 0xF3 => Some(1),
+        0xF2 => Some(2),
+        0xF6 | 0xF8..=0xFF => Some(0),
+        _ => None,
+    }
+}
+
+impl MidiMessage {
+    /// Decode one complete message
+    pub fn decode(bytes: &[u8]) -> Result<MidiMessage, MIDIError> {
+        let bad = |reason: &'static str| MIDIError::BadMessage {
+            bytes: bytes.to_vec(),
+            reason,
+        };
+        let (&status, data) = bytes.split_first().ok_or_else(|| bad("empty message"))?;
+        if status < 0x80 {
+            return Err(bad("no status byte"));
+        }
+        if status == SYSEX_START {
+            return match data.split_last() {
+                Some((&SYSEX_END, body)) if body.iter().all(|b| *b < 0x80) => {
+                    Ok(MidiMessage::SysEx(bytes.to_vec()))
+                }
+                Some((&SYSEX_END, _)) => Err(bad("status byte inside SysEx")),
+                _ => Err(bad("SysEx not terminated")),
+            };
+        }
+        match data_length(status) {
+            Some(length) if length == data.len() => (),
+            Some(_) => return Err(bad("wrong length for status")),
+            None => return Err(bad("not a message status")),
+        }
+        if data.iter().any(|b| *b >= 0x80) {
+            return Err(bad("data byte out of range"));
+        }
+        Ok(MidiMessage::from_parts(status, data))
+    }
+
+    /// Build a message from a status byte, that is not SysEx, and
+    /// the right number of data bytes
+    fn from_parts(status: u8, data: &[u8]) -> MidiMessage {
+        let channel = status & 0x0F;
+        match status & 0xF0 {
+            0x80 => MidiMessage::NoteOff {
+                channel,
+                note: data[0],
+                velocity: data[1],
+            },
+            0x90 => MidiMessage::NoteOn {
+                channel,
+                note: data[0],
+                velocity: data[1],
+            },
+            0xA0 => MidiMessage::PolyPressure {
+                channel,
+                note: data[0],
+                pressure: data[1],
+            },
+            0xB0 => MidiMessage::ControlChange {
+                channel,
+                controller: data[0],
+                value: data[1],
+            },
+            0xC0 => MidiMessage::ProgramChange {
+                channel,
+                program: data[0],
+            },
+            0xD0 => MidiMessage::ChannelPressure {
+                channel,
+                pressure: data[0],
+            },
+            0xE0 => MidiMessage::PitchBend {
+                channel,
+                value: data[0] as u16 | (data[1] as u16) << 7,
+            },
+            _ if status >= 0xF8 => MidiMessage::Realtime(status),
+            _ => {
+                let mut bytes = vec![status];
+                bytes.extend_from_slice(data);
+                MidiMessage::SystemCommon(bytes)
+            }
+        }
+    }
+
+    /// The bytes to send.  Channels and data are masked into range
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(3);
+        self.encode_into(&mut bytes);
+        bytes
+    }
+
+    /// Append the bytes to send to `bytes`
+    pub fn encode_into(&self, bytes: &mut Vec<u8>) {
+        match (self, self.short_bytes()) {
+            (MidiMessage::SysEx(sysex) | MidiMessage::SystemCommon(sysex), _) => {
+                bytes.extend_from_slice(sysex)
+            }
+            (_, Some((short, length))) => bytes.extend_from_slice(&short[..length]),
+            (_, None) => (),
+        }
+    }
+
+    /// The bytes to send, and how many there are, for a message that
+    /// is never more than three bytes.  Made without allocating, for
+    /// sending notes.  `None` for SysEx and system common messages
+    pub fn short_bytes(&self) -> Option<([u8; 3], usize)> {
+        let voice = |status: u8, channel: u8, data: &[u8]| {
+            let mut bytes = [status | (channel & 0x0F), 0, 0];
+            for (byte, data) in bytes[1..].iter_mut().zip(data) {
+                *byte = data & 0x7F;
+            }
+            Some((bytes, 1 + data.len()))
+        };
+        match self {
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => voice(0x80, *channel, &[*note, *velocity]),
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => voice(0x90, *channel, &[*note, *velocity]),
+            MidiMessage::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => voice(0xA0, *channel, &[*note, *pressure]),
+            MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            } => voice(0xB0, *channel, &[*controller, *value]),
+            MidiMessage::ProgramChange { channel, program } => voice(0xC0, *channel, &[*program]),
+            MidiMessage::ChannelPressure { channel, pressure } => {
+                voice(0xD0, *channel, &[*pressure])
+            }
+            MidiMessage::PitchBend { channel, value } => {
+                voice(0xE0, *channel, &[*value as u8, (*value >> 7) as u8])
+            }
+            MidiMessage::Realtime(status) => Some(([*status, 0, 0], 1)),
+            MidiMessage::SysEx(_) | MidiMessage::SystemCommon(_) => None,
+        }
+    }
+
+    /// The channel of a channel voice message
+    pub fn channel(&self) -> Option<u8> {
+        match self {
+            MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::PolyPressure { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. }
+            | MidiMessage::ChannelPressure { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => Some(*channel),
+            _ => None,
+        }
+    }
+
+    /// Move a channel voice message to `new_channel`.  Other
+    /// messages are not changed
+    pub fn set_channel(&mut self, new_channel: u8) {
+        match self {
+            MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::PolyPressure { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. }
+            | MidiMessage::ChannelPressure { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => *channel = new_channel & 0x0F,
+            _ => (),
+        }
+    }
+
+    /// The note of a note on, note off or polyphonic pressure message
+    pub fn note(&self) -> Option<u8> {
+        match self {
+            MidiMessage::NoteOff { note, .. }
+            | MidiMessage::NoteOn { note, .. }
+            | MidiMessage::PolyPressure { note, .. } => Some(*note),
+            _ => None,
+        }
+    }
+
+    /// The message with its note moved by `semitones`.  `None` if
+    /// that is off the end of the MIDI range.  Messages without a
+    /// note are not changed
+    pub fn transposed(&self, semitones: i16) -> Option<MidiMessage> {
+        let mut message = self.clone();
+        match &mut message {
+            MidiMessage::NoteOff { note, .. }
+            | MidiMessage::NoteOn { note, .. }
+            | MidiMessage::PolyPressure { note, .. } => {
+                *note = u8::try_from(*note as i16 + semitones)
+                    .ok()
+                    .filter(|n| *n < 0x80)?;
+            }
+            _ => (),
+        }
+        Some(message)
+    }
+
+    /// True for a `NoteOff`, or a `NoteOn` with velocity 0
+    pub fn is_note_off(&self) -> bool {
+        matches!(
+            self,
+            MidiMessage::NoteOff { .. } | MidiMessage::NoteOn { velocity: 0, .. }
+        )
+    }
+}
+
+/// Turns a stream of bytes into messages.  Data bytes with no status
+/// before them, or after an undefined status (F4h, F5h), and SysEx
+/// that is interrupted, are dropped
+#[derive(Debug, Default)]
+pub struct Parser {
+    // The status of the message being read, kept after the message is
+    // finished for running status
+    running_status: Option<u8>,
+    data: Vec<u8>,
+    sysex: Option<Vec<u8>>,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read one byte.  Returns the message it completes, if any
+    pub fn push(&mut self, byte: u8) -> Option<MidiMessage> {
+        if byte >= 0xF8 {
+            // Real time bytes can come anywhere, even inside SysEx,
+            // and do not change anything
+            return Some(MidiMessage::Realtime(byte));
+        }
+        if byte == SYSEX_END {
+            self.running_status = None;
+            let mut sysex = self.sysex.take()?;
+            sysex.push(byte);
+            return Some(MidiMessage::SysEx(sysex));
+        }
+        if byte >= 0x80 {
+            self.sysex = None;
+            self.data.clear();
+            if byte == SYSEX_START {
+                self.running_status = None;
+                self.sysex = Some(vec![byte]);
+                return None;
+            }
+            self.running_status = Some(byte);
+        } else if let Some(sysex) = self.sysex.as_mut() {
+            sysex.push(byte);
+            return None;
+        } else if self.running_status.and_then(data_length).is_some() {
+            self.data.push(byte);
+        } else {
+            // No message to put it in (no running status, or after an
+            // undefined status), so it is dropped
+            return None;
+        }
+
+        let status = self.running_status?;
+        if self.data.len() < data_length(status)? {
+            return None;
+        }
+        let message = MidiMessage::from_parts(status, &self.data);
+        self.data.clear();
+        if status >= 0xF0 {
+            // System common messages cancel running status
+            self.running_status = None;
+        }
+        Some(message)
+    }
+
+    /// Read all of `bytes`, returning the messages they complete
+    pub fn parse(&mut self, bytes: &[u8]) -> Vec<MidiMessage> {
+        bytes.iter().filter_map(|b| self.push(*b)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let messages = vec![
+            MidiMessage::NoteOff {
+                channel: 0,
+                note: 60,
+                velocity: 64,
+            },
+            MidiMessage::NoteOn {
+                channel: 15,
+                note: 81,
+                velocity: 127,
+            },
+            MidiMessage::PolyPressure {
+                channel: 1,
+                note: 11,
+                pressure: 40,
+            },
+            MidiMessage::ControlChange {
+                channel: 0,
+                controller: 19,
+                value: 127,
+            },
+            MidiMessage::ProgramChange {
+                channel: 9,
+                program: 5,
+            },
+            MidiMessage::ChannelPressure {
+                channel: 2,
+                pressure: 99,
+            },
+            MidiMessage::PitchBend {
+                channel: 3,
+                value: 0x2000,
+            },
+            MidiMessage::SysEx(vec![240, 0, 32, 41, 2, 12, 0, 1, 247]),
+            MidiMessage::SystemCommon(vec![0xF2, 0, 8]),
+            MidiMessage::Realtime(0xF8),
+        ];
+        for message in messages {
+            assert_eq!(MidiMessage::decode(&message.encode()).unwrap(), message);
+        }
+        assert_eq!(
+            MidiMessage::PitchBend {
+                channel: 0,
+                value: 0x2000
+            }
+            .encode(),
+            vec![0xE0, 0, 0x40]
+        );
+        assert!(MidiMessage::decode(&[144, 60]).is_err());
+        assert!(MidiMessage::decode(&[60, 100]).is_err());
+        assert!(MidiMessage::decode(&[240, 0, 32]).is_err());
+    }
+
+    #[test]
+    fn running_status() {
+        let mut parser = Parser::new();
+        let messages = parser.parse(&[144, 60, 100, 62, 0xF8, 100, 64, 0, 0xC0, 3, 4]);
+        assert_eq!(
+            messages,
+            vec![
+                MidiMessage::NoteOn {
+                    channel: 0,
+                    note: 60,
+                    velocity: 100
+                },
+                MidiMessage::Realtime(0xF8),
+                MidiMessage::NoteOn {
+                    channel: 0,
+                    note: 62,
+                    velocity: 100
+                },
+                MidiMessage::NoteOn {
+                    channel: 0,
+                    note: 64,
+                    velocity: 0
+                },
+                MidiMessage::ProgramChange {
+                    channel: 0,
+                    program: 3
+                },
+                MidiMessage::ProgramChange {
+                    channel: 0,
+                    program: 4
+                },
+            ]
+        );
+        // SysEx cancels running status, so the 60 is dropped
+        let messages = parser.parse(&[240, 0, 32, 0xFE, 41, 247, 60, 0xB0, 19, 127]);
+        assert_eq!(
+            messages,
+            vec![
+                MidiMessage::Realtime(0xFE),
+                MidiMessage::SysEx(vec![240, 0, 32, 41, 247]),
+                MidiMessage::ControlChange {
+                    channel: 0,
+                    controller: 19,
+                    value: 127
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn orphan_data() {
+        // Data bytes with no status for them are dropped, not kept
+        // for the next message
+        let mut parser = Parser::new();
+        assert_eq!(parser.parse(&[60, 100, 0xF4, 1, 2, 3]), vec![]);
+        for _ in 0..1000 {
+            parser.push(0x40);
+        }
+        assert!(parser.data.is_empty());
+        assert_eq!(
+            parser.parse(&[0xF5, 7, 0x90, 60, 100]),
+            vec![MidiMessage::NoteOn {
+                channel: 0,
+                note: 60,
+                velocity: 100
+            }]
+        );
+        // Tune request takes no data and cancels running status
+        assert_eq!(
+            parser.parse(&[0xF6, 60, 100]),
+            vec![MidiMessage::decode(&[0xF6]).unwrap()]
+        );
+        assert!(parser.data.is_empty());
+    }
+}
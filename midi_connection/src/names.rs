@@ -0,0 +1,109 @@
+//! The names our programmes are known by to the MIDI system, the
+//! client names that `aconnect -l` and patchbays show.  Every
+//! programme takes `--client NAME` on its command line.  By default
+//! it is called `120-Proof-`, the name it was run by and its process
+//! ID (e.g. `120-Proof-lpx_mode-4242`), so two programmes, or two of
+//! the same programme, never share a name.  A programme run with a
+//! configuration file is called after the file instead (e.g.
+//! `120-Proof-lpx_manager-bass` for `bass.cfg`), so its name is the
+//! same each time and patchbays can remember its connections.
+//! Each connection a programme makes adds what it is for to the end
+//! of the client name, e.g. `120-Proof-lpx_manager-bass-LPX-In`.
+use crate::MIDIError;
+use std::env;
+use std::path::Path;
+use std::process;
+
+/// The start of every default client name
+pub const CLIENT_PREFIX: &str = "120-Proof";
+
+/// The file stem of `path`, if it has one
+fn stem(path: &str) -> Option<String> {
+    Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+}
+
+/// `120-Proof-PROGRAMME-INSTANCE`, where `PROGRAMME` is the name this
+/// programme was run by
+fn client_name(instance: &str) -> String {
+    match env::args().next().as_deref().and_then(stem) {
+        Some(programme) => format!("{}-{}-{}", CLIENT_PREFIX, programme, instance),
+        None => format!("{}-{}", CLIENT_PREFIX, instance),
+    }
+}
+
+/// `120-Proof-PROGRAMME-PID`, where `PROGRAMME` is the name this
+/// programme was run by and `PID` its process ID
+pub fn default_client_name() -> String {
+    client_name(process::id().to_string().as_str())
+}
+
+/// `120-Proof-PROGRAMME-CONFIG`, where `CONFIG` is the name of the
+/// configuration file `cfg_fn` without its directory or extension.
+/// For programmes run with a configuration file
+pub fn config_client_name(cfg_fn: &str) -> String {
+    match stem(cfg_fn) {
+        Some(config) => client_name(config.as_str()),
+        None => default_client_name(),
+    }
+}
+
+/// Take `--client NAME` out of `args`, and return `NAME`.  A
+/// `--client` without a name after it is an error
+pub fn client_name_from_args(args: &mut Vec<String>) -> Result<Option<String>, MIDIError> {
+    match args.iter().position(|arg| arg == "--client") {
+        Some(i) if i + 1 < args.len() => {
+            let name = args.remove(i + 1);
+            args.remove(i);
+            Ok(Some(name))
+        }
+        Some(_) => Err(MIDIError::BadArgument {
+            argument: "--client".to_string(),
+            reason: "expected a client name after it".to_string(),
+        }),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_name_argument() {
+        let mut args: Vec<String> = ["lpx_manager", "--client", "Bass", "midi.cfg"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            client_name_from_args(&mut args).unwrap(),
+            Some("Bass".to_string())
+        );
+        assert_eq!(args, vec!["lpx_manager", "midi.cfg"]);
+        assert_eq!(client_name_from_args(&mut args).unwrap(), None);
+        // Not taken for the configuration file
+        let mut args = vec!["lpx_manager".to_string(), "--client".to_string()];
+        assert!(matches!(
+            client_name_from_args(&mut args),
+            Err(MIDIError::BadArgument { .. })
+        ));
+    }
+
+    #[test]
+    fn default_names_are_unique() {
+        // Two programmes, or two of the same, have different names
+        let default = default_client_name();
+        assert!(default.starts_with(CLIENT_PREFIX));
+        assert!(default.ends_with(format!("-{}", process::id()).as_str()));
+
+        let bass = config_client_name("/home/me/lpx/bass.cfg");
+        let lead = config_client_name("lead.cfg");
+        assert!(bass.starts_with(CLIENT_PREFIX) && bass.ends_with("-bass"));
+        assert!(lead.ends_with("-lead"));
+        assert_ne!(bass, lead);
+        assert_ne!(bass, default);
+        // The same file gives the same name each time
+        assert_eq!(config_client_name("bass.cfg"), bass);
+    }
+}
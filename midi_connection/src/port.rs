@@ -0,0 +1,244 @@
+//! Choosing a MIDI port by name.  A `PortMatch` is usually written as
+//! a string, in `midi.cfg` or on the command line:
+//!
+//! * `exact:NAME` The port called `NAME`.  The ALSA client and port
+//!   numbers that `midir` adds to the end of names may be left off
+//! * `prefix:NAME` The port whose name starts with `NAME`
+//! * `substring:NAME` The port whose name contains `NAME`
+//! * `regex:RE` The port whose name matches the regular expression
+//!   `RE`
+//! * `CLIENT:PORT` (or `alsa:CLIENT:PORT`) The port with those ALSA
+//!   numbers, as shown by `aconnect -l`.  E.g. `24:0`
+//! * `index:N` The `N`th port, counting from 0
+//!
+//! Anything else is a prefix, so the existing `midi.cfg` entries
+//! like `Launchpad X:Launchpad X MIDI 1` keep working.
+//!
+//! A match that picks out more than one port is an error, so a
+//! second Launchpad or synthesiser is never connected to by
+//! accident.
+use crate::{Direction, MIDIError};
+use regex::Regex;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone)]
+pub enum PortMatch {
+    Exact(String),
+    Prefix(String),
+    Substring(String),
+    Regex(Regex),
+    AlsaId { client: u32, port: u32 },
+    Index(usize),
+}
+
+/// The Launchpad X has two USB MIDI interfaces.  `Daw` takes the
+/// lighting and mode SysEx, `Midi` is where the notes from the pads
+/// come out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LpxInterface {
+    Daw,
+    Midi,
+}
+
+impl fmt::Display for LpxInterface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LpxInterface::Daw => write!(f, "DAW"),
+            LpxInterface::Midi => write!(f, "MIDI"),
+        }
+    }
+}
+
+/// Guess from its name if `port_name` is one of a Launchpad X's
+/// interfaces.  ALSA calls them "Launchpad X MIDI 1" (DAW) and
+/// "Launchpad X MIDI 2", other systems "LPX DAW" and "LPX MIDI"
+pub fn lpx_interface(port_name: &str) -> Option<LpxInterface> {
+    let name = strip_alsa_id(port_name);
+    if !name.contains("Launchpad X") && !name.contains("LPX ") {
+        return None;
+    }
+    if name.contains("DAW") || name.contains("MIDI 1") {
+        Some(LpxInterface::Daw)
+    } else if name.contains("MIDI") {
+        Some(LpxInterface::Midi)
+    } else {
+        None
+    }
+}
+
+/// Parse `client:port` as a pair of ALSA numbers
+fn parse_alsa_id(s: &str) -> Option<(u32, u32)> {
+    let (client, port) = s.split_once(':')?;
+    Some((client.parse().ok()?, port.parse().ok()?))
+}
+
+/// The ALSA client and port numbers `midir` puts at the end of port
+/// names.  "Launchpad X:Launchpad X MIDI 1 24:0" is `Some((24, 0))`
+pub fn alsa_id(port_name: &str) -> Option<(u32, u32)> {
+    parse_alsa_id(port_name.rsplit_once(' ')?.1)
+}
+
+/// `port_name` without the ALSA numbers on the end, if there are any
+pub fn strip_alsa_id(port_name: &str) -> &str {
+    match port_name.rsplit_once(' ') {
+        Some((name, id)) if parse_alsa_id(id).is_some() => name,
+        _ => port_name,
+    }
+}
+
+impl PortMatch {
+    /// True if the port called `port_name`, that is at `index` in
+    /// the list of ports, matches
+    pub fn matches(&self, index: usize, port_name: &str) -> bool {
+        match self {
+            PortMatch::Exact(name) => port_name == name || strip_alsa_id(port_name) == name,
+            PortMatch::Prefix(name) => port_name.starts_with(name.as_str()),
+            PortMatch::Substring(name) => port_name.contains(name.as_str()),
+            PortMatch::Regex(re) => re.is_match(port_name),
+            PortMatch::AlsaId { client, port } => alsa_id(port_name) == Some((*client, *port)),
+            PortMatch::Index(i) => *i == index,
+        }
+    }
+
+    /// Choose the one port from `port_names` that matches.  It is an
+    /// error if none, or more than one, do
+    pub fn select(&self, port_names: &[String], direction: Direction) -> Result<String, MIDIError> {
+        let matched: Vec<&String> = port_names
+            .iter()
+            .enumerate()
+            .filter(|(index, port_name)| self.matches(*index, port_name.as_str()))
+            .map(|(_, port_name)| port_name)
+            .collect();
+        match matched.as_slice() {
+            [port_name] => Ok(port_name.to_string()),
+            [] => Err(MIDIError::NoMatchingPort {
+                direction,
+                requested: self.to_string(),
+                available: port_names.to_vec(),
+            }),
+            _ => Err(MIDIError::AmbiguousPort {
+                direction,
+                requested: self.to_string(),
+                matched: matched.iter().map(|p| p.to_string()).collect(),
+            }),
+        }
+    }
+}
+
+impl FromStr for PortMatch {
+    type Err = MIDIError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = |reason: &str| MIDIError::BadPortMatch {
+            spec: s.to_string(),
+            reason: reason.to_string(),
+        };
+        let port_match = if let Some(name) = s.strip_prefix("exact:") {
+            PortMatch::Exact(name.to_string())
+        } else if let Some(name) = s.strip_prefix("prefix:") {
+            PortMatch::Prefix(name.to_string())
+        } else if let Some(name) = s.strip_prefix("substring:") {
+            PortMatch::Substring(name.to_string())
+        } else if let Some(re) = s.strip_prefix("regex:") {
+            PortMatch::Regex(Regex::new(re).map_err(|err| bad(err.to_string().as_str()))?)
+        } else if let Some(index) = s.strip_prefix("index:") {
+            PortMatch::Index(index.parse().map_err(|_| bad("index is not a number"))?)
+        } else if let Some(id) = s.strip_prefix("alsa:") {
+            let (client, port) = parse_alsa_id(id).ok_or_else(|| bad("expected CLIENT:PORT"))?;
+            PortMatch::AlsaId { client, port }
+        } else if let Some((client, port)) = parse_alsa_id(s) {
+            PortMatch::AlsaId { client, port }
+        } else {
+            PortMatch::Prefix(s.to_string())
+        };
+        match &port_match {
+            PortMatch::Exact(name) | PortMatch::Prefix(name) | PortMatch::Substring(name)
+                if name.is_empty() =>
+            {
+                Err(bad("no port name given"))
+            }
+            _ => Ok(port_match),
+        }
+    }
+}
+
+impl fmt::Display for PortMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortMatch::Exact(name) => write!(f, "exact:{}", name),
+            PortMatch::Prefix(name) => write!(f, "prefix:{}", name),
+            PortMatch::Substring(name) => write!(f, "substring:{}", name),
+            PortMatch::Regex(re) => write!(f, "regex:{}", re.as_str()),
+            PortMatch::AlsaId { client, port } => write!(f, "{}:{}", client, port),
+            PortMatch::Index(index) => write!(f, "index:{}", index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ports() -> Vec<String> {
+        vec![
+            "Midi Through:Midi Through Port-0 14:0".to_string(),
+            "Launchpad X:Launchpad X MIDI 1 24:0".to_string(),
+            "Launchpad X:Launchpad X MIDI 2 24:1".to_string(),
+        ]
+    }
+
+    fn select(spec: &str) -> Result<String, MIDIError> {
+        spec.parse::<PortMatch>()?
+            .select(&ports(), Direction::Output)
+    }
+
+    #[test]
+    fn strategies() {
+        let lpx1 = "Launchpad X:Launchpad X MIDI 1 24:0";
+        let lpx2 = "Launchpad X:Launchpad X MIDI 2 24:1";
+        assert_eq!(select("Launchpad X:Launchpad X MIDI 1").unwrap(), lpx1);
+        assert_eq!(
+            select("exact:Launchpad X:Launchpad X MIDI 2").unwrap(),
+            lpx2
+        );
+        assert_eq!(
+            select("exact:Launchpad X:Launchpad X MIDI 2 24:1").unwrap(),
+            lpx2
+        );
+        assert_eq!(select("substring:MIDI 2").unwrap(), lpx2);
+        assert_eq!(select("regex:MIDI 1 ").unwrap(), lpx1);
+        assert_eq!(select("24:1").unwrap(), lpx2);
+        assert_eq!(select("alsa:24:0").unwrap(), lpx1);
+        assert_eq!(select("index:2").unwrap(), lpx2);
+    }
+
+    #[test]
+    fn lpx_interfaces() {
+        let interfaces: Vec<Option<LpxInterface>> =
+            ports().iter().map(|p| lpx_interface(p)).collect();
+        assert_eq!(
+            interfaces,
+            vec![None, Some(LpxInterface::Daw), Some(LpxInterface::Midi)]
+        );
+        assert_eq!(lpx_interface("LPX DAW Out"), Some(LpxInterface::Daw));
+        assert_eq!(lpx_interface("LPX MIDI In"), Some(LpxInterface::Midi));
+        // Our own ports are not the LPX
+        assert_eq!(lpx_interface("120-Proof-MIDI-In-LPX"), None);
+    }
+
+    #[test]
+    fn ambiguous_and_missing() {
+        assert!(matches!(
+            select("Launchpad X"),
+            Err(MIDIError::AmbiguousPort { .. })
+        ));
+        assert!(matches!(
+            select("exact:Launchpad X"),
+            Err(MIDIError::NoMatchingPort { .. })
+        ));
+        assert!(matches!(
+            "".parse::<PortMatch>(),
+            Err(MIDIError::BadPortMatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,186 @@
+//! Playing a recorded session back through a `Loopback`, to
+//! reproduce bugs and to check that changes do not alter what is
+//! sent.
+//!
+//! The messages recorded coming in (`Direction::Input`) from one port
+//! are injected into one loopback port, in order.  What came in from
+//! other ports (e.g. the LPX's replies on its DAW port) is left out.
+//! After each one the messages sent to the output ports are
+//! collected, stamped with the time of the input that caused them.
+//! So the output does not depend on how fast the replay ran, and can
+//! be compared with a golden file written by an earlier run.
+use crate::tap::{text_line, TapRecord};
+use crate::{Direction, Loopback, MIDIError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct Replay {
+    loopback: Loopback,
+    input_port: String,
+    output_ports: Vec<Arc<str>>,
+    // Which recorded ports' inputs are played
+    recorded: Box<dyn Fn(&str) -> bool>,
+    real_time: bool,
+}
+
+impl Replay {
+    /// Inject into `input_port` of `loopback`, and collect what is
+    /// sent to `output_ports`.  Only what was recorded coming in from
+    /// a port called `input_port` is played, unless `recorded` says
+    /// otherwise
+    pub fn new(loopback: &Loopback, input_port: &str, output_ports: &[&str]) -> Self {
+        let recorded_port = input_port.to_string();
+        Replay {
+            loopback: loopback.clone(),
+            input_port: input_port.to_string(),
+            output_ports: output_ports.iter().map(|p| Arc::from(*p)).collect(),
+            recorded: Box::new(move |port| port == recorded_port),
+            real_time: false,
+        }
+    }
+
+    /// Play what was recorded coming in from the ports `port` is true
+    /// for.  E.g. the pads of any LPX:
+    ///
+    /// ```
+    /// # use midi_connection::{lpx_interface, LpxInterface, Loopback, Replay};
+    /// # let loopback = Loopback::new(&["LPX", "Synth"]);
+    /// let replay = Replay::new(&loopback, "LPX", &["Synth"])
+    ///     .recorded(|port| lpx_interface(port) == Some(LpxInterface::Midi));
+    /// ```
+    pub fn recorded<F: Fn(&str) -> bool + 'static>(mut self, port: F) -> Self {
+        self.recorded = Box::new(port);
+        self
+    }
+
+    /// If `real_time` is true wait between inputs as long as the
+    /// recording did.  Off by default, the inputs go as fast as they
+    /// are handled
+    pub fn real_time(mut self, real_time: bool) -> Self {
+        self.real_time = real_time;
+        self
+    }
+
+    /// The messages sent to the output ports since last time
+    fn collect(&self, time: Duration, output: &mut Vec<TapRecord>) {
+        for port in self.output_ports.iter() {
+            for bytes in self.loopback.take_sent(port) {
+                output.push(TapRecord {
+                    time,
+                    direction: Direction::Output,
+                    port: port.clone(),
+                    bytes,
+                });
+            }
+        }
+    }
+
+    /// Play the inputs in `records`.  `settle` is called after each
+    /// one, and at the start, to wait for any output handled on other
+    /// threads (e.g. `LedPipeline::flush`).  Returns the output, with
+    /// anything sent before the first input at time zero
+    pub fn run<F: FnMut()>(
+        &self,
+        records: &[TapRecord],
+        mut settle: F,
+    ) -> Vec<TapRecord> {
+        let mut output = Vec::new();
+        settle();
+        self.collect(Duration::ZERO, &mut output);
+        let start = Instant::now();
+        for record in records
+            .iter()
+            .filter(|r| r.direction == Direction::Input && (self.recorded)(&r.port))
+        {
+            if self.real_time {
+                thread::sleep(record.time.saturating_sub(start.elapsed()));
+            }
+            self.loopback
+                .inject(self.input_port.as_str(), &record.bytes);
+            settle();
+            self.collect(record.time, &mut output);
+        }
+        output
+    }
+}
+
+/// Check `actual` output against `golden`
+pub fn compare_golden(
+    actual: &[TapRecord],
+    golden: &[TapRecord],
+) -> Result<(), MIDIError> {
+    let length = actual.len().max(golden.len());
+    for index in 0..length {
+        let expected = golden.get(index).map(text_line);
+        let got = actual.get(index).map(text_line);
+        if expected != got {
+            return Err(MIDIError::GoldenMismatch {
+                index,
+                expected,
+                actual: got,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::loopback_output;
+    use crate::MIDICommunicator;
+
+    fn input(millis: u64, bytes: &[u8]) -> TapRecord {
+        TapRecord {
+            time: Duration::from_millis(millis),
+            direction: Direction::Input,
+            port: Arc::from("LPX"),
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    #[test]
+    fn replay_and_compare() {
+        // Pads on "LPX" are played, transposed, on "Synth"
+        let loopback = Loopback::new(&["LPX", "Synth"]);
+        let midi_out = loopback_output(&loopback, "Synth");
+        let _midi_in = MIDICommunicator::builder("LPX", "120-Proof-Test")
+            .direction(Direction::Input)
+            .transport(Arc::new(loopback.clone()))
+            .callback(
+                |_, message, midi_out: &mut MIDICommunicator| {
+                    midi_out
+                        .send(&[message[0], message[1] + 12, message[2]])
+                        .unwrap()
+                },
+                midi_out,
+            )
+            .build()
+            .unwrap();
+
+        // The LPX's reply on another port is not played
+        let reply = TapRecord {
+            port: Arc::from("LPX DAW"),
+            ..input(100, &[240, 0, 32, 41, 2, 12, 0, 1, 247])
+        };
+        let session = vec![input(0, &[144, 48, 100]), reply, input(250, &[144, 48, 0])];
+        let output = Replay::new(&loopback, "LPX", &["Synth"]).run(&session, || ());
+        let synth = |millis, bytes: &[u8]| TapRecord {
+            time: Duration::from_millis(millis),
+            direction: Direction::Output,
+            port: Arc::from("Synth"),
+            bytes: bytes.to_vec(),
+        };
+        let golden = vec![synth(0, &[144, 60, 100]), synth(250, &[144, 60, 0])];
+        assert!(compare_golden(&output, &golden).is_ok());
+        assert!(matches!(
+            compare_golden(&output, &golden[..1]),
+            Err(MIDIError::GoldenMismatch {
+                index: 1,
+                expected: None,
+                ..
+            })
+        ));
+    }
+}
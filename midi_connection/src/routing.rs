@@ -0,0 +1,399 @@
+//! Connecting MIDI ports to each other, through filters and
+//! transforms, as set out in a configuration file.  Instead of each
+//! programme plumbing its own ports together (and users untangling
+//! doubled notes in a patchbay) the routing is declared:
+//!
+//! ```text
+//! # Ports.  A name, then the port as in midi.cfg
+//! source lpx Launchpad X:Launchpad X MIDI 2
+//! sink pad yoshimi-Yoshimi01:input
+//! sink bass virtual:120-Proof-Bass
+//!
+//! # route FROM TO, then stages applied in order
+//! route lpx pad
+//! route lpx bass only=note notes=36-59 transpose=-12 channel=2
+//! ```
+//!
+//! A source can have many routes (fan out) and a sink can be the end
+//! of many (merge).  The stages are:
+//!
+//! * `channel=N` Move channel messages to channel `N` (1 to 16)
+//! * `notes=LOW-HIGH` Pass notes from `LOW` to `HIGH` inclusive.
+//!   Messages that are not notes pass
+//! * `transpose=N` Move notes by `N` semitones.  Notes moved out of
+//!   the MIDI range are dropped
+//! * `only=KIND,...` Pass only these kinds of message
+//! * `drop=KIND,...` Pass everything but these kinds of message
+//!
+//! The kinds are `note` (note on and off), `poly` (polyphonic
+//! pressure), `cc`, `program`, `pressure` (channel pressure), `bend`,
+//! `sysex`, `common` and `realtime`.
+use crate::{Direction, MIDICommunicator, MIDIError, MidiMessage, Midir, Transport};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The kinds of message `only=` and `drop=` choose between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Note,
+    PolyPressure,
+    ControlChange,
+    ProgramChange,
+    ChannelPressure,
+    PitchBend,
+    SysEx,
+    SystemCommon,
+    Realtime,
+}
+
+impl MessageKind {
+    pub fn of(message: &MidiMessage) -> MessageKind {
+        match message {
+            MidiMessage::NoteOff { .. } | MidiMessage::NoteOn { .. } => MessageKind::Note,
+            MidiMessage::PolyPressure { .. } => MessageKind::PolyPressure,
+            MidiMessage::ControlChange { .. } => MessageKind::ControlChange,
+            MidiMessage::ProgramChange { .. } => MessageKind::ProgramChange,
+            MidiMessage::ChannelPressure { .. } => MessageKind::ChannelPressure,
+            MidiMessage::PitchBend { .. } => MessageKind::PitchBend,
+            MidiMessage::SysEx(_) => MessageKind::SysEx,
+            MidiMessage::SystemCommon(_) => MessageKind::SystemCommon,
+            MidiMessage::Realtime(_) => MessageKind::Realtime,
+        }
+    }
+}
+
+impl FromStr for MessageKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "note" => MessageKind::Note,
+            "poly" => MessageKind::PolyPressure,
+            "cc" => MessageKind::ControlChange,
+            "program" => MessageKind::ProgramChange,
+            "pressure" => MessageKind::ChannelPressure,
+            "bend" => MessageKind::PitchBend,
+            "sysex" => MessageKind::SysEx,
+            "common" => MessageKind::SystemCommon,
+            "realtime" => MessageKind::Realtime,
+            _ => return Err(format!("unknown kind of message \"{}\"", s)),
+        })
+    }
+}
+
+/// One filter or transform on a route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stage {
+    /// Move channel messages to this channel (0 to 15)
+    Channel(u8),
+    /// Pass notes in this range, inclusive
+    Notes(u8, u8),
+    Transpose(i8),
+    /// Pass only these kinds
+    Only(Vec<MessageKind>),
+    /// Pass all but these kinds
+    Drop(Vec<MessageKind>),
+}
+
+impl Stage {
+    /// `message` after this stage.  `None` if it is dropped
+    pub fn apply(&self, mut message: MidiMessage) -> Option<MidiMessage> {
+        match self {
+            Stage::Channel(channel) => {
+                message.set_channel(*channel);
+                Some(message)
+            }
+            Stage::Notes(low, high) => match message.note() {
+                Some(note) if note < *low || note > *high => None,
+                _ => Some(message),
+            },
+            Stage::Transpose(semitones) => message.transposed(*semitones as i16),
+            Stage::Only(kinds) => Some(message).filter(|m| kinds.contains(&MessageKind::of(m))),
+            Stage::Drop(kinds) => Some(message).filter(|m| !kinds.contains(&MessageKind::of(m))),
+        }
+    }
+}
+
+impl FromStr for Stage {
+    type Err = String;
+
+    /// `KEY=VALUE`, as in a route
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected KEY=VALUE, found \"{}\"", s))?;
+        let kinds = || {
+            value
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<Vec<MessageKind>, String>>()
+        };
+        let note = |n: &str| match n.parse::<u8>() {
+            Ok(note) if note < 0x80 => Ok(note),
+            _ => Err(format!("bad note \"{}\"", n)),
+        };
+        match key {
+            "channel" => match value.parse::<u8>() {
+                Ok(channel @ 1..=16) => Ok(Stage::Channel(channel - 1)),
+                _ => Err("channel must be 1 to 16".to_string()),
+            },
+            "notes" => {
+                let (low, high) = value
+                    .split_once('-')
+                    .ok_or_else(|| "expected notes=LOW-HIGH".to_string())?;
+                match (note(low)?, note(high)?) {
+                    (low, high) if low > high => Err(format!(
+                        "notes={}-{} is empty: LOW is above HIGH",
+                        low, high
+                    )),
+                    (low, high) => Ok(Stage::Notes(low, high)),
+                }
+            }
+            "transpose" => value
+                .parse()
+                .map(Stage::Transpose)
+                .map_err(|_| "transpose must be a number of semitones".to_string()),
+            "only" => Ok(Stage::Only(kinds()?)),
+            "drop" => Ok(Stage::Drop(kinds()?)),
+            _ => Err(format!("unknown stage \"{}\"", key)),
+        }
+    }
+}
+
+/// Messages from the source `from` go to the sink `to` through
+/// `stages`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub from: String,
+    pub to: String,
+    pub stages: Vec<Stage>,
+}
+
+impl Route {
+    /// `message` as it arrives at the sink.  `None` if it does not
+    pub fn apply(&self, message: &MidiMessage) -> Option<MidiMessage> {
+        self.stages
+            .iter()
+            .try_fold(message.clone(), |message, stage| stage.apply(message))
+    }
+}
+
+/// The ports and the routes between them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteConfig {
+    /// Names, and the ports they are
+    pub sources: Vec<(String, String)>,
+    pub sinks: Vec<(String, String)>,
+    pub routes: Vec<Route>,
+}
+
+impl RouteConfig {
+    /// Read a configuration file
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<RouteConfig, MIDIError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+}
+
+impl FromStr for RouteConfig {
+    type Err = MIDIError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = RouteConfig::default();
+        for (index, line) in s.lines().enumerate() {
+            let bad = |reason: String| MIDIError::BadRoute {
+                line: index + 1,
+                reason,
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let (keyword, name) = match (words.next(), words.next()) {
+                (Some(keyword), Some(name)) => (keyword, name.to_string()),
+                _ => return Err(bad("expected source, sink or route and a name".to_string())),
+            };
+            let declared =
+                |ports: &[(String, String)], name: &str| ports.iter().any(|(n, _)| n == name);
+            match keyword {
+                "source" | "sink" => {
+                    if declared(&config.sources, &name) || declared(&config.sinks, &name) {
+                        return Err(bad(format!("\"{}\" is already declared", name)));
+                    }
+                    // The port is the rest of the line, spaces and all
+                    let port = line[keyword.len()..].trim_start()[name.len()..].trim();
+                    if port.is_empty() {
+                        return Err(bad(format!("no port given for \"{}\"", name)));
+                    }
+                    let ports = match keyword {
+                        "source" => &mut config.sources,
+                        _ => &mut config.sinks,
+                    };
+                    ports.push((name, port.to_string()));
+                }
+                "route" => {
+                    let to = words
+                        .next()
+                        .ok_or_else(|| bad("expected route FROM TO".to_string()))?
+                        .to_string();
+                    if !declared(&config.sources, &name) {
+                        return Err(bad(format!("no source called \"{}\"", name)));
+                    }
+                    if !declared(&config.sinks, &to) {
+                        return Err(bad(format!("no sink called \"{}\"", to)));
+                    }
+                    let stages = words
+                        .map(str::parse)
+                        .collect::<Result<Vec<Stage>, String>>()
+                        .map_err(bad)?;
+                    config.routes.push(Route {
+                        from: name,
+                        to,
+                        stages,
+                    });
+                }
+                _ => return Err(bad(format!("unknown keyword \"{}\"", keyword))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// The running connections of a `RouteConfig`.  Routing stops when
+/// it is dropped
+#[derive(Debug)]
+pub struct Router {
+    _sources: Vec<MIDICommunicator>,
+    _sinks: Vec<MIDICommunicator>,
+}
+
+impl Router {
+    /// Connect the ports of `config` and start routing.
+    /// `client_name` and the port's name make the client name of each
+    /// connection
+    pub fn start(config: &RouteConfig, client_name: &str) -> Result<Router, MIDIError> {
+        Self::start_with(config, client_name, Arc::new(Midir))
+    }
+
+    /// Start routing through `transport`
+    pub fn start_with(
+        config: &RouteConfig,
+        client_name: &str,
+        transport: Arc<dyn Transport>,
+    ) -> Result<Router, MIDIError> {
+        let mut sinks: HashMap<&str, MIDICommunicator> = HashMap::new();
+        for (name, port) in config.sinks.iter() {
+            let sink =
+                MIDICommunicator::builder(port, format!("{}-{}", client_name, name).as_str())
+                    .direction(Direction::Output)
+                    .transport(transport.clone())
+                    .reconnect(true)
+                    .build()?;
+            sinks.insert(name.as_str(), sink);
+        }
+        let mut sources = Vec::new();
+        for (name, port) in config.sources.iter() {
+            // Each source has its own routes, with handles to their
+            // sinks
+            let routes: Vec<(Route, MIDICommunicator)> = config
+                .routes
+                .iter()
+                .filter(|route| &route.from == name)
+                .map(|route| (route.clone(), sinks[route.to.as_str()].clone()))
+                .collect();
+            let source =
+                MIDICommunicator::builder(port, format!("{}-{}", client_name, name).as_str())
+                    .direction(Direction::Input)
+                    .transport(transport.clone())
+                    .reconnect(true)
+                    .callback(
+                        |_, bytes, routes: &mut Vec<(Route, MIDICommunicator)>| {
+                            // What cannot be read cannot be routed
+                            let message = match MidiMessage::decode(bytes) {
+                                Ok(message) => message,
+                                Err(_) => return,
+                            };
+                            for (route, sink) in routes.iter_mut() {
+                                if let Some(message) = route.apply(&message) {
+                                    // A missing sink is reported by its
+                                    // connection, and the other routes
+                                    // carry on
+                                    let _ = sink.send_message(&message);
+                                }
+                            }
+                        },
+                        routes,
+                    )
+                    .build()?;
+            sources.push(source);
+        }
+        Ok(Router {
+            _sources: sources,
+            _sinks: sinks.into_values().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Loopback;
+
+    const CONFIG: &str = "
+        # The LPX plays both
+        source lpx Launchpad X MIDI 2
+        sink pad Pad Synth
+        sink bass Bass Synth
+
+        route lpx pad drop=cc
+        route lpx bass only=note notes=36-59 transpose=-12 channel=2
+    ";
+
+    #[test]
+    fn parse() {
+        let config: RouteConfig = CONFIG.parse().unwrap();
+        assert_eq!(
+            config.sources,
+            vec![("lpx".to_string(), "Launchpad X MIDI 2".to_string())]
+        );
+        assert_eq!(config.sinks.len(), 2);
+        assert_eq!(
+            config.routes[1].stages,
+            vec![
+                Stage::Only(vec![MessageKind::Note]),
+                Stage::Notes(36, 59),
+                Stage::Transpose(-12),
+                Stage::Channel(1)
+            ]
+        );
+        for (bad, line) in [
+            ("route lpx pad", 1),
+            ("source lpx LPX\nroute lpx synth", 2),
+            ("source lpx LPX\nsink s S\nroute lpx s channel=0", 3),
+            ("source lpx", 1),
+            ("source lpx LPX\nsink s S\nroute lpx s notes=60-40", 3),
+        ] {
+            assert!(
+                matches!(bad.parse::<RouteConfig>(), Err(MIDIError::BadRoute { line: l, .. }) if l == line),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn routes_through_stages() {
+        let loopback = Loopback::new(&["Launchpad X MIDI 2", "Pad Synth", "Bass Synth"]);
+        let config: RouteConfig = CONFIG.parse().unwrap();
+        let _router =
+            Router::start_with(&config, "120-Proof-Test", Arc::new(loopback.clone())).unwrap();
+        let lpx = "Launchpad X MIDI 2";
+        loopback.inject(lpx, &[0x90, 48, 100]);
+        loopback.inject(lpx, &[0x90, 70, 100]);
+        loopback.inject(lpx, &[0xB0, 19, 127]);
+        assert_eq!(
+            loopback.sent("Pad Synth"),
+            vec![vec![0x90, 48, 100], vec![0x90, 70, 100]]
+        );
+        assert_eq!(loopback.sent("Bass Synth"), vec![vec![0x91, 36, 100]]);
+    }
+}
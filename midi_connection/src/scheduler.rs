@@ -0,0 +1,270 @@
+//! Sending MIDI at given times.  A `Scheduler` owns a thread that
+//! sleeps until each message is due and then sends it.  Arpeggios,
+//! note repeat and flashing pads are queued up in advance, instead
+//! of calling `thread::sleep` between sends.
+//!
+//! Times are `Instant`s, so they are monotonic and not affected by
+//! the clock being changed.  Each message has a tag, and all the
+//! messages with a tag can be cancelled at once (e.g. when the pad
+//! that started an arpeggio is released).
+//!
+//! On unix the thread asks for real time (`SCHED_FIFO`) priority.
+//! That needs `CAP_SYS_NICE` or an `rtprio` limit (as set up for
+//! JACK), and without them the thread runs at normal priority.  How
+//! late each message was sent can be reported, to see if it matters.
+use crate::{MIDICommunicator, MIDIError, MidiMessage};
+use crossbeam_channel::{Receiver, Sender};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The `SCHED_FIFO` priority asked for.  Below JACK's default (so
+/// audio comes first) and above everything else
+#[cfg(unix)]
+const PRIORITY: i32 = 70;
+
+/// How a scheduled message went
+#[derive(Debug)]
+pub struct SendReport {
+    pub tag: u64,
+    /// When it should have been sent
+    pub due: Instant,
+    /// How long after `due` it was sent
+    pub lateness: Duration,
+    pub result: Result<(), MIDIError>,
+}
+
+struct Entry {
+    due: Instant,
+    // Messages due at the same time are sent in the order they were
+    // scheduled
+    seq: u64,
+    tag: u64,
+    bytes: Vec<u8>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Entry {}
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.due, self.seq).cmp(&(other.due, other.seq))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    // Soonest first
+    entries: BinaryHeap<Reverse<Entry>>,
+    next_seq: u64,
+    stop: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    // Signalled when the queue changes
+    changed: Condvar,
+    realtime: AtomicBool,
+}
+
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Scheduler")
+            .field("pending", &self.pending())
+            .field("realtime", &self.is_realtime())
+            .finish()
+    }
+}
+
+/// Ask for real time priority for this thread
+#[cfg(unix)]
+fn raise_priority() -> bool {
+    // SAFETY: `sched_param` is plain data and the call only affects
+    // this thread
+    unsafe {
+        let mut param: libc::sched_param = std::mem::zeroed();
+        param.sched_priority = PRIORITY.min(libc::sched_get_priority_max(libc::SCHED_FIFO));
+        libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) == 0
+    }
+}
+
+#[cfg(not(unix))]
+fn raise_priority() -> bool {
+    false
+}
+
+impl Scheduler {
+    /// Send scheduled messages to `midi_out`
+    pub fn new(midi_out: MIDICommunicator) -> Scheduler {
+        Self::start(midi_out, None)
+    }
+
+    /// Send scheduled messages to `midi_out`, and report each send
+    /// on the receiver
+    pub fn with_reports(midi_out: MIDICommunicator) -> (Scheduler, Receiver<SendReport>) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        (Self::start(midi_out, Some(sender)), receiver)
+    }
+
+    fn start(mut midi_out: MIDICommunicator, reports: Option<Sender<SendReport>>) -> Scheduler {
+        let shared = Arc::new(Shared::default());
+        let thread_shared = shared.clone();
+        let thread = thread::spawn(move || {
+            let shared = thread_shared;
+            shared
+                .realtime
+                .store(raise_priority(), AtomicOrdering::Relaxed);
+            let mut queue = shared.queue.lock().unwrap();
+            while !queue.stop {
+                let now = Instant::now();
+                let due = match queue.entries.peek() {
+                    Some(Reverse(entry)) => entry.due,
+                    None => {
+                        queue = shared.changed.wait(queue).unwrap();
+                        continue;
+                    }
+                };
+                if due > now {
+                    queue = shared.changed.wait_timeout(queue, due - now).unwrap().0;
+                    continue;
+                }
+                let Reverse(entry) = queue.entries.pop().unwrap();
+                // Do not hold the queue while sending, so scheduling
+                // is never held up by a slow MIDI port
+                drop(queue);
+                let lateness = Instant::now().saturating_duration_since(entry.due);
+                let result = midi_out.send(&entry.bytes);
+                if let Some(reports) = reports.as_ref() {
+                    let _ = reports.send(SendReport {
+                        tag: entry.tag,
+                        due: entry.due,
+                        lateness,
+                        result,
+                    });
+                }
+                queue = shared.queue.lock().unwrap();
+            }
+        });
+        Scheduler {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Send `bytes` at `due`.  If `due` has passed send it now
+    pub fn schedule(&self, due: Instant, tag: u64, bytes: &[u8]) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.entries.push(Reverse(Entry {
+            due,
+            seq,
+            tag,
+            bytes: bytes.to_vec(),
+        }));
+        self.shared.changed.notify_one();
+    }
+
+    /// Send `message` at `due`
+    pub fn schedule_message(&self, due: Instant, tag: u64, message: &MidiMessage) {
+        self.schedule(due, tag, &message.encode());
+    }
+
+    /// Drop all the messages, not yet sent, tagged `tag`.  Returns
+    /// how many were dropped
+    pub fn cancel(&self, tag: u64) -> usize {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let before = queue.entries.len();
+        queue.entries.retain(|Reverse(entry)| entry.tag != tag);
+        let cancelled = before - queue.entries.len();
+        if cancelled > 0 {
+            self.shared.changed.notify_one();
+        }
+        cancelled
+    }
+
+    /// The number of messages waiting to be sent
+    pub fn pending(&self) -> usize {
+        self.shared.queue.lock().unwrap().entries.len()
+    }
+
+    /// True if the sending thread got real time priority
+    pub fn is_realtime(&self) -> bool {
+        self.shared.realtime.load(AtomicOrdering::Relaxed)
+    }
+}
+
+impl Drop for Scheduler {
+    /// Messages not yet sent are dropped
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().stop = true;
+        self.shared.changed.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::loopback_output;
+    use crate::Loopback;
+
+    fn scheduler(loopback: &Loopback) -> (Scheduler, Receiver<SendReport>) {
+        let midi_out = loopback_output(loopback, "Synth");
+        Scheduler::with_reports(midi_out)
+    }
+
+    #[test]
+    fn sends_in_time_order() {
+        let loopback = Loopback::new(&["Synth"]);
+        let (scheduler, reports) = scheduler(&loopback);
+        let start = Instant::now();
+        scheduler.schedule(start + Duration::from_millis(30), 1, &[144, 64, 100]);
+        scheduler.schedule(start + Duration::from_millis(10), 1, &[144, 60, 100]);
+        scheduler.schedule(start + Duration::from_millis(20), 1, &[144, 62, 100]);
+        for _ in 0..3 {
+            let report = reports.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert!(report.result.is_ok());
+            assert!(report.due >= start);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(
+            loopback.sent("Synth"),
+            vec![vec![144, 60, 100], vec![144, 62, 100], vec![144, 64, 100]]
+        );
+    }
+
+    #[test]
+    fn cancel_by_tag() {
+        let loopback = Loopback::new(&["Synth"]);
+        let (scheduler, reports) = scheduler(&loopback);
+        let later = Instant::now() + Duration::from_millis(20);
+        scheduler.schedule(later, 1, &[144, 60, 100]);
+        scheduler.schedule(later, 2, &[144, 62, 100]);
+        scheduler.schedule(later, 1, &[144, 64, 100]);
+        assert_eq!(scheduler.cancel(1), 2);
+        assert_eq!(scheduler.pending(), 1);
+        let report = reports.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(report.tag, 2);
+        assert_eq!(loopback.sent("Synth"), vec![vec![144, 62, 100]]);
+    }
+}
@@ -0,0 +1,376 @@
+//! Stopping cleanly.  A programme killed while a note is held leaves
+//! the synthesiser sounding it, and the Launchpad X lit up in the
+//! colours the programme gave it.
+//!
+//! `Shutdown` turns SIGINT, SIGTERM and SIGHUP into a message the
+//! main loop can wait for.  The loop returns, and the cleanup is done
+//! as things are dropped:
+//!
+//! * The guard from `FanOut::release_on_drop` sends a note off for
+//!   each note still held, and All Notes Off, to the synthesisers
+//! * `LpxRestore` does what `--on-exit` asks to the Launchpad: leave
+//!   it (`keep`), turn the pads off (`clear`), or turn them off and
+//!   put back the layout it was in when the programme started
+//!   (`restore`)
+//!
+//! A second signal while cleaning up stops the programme at once.
+use crate::{Direction, MIDICommunicator, MIDIError, MidiMessage, Transport, LPX_HEADER};
+use crossbeam_channel::Receiver;
+use lpx_protocol::{layout_reply, query_layout, select_layout, Layout, LedMessage, Pad};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The controller that turns off every note on a channel
+pub const ALL_NOTES_OFF: u8 = 123;
+
+/// How long to wait for the Launchpad X to say what layout it is in
+const LAYOUT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The notes that are on, on each channel.  Kept with atomics, so
+/// notes can be tracked as they are sent without locking
+#[derive(Debug, Default)]
+pub struct HeldNotes {
+    // A bit for each note, two words to a channel
+    held: [AtomicU64; 32],
+    // A bit for each channel a note has been played on
+    played: AtomicU16,
+}
+
+impl HeldNotes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The word and bit for `note` on `channel`
+    fn bit(channel: u8, note: u8) -> (usize, u64) {
+        let note = note & 0x7F;
+        (
+            (channel as usize & 0x0F) * 2 + note as usize / 64,
+            1 << (note % 64),
+        )
+    }
+
+    /// Keep track of `message` as it is sent
+    pub fn track(&self, message: &MidiMessage) {
+        match *message {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } if velocity > 0 => {
+                let (word, bit) = Self::bit(channel, note);
+                self.held[word].fetch_or(bit, Ordering::Relaxed);
+                self.played
+                    .fetch_or(1 << (channel & 0x0F), Ordering::Relaxed);
+            }
+            MidiMessage::NoteOn { channel, note, .. }
+            | MidiMessage::NoteOff { channel, note, .. } => {
+                let (word, bit) = Self::bit(channel, note);
+                self.held[word].fetch_and(!bit, Ordering::Relaxed);
+            }
+            MidiMessage::ControlChange {
+                channel,
+                controller: ALL_NOTES_OFF,
+                ..
+            } => {
+                let word = (channel as usize & 0x0F) * 2;
+                self.held[word].store(0, Ordering::Relaxed);
+                self.held[word + 1].store(0, Ordering::Relaxed);
+            }
+            _ => (),
+        }
+    }
+
+    /// True if `note` is on on `channel`
+    pub fn is_held(&self, channel: u8, note: u8) -> bool {
+        let (word, bit) = Self::bit(channel, note);
+        self.held[word].load(Ordering::Relaxed) & bit != 0
+    }
+
+    /// The messages that stop every note: a note off for each one
+    /// held, then All Notes Off on each channel that has been played
+    /// on, in case a note was missed.  They are all forgotten
+    pub fn release(&self) -> Vec<MidiMessage> {
+        let mut messages = Vec::new();
+        for channel in 0..16_u8 {
+            for note in 0..128_u8 {
+                let (word, bit) = Self::bit(channel, note);
+                if self.held[word].fetch_and(!bit, Ordering::Relaxed) & bit != 0 {
+                    messages.push(MidiMessage::NoteOff {
+                        channel,
+                        note,
+                        velocity: 0,
+                    });
+                }
+            }
+        }
+        let played = self.played.swap(0, Ordering::Relaxed);
+        for channel in (0..16_u8).filter(|c| played & (1 << c) != 0) {
+            messages.push(MidiMessage::ControlChange {
+                channel,
+                controller: ALL_NOTES_OFF,
+                value: 0,
+            });
+        }
+        messages
+    }
+}
+
+/// Waits for a signal to stop.  SIGINT, SIGTERM and SIGHUP are
+/// caught from when it is made until it is dropped
+pub struct Shutdown {
+    receiver: Receiver<i32>,
+    #[cfg(unix)]
+    handle: signal_hook::iterator::Handle,
+    // Keeps `receiver` open where there are no signals to catch
+    #[cfg(not(unix))]
+    _sender: crossbeam_channel::Sender<i32>,
+}
+
+impl std::fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Shutdown").finish()
+    }
+}
+
+impl Shutdown {
+    #[cfg(unix)]
+    pub fn new() -> Result<Shutdown, MIDIError> {
+        use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+        let mut signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM, SIGHUP])
+            .map_err(|err| MIDIError::Signal(err.to_string()))?;
+        let handle = signals.handle();
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        std::thread::spawn(move || {
+            let mut stopping = false;
+            for signal in signals.forever() {
+                if stopping {
+                    // Cleaning up is stuck
+                    std::process::exit(exit_status(signal));
+                }
+                stopping = true;
+                let _ = sender.try_send(signal);
+            }
+        });
+        Ok(Shutdown { receiver, handle })
+    }
+
+    /// There are no signals to catch.  `wait` never returns
+    #[cfg(not(unix))]
+    pub fn new() -> Result<Shutdown, MIDIError> {
+        let (_sender, receiver) = crossbeam_channel::bounded(1);
+        Ok(Shutdown { receiver, _sender })
+    }
+
+    /// Gets the number of the signal when one comes.  For `select!`
+    pub fn receiver(&self) -> &Receiver<i32> {
+        &self.receiver
+    }
+
+    /// Wait for a signal, and return its number
+    pub fn wait(&self) -> i32 {
+        // The sender is only dropped with `self`
+        self.receiver.recv().unwrap_or(0)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        self.handle.close();
+    }
+}
+
+/// The exit status for a programme stopped by `signal`.  The same as
+/// the shell reports for a programme killed by it
+pub fn exit_status(signal: i32) -> i32 {
+    128 + signal
+}
+
+/// What to do to the Launchpad X when a programme stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnExit {
+    /// Leave the pads lit
+    #[default]
+    Keep,
+    /// Turn every pad off
+    Clear,
+    /// Turn every pad off and put back the layout it was in
+    Restore,
+}
+
+impl FromStr for OnExit {
+    type Err = MIDIError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(OnExit::Keep),
+            "clear" => Ok(OnExit::Clear),
+            "restore" => Ok(OnExit::Restore),
+            _ => Err(MIDIError::BadArgument {
+                argument: format!("--on-exit {}", s),
+                reason: "expected keep, clear or restore".to_string(),
+            }),
+        }
+    }
+}
+
+/// Take `--on-exit WHAT` out of `args`, and return `WHAT`.  `Keep` if
+/// it is not there
+pub fn on_exit_from_args(args: &mut Vec<String>) -> Result<OnExit, MIDIError> {
+    match args.iter().position(|arg| arg == "--on-exit") {
+        Some(i) if i + 1 < args.len() => {
+            let on_exit = args.remove(i + 1).parse()?;
+            args.remove(i);
+            Ok(on_exit)
+        }
+        Some(_) => Err(MIDIError::BadArgument {
+            argument: "--on-exit".to_string(),
+            reason: "expected keep, clear or restore after it".to_string(),
+        }),
+        None => Ok(OnExit::Keep),
+    }
+}
+
+/// Puts the Launchpad X back, as `OnExit` says, when dropped.  Drop
+/// it after everything else that lights the pads (the last clone of
+/// a `LedPipeline`, a `Scheduler`), or they may be lit again
+#[derive(Debug)]
+pub struct LpxRestore {
+    daw_out: MIDICommunicator,
+    on_exit: OnExit,
+    layout: Option<Layout>,
+}
+
+impl LpxRestore {
+    /// `daw_out` is the Launchpad's DAW port
+    pub fn new(daw_out: MIDICommunicator, on_exit: OnExit) -> Self {
+        LpxRestore {
+            daw_out,
+            on_exit,
+            layout: None,
+        }
+    }
+
+    /// For `OnExit::Restore`, ask the Launchpad what layout it is in
+    /// now, so it can be put back.  The answer comes in on `daw_in`,
+    /// connected to as `client_name`.  Returns the layout, `None` if
+    /// it did not answer.  Does nothing for the others
+    pub fn remember_layout(
+        &mut self,
+        daw_in: &str,
+        client_name: &str,
+        transport: Arc<dyn Transport>,
+    ) -> Result<Option<Layout>, MIDIError> {
+        if self.on_exit != OnExit::Restore {
+            return Ok(None);
+        }
+        let (_daw_in, replies) = MIDICommunicator::builder(daw_in, client_name)
+            .direction(Direction::Input)
+            .transport(transport)
+            .sysex_header(&LPX_HEADER)
+            .build_with_receiver()?;
+        self.daw_out.send(&query_layout())?;
+        let deadline = Instant::now() + LAYOUT_TIMEOUT;
+        while let Ok(reply) = replies.recv_deadline(deadline) {
+            if let Some(layout) = layout_reply(&reply.bytes) {
+                self.layout = Some(layout);
+                break;
+            }
+        }
+        Ok(self.layout)
+    }
+}
+
+impl Drop for LpxRestore {
+    fn drop(&mut self) {
+        let mut messages = match self.on_exit {
+            OnExit::Keep => return,
+            OnExit::Clear | OnExit::Restore => vec![clear_message()],
+        };
+        if let (OnExit::Restore, Some(layout)) = (self.on_exit, self.layout) {
+            messages.push(select_layout(layout));
+        }
+        for message in messages {
+            // Nothing to be done about it now
+            let _ = self.daw_out.send(&message);
+        }
+    }
+}
+
+/// Turn off all 81 pads, the logo too
+fn clear_message() -> Vec<u8> {
+    LedMessage::new()
+        .colours(Pad::all().map(|pad| (pad.number(), 0)))
+        .expect("Every pad is on the LPX")
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::loopback_output;
+    use crate::Loopback;
+
+    #[test]
+    fn releases_held_notes() {
+        let held = HeldNotes::new();
+        let note = |channel, note, velocity| MidiMessage::NoteOn {
+            channel,
+            note,
+            velocity,
+        };
+        held.track(&note(0, 60, 100));
+        held.track(&note(0, 64, 100));
+        held.track(&note(3, 127, 1));
+        held.track(&note(0, 60, 0));
+        assert!(held.is_held(0, 64));
+        assert!(!held.is_held(0, 60));
+        let off = |channel, note| MidiMessage::NoteOff {
+            channel,
+            note,
+            velocity: 0,
+        };
+        let all_off = |channel| MidiMessage::ControlChange {
+            channel,
+            controller: ALL_NOTES_OFF,
+            value: 0,
+        };
+        assert_eq!(
+            held.release(),
+            vec![off(0, 64), off(3, 127), all_off(0), all_off(3)]
+        );
+        assert_eq!(held.release(), vec![]);
+    }
+
+    #[test]
+    fn on_exit() {
+        let mut args: Vec<String> = ["lpx_manager", "--on-exit", "restore", "midi.cfg"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(on_exit_from_args(&mut args).unwrap(), OnExit::Restore);
+        assert_eq!(args, vec!["lpx_manager", "midi.cfg"]);
+        assert_eq!(on_exit_from_args(&mut args).unwrap(), OnExit::Keep);
+        args.push("--on-exit".to_string());
+        assert!(matches!(
+            on_exit_from_args(&mut args),
+            Err(MIDIError::BadArgument { .. })
+        ));
+
+        let loopback = Loopback::new(&["LPX DAW"]);
+        let daw_out = || loopback_output(&loopback, "LPX DAW");
+        drop(LpxRestore::new(daw_out(), OnExit::Keep));
+        assert!(loopback.take_sent("LPX DAW").is_empty());
+        let mut restore = LpxRestore::new(daw_out(), OnExit::Restore);
+        restore.layout = Some(Layout::Note);
+        drop(restore);
+        let sent = loopback.take_sent("LPX DAW");
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].len(), 8 + 81 * 3);
+        assert_eq!(&sent[0][..10], &[0xF0, 0, 0x20, 0x29, 2, 0x0C, 3, 0, 11, 0]);
+        assert_eq!(sent[1], vec![0xF0, 0, 0x20, 0x29, 2, 0x0C, 0, 1, 0xF7]);
+    }
+}
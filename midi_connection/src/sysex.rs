@@ -0,0 +1,209 @@
+//! Putting SysEx back together.  The MIDI system can hand a long
+//! SysEx message over in several pieces.  `SysExAssembler` buffers
+//! the pieces and passes on complete, correctly framed, messages.
+//! Real time bytes that arrive in the middle of a SysEx message are
+//! passed on straight away.
+//!
+//! It can be told to pass on only SysEx that starts with a given
+//! header, e.g. `LPX_HEADER` for replies from the Launchpad X.
+//! Other SysEx is dropped.  Messages that are not SysEx are passed
+//! on unchanged.
+use crate::MIDIError;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+
+/// Longer SysEx than this is dropped rather than buffered forever
+const MAX_SYSEX: usize = 64 * 1024;
+
+/// The manufacturer (Novation) and device (Launchpad X) bytes that
+/// follow 0xF0 in every Launchpad X SysEx message
+pub const LPX_HEADER: [u8; 5] = lpx_protocol::HEADER;
+
+#[derive(Debug, Default)]
+pub struct SysExAssembler {
+    // The start of a SysEx message waiting for the rest
+    buffer: Option<Vec<u8>>,
+    // Only pass on SysEx with this after the 0xF0
+    header: Option<Vec<u8>>,
+}
+
+/// True if every byte of `bytes` is a data byte
+fn all_data(bytes: &[u8]) -> bool {
+    bytes.iter().all(|b| *b < 0x80)
+}
+
+impl SysExAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only pass on SysEx messages whose bytes after the 0xF0 start
+    /// with `header`
+    pub fn with_header(header: &[u8]) -> Self {
+        SysExAssembler {
+            buffer: None,
+            header: Some(header.to_vec()),
+        }
+    }
+
+    /// True if part of a SysEx message is waiting for the rest
+    pub fn is_partial(&self) -> bool {
+        self.buffer.is_some()
+    }
+
+    fn wanted(&self, sysex: &[u8]) -> bool {
+        match &self.header {
+            Some(header) => sysex[1..].starts_with(header),
+            None => true,
+        }
+    }
+
+    /// Read a piece of MIDI as it was handed over by the MIDI system.
+    /// `deliver` is called with each complete message.  Badly framed
+    /// SysEx is dropped and reported as an error, after the rest of
+    /// `chunk` has been read
+    pub fn push<F: FnMut(&[u8])>(&mut self, chunk: &[u8], mut deliver: F) -> Result<(), MIDIError> {
+        self.read(chunk, &mut deliver)
+    }
+
+    fn read(&mut self, chunk: &[u8], deliver: &mut dyn FnMut(&[u8])) -> Result<(), MIDIError> {
+        let bad = |bytes: &[u8], reason: &'static str| MIDIError::BadMessage {
+            bytes: bytes.to_vec(),
+            reason,
+        };
+        if self.buffer.is_none() {
+            match chunk {
+                [] => return Ok(()),
+                [SYSEX_START, body @ .., SYSEX_END] if all_data(body) => {
+                    // The usual case, all of the SysEx at once
+                    if self.wanted(chunk) {
+                        deliver(chunk);
+                    }
+                    return Ok(());
+                }
+                [SYSEX_START, ..] => self.buffer = Some(Vec::with_capacity(chunk.len() * 2)),
+                [status, ..] if *status >= 0x80 => {
+                    deliver(chunk);
+                    return Ok(());
+                }
+                _ => return Err(bad(chunk, "data with no status byte")),
+            }
+        }
+
+        for (i, &byte) in chunk.iter().enumerate() {
+            let buffer = match self.buffer.as_mut() {
+                Some(buffer) => buffer,
+                // The SysEx finished part way through the chunk
+                None => return self.read(&chunk[i..], deliver),
+            };
+            match byte {
+                0xF8..=0xFF => deliver(&[byte]),
+                SYSEX_START if buffer.is_empty() => buffer.push(byte),
+                SYSEX_END => {
+                    buffer.push(byte);
+                    let sysex = self.buffer.take().unwrap();
+                    if self.wanted(&sysex) {
+                        deliver(&sysex);
+                    }
+                }
+                0x80..=0xF7 => {
+                    // A new message has started before the SysEx
+                    // ended.  Drop the SysEx and read the new message
+                    let sysex = self.buffer.take().unwrap();
+                    let error = bad(&sysex, "SysEx interrupted by a status byte");
+                    return self.read(&chunk[i..], deliver).and(Err(error));
+                }
+                _ if buffer.len() >= MAX_SYSEX => {
+                    let sysex = self.buffer.take().unwrap();
+                    let error = bad(&sysex[..16], "SysEx too long");
+                    // Skip the rest of it
+                    return match chunk[i..].iter().position(|b| *b >= 0x80) {
+                        Some(end) => self.read(&chunk[i + end..], deliver).and(Err(error)),
+                        None => Err(error),
+                    };
+                }
+                _ => buffer.push(byte),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(assembler: &mut SysExAssembler, chunks: &[&[u8]]) -> (Vec<Vec<u8>>, usize) {
+        let mut delivered = Vec::new();
+        let mut errors = 0;
+        for chunk in chunks {
+            if assembler
+                .push(chunk, |message| delivered.push(message.to_vec()))
+                .is_err()
+            {
+                errors += 1;
+            }
+        }
+        (delivered, errors)
+    }
+
+    #[test]
+    fn reassembles_fragments() {
+        let mut assembler = SysExAssembler::new();
+        let (delivered, errors) = assemble(
+            &mut assembler,
+            &[
+                &[240, 0, 32],
+                &[41, 2, 0xF8, 12],
+                &[0, 1, 247],
+                &[144, 60, 100],
+            ],
+        );
+        assert_eq!(errors, 0);
+        assert_eq!(
+            delivered,
+            vec![
+                vec![0xF8],
+                vec![240, 0, 32, 41, 2, 12, 0, 1, 247],
+                vec![144, 60, 100],
+            ]
+        );
+        assert!(!assembler.is_partial());
+    }
+
+    #[test]
+    fn bad_framing_is_dropped() {
+        let mut assembler = SysExAssembler::new();
+        let (delivered, errors) = assemble(
+            &mut assembler,
+            &[&[240, 0, 32], &[144, 60, 100], &[60, 100], &[240, 1, 247]],
+        );
+        assert_eq!(errors, 2);
+        assert_eq!(delivered, vec![vec![144, 60, 100], vec![240, 1, 247]]);
+    }
+
+    #[test]
+    fn header_filter() {
+        let mut assembler = SysExAssembler::with_header(&LPX_HEADER);
+        let (delivered, errors) = assemble(
+            &mut assembler,
+            &[
+                &[240, 0, 32, 41, 2, 12, 0, 1, 247],
+                &[240, 126, 127, 6, 1, 247],
+                &[240, 0, 32, 41],
+                &[2, 12, 0, 0, 247],
+                &[176, 19, 127],
+            ],
+        );
+        assert_eq!(errors, 0);
+        assert_eq!(
+            delivered,
+            vec![
+                vec![240, 0, 32, 41, 2, 12, 0, 1, 247],
+                vec![240, 0, 32, 41, 2, 12, 0, 0, 247],
+                vec![176, 19, 127],
+            ]
+        );
+    }
+}
@@ -0,0 +1,405 @@
+//! A record of the MIDI that went through one or more
+//! `MIDICommunicator`s.  Give the same `Tap` to the communicators for
+//! the Launchpad and the synthesiser and every message in and out is
+//! written to one file, with the time and the port.
+//!
+//! There are two formats:
+//!
+//! * `TapFormat::Text` One line per message.  Seconds since the tap
+//!   was made, `in` or `out`, the port name and the bytes in hex,
+//!   separated by tabs.  E.g. a pad press:
+//!
+//!   `1.503271 in Launchpad X:Launchpad X MIDI 2 24:1 90 33 64`
+//!
+//! * `TapFormat::Smf` A type 1 Standard MIDI File, with a track for
+//!   each port and direction, named like `in Launchpad X:...`.  Time
+//!   is in milliseconds (25 frames a second, 40 ticks a frame).  It
+//!   is written when the tap is finished, so it is kept in memory
+//!   until then
+//!
+//! `Tap::from_args` takes `--tap FILE` from the command line.
+//!
+//! Writing is done by a thread of its own, so the MIDI threads do
+//! not wait for the disk.  The file is finished when the last clone
+//! of the `Tap`, and the communicators using it, are dropped.
+use crate::{Direction, MIDIError};
+use crossbeam_channel::Sender;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The first line of a text file
+const TEXT_HEADER: &str = "# seconds\tdirection\tport\tbytes";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapFormat {
+    Text,
+    Smf,
+}
+
+/// One message through a tapped communicator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapRecord {
+    /// Since the tap was made
+    pub time: Duration,
+    /// `Input` for messages from the port, `Output` for messages sent
+    /// to it
+    pub direction: Direction,
+    pub port: Arc<str>,
+    pub bytes: Vec<u8>,
+}
+
+/// The name used for `direction` in the files
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Input => "in",
+        _ => "out",
+    }
+}
+
+/// One line of the text format, without the newline
+pub fn text_line(record: &TapRecord) -> String {
+    let hex: Vec<String> = record.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(
+        "{}.{:06}\t{}\t{}\t{}",
+        record.time.as_secs(),
+        record.time.subsec_micros(),
+        direction_name(record.direction),
+        record.port,
+        hex.join(" ")
+    )
+}
+
+/// Read one line of the text format
+pub fn parse_text_line(line: &str) -> Result<TapRecord, String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [time, direction, port, bytes] = fields.as_slice() else {
+        return Err(format!("expected 4 fields, found {}", fields.len()));
+    };
+    let (secs, micros) = time.split_once('.').unwrap_or((time, "0"));
+    let time = match (secs.parse::<u64>(), micros.parse::<u32>()) {
+        (Ok(secs), Ok(micros)) if micros < 1_000_000 => {
+            Duration::from_secs(secs) + Duration::from_micros(micros as u64)
+        }
+        _ => return Err(format!("bad time \"{}\"", time)),
+    };
+    let direction = match *direction {
+        "in" => Direction::Input,
+        "out" => Direction::Output,
+        _ => return Err(format!("bad direction \"{}\"", direction)),
+    };
+    let bytes = bytes
+        .split_whitespace()
+        .map(|b| u8::from_str_radix(b, 16).map_err(|_| format!("bad byte \"{}\"", b)))
+        .collect::<Result<Vec<u8>, String>>()?;
+    Ok(TapRecord {
+        time,
+        direction,
+        port: Arc::from(*port),
+        bytes,
+    })
+}
+
+/// Read a file in the text format.  Lines starting with `#` are
+/// comments
+pub fn read_text<P: AsRef<Path>>(path: P) -> Result<Vec<TapRecord>, MIDIError> {
+    let text = std::fs::read_to_string(path)?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            parse_text_line(line).map_err(|reason| MIDIError::BadRecord {
+                line: index + 1,
+                reason,
+            })
+        })
+        .collect()
+}
+
+/// Write `records` to a file in the text format
+pub fn write_text<P: AsRef<Path>>(path: P, records: &[TapRecord]) -> Result<(), MIDIError> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "{}", TEXT_HEADER)?;
+    for record in records {
+        writeln!(out, "{}", text_line(record))?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Append `value` as a MIDI file variable length quantity
+fn write_varlen(track: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    track.extend(bytes.iter().rev());
+}
+
+/// The events, without the header or end of track, for one track
+/// of a MIDI file
+struct SmfTrack {
+    direction: Direction,
+    port: Arc<str>,
+    events: Vec<u8>,
+    // Milliseconds
+    last_time: u32,
+}
+
+impl SmfTrack {
+    fn new(direction: Direction, port: Arc<str>) -> Self {
+        let mut events = Vec::new();
+        // Track name
+        let name = format!("{} {}", direction_name(direction), port);
+        write_varlen(&mut events, 0);
+        events.extend_from_slice(&[0xFF, 0x03]);
+        write_varlen(&mut events, name.len() as u32);
+        events.extend_from_slice(name.as_bytes());
+        SmfTrack {
+            direction,
+            port,
+            events,
+            last_time: 0,
+        }
+    }
+
+    fn add(&mut self, time: Duration, bytes: &[u8]) {
+        if bytes.is_empty() {
+            // A delta time must have an event after it
+            return;
+        }
+        let time = time.as_millis().min(u32::MAX as u128) as u32;
+        write_varlen(&mut self.events, time.saturating_sub(self.last_time));
+        self.last_time = time.max(self.last_time);
+        match bytes.first() {
+            Some(0xF0) => {
+                // SysEx is stored without its 0xF0
+                self.events.push(0xF0);
+                write_varlen(&mut self.events, bytes.len() as u32 - 1);
+                self.events.extend_from_slice(&bytes[1..]);
+            }
+            Some(status) if *status >= 0xF1 => {
+                // Anything else that is not a channel message goes in
+                // as an escape
+                self.events.push(0xF7);
+                write_varlen(&mut self.events, bytes.len() as u32);
+                self.events.extend_from_slice(bytes);
+            }
+            _ => self.events.extend_from_slice(bytes),
+        }
+    }
+}
+
+/// Write a MIDI file of `tracks`
+fn write_smf(out: &mut impl Write, tracks: &[SmfTrack]) -> std::io::Result<()> {
+    out.write_all(b"MThd")?;
+    out.write_all(&6_u32.to_be_bytes())?;
+    out.write_all(&1_u16.to_be_bytes())?;
+    out.write_all(&(tracks.len() as u16).to_be_bytes())?;
+    // -25 frames a second, 40 ticks a frame: A tick is a millisecond
+    out.write_all(&[0xE7, 40])?;
+    for track in tracks {
+        out.write_all(b"MTrk")?;
+        out.write_all(&(track.events.len() as u32 + 4).to_be_bytes())?;
+        out.write_all(&track.events)?;
+        out.write_all(&[0, 0xFF, 0x2F, 0])?;
+    }
+    out.flush()
+}
+
+/// The thread that writes records to `file`
+fn writer(
+    file: File,
+    format: TapFormat,
+    records: crossbeam_channel::Receiver<TapRecord>,
+) -> std::io::Result<()> {
+    let mut out = BufWriter::new(file);
+    let mut tracks: Vec<SmfTrack> = Vec::new();
+    if format == TapFormat::Text {
+        writeln!(out, "{}", TEXT_HEADER)?;
+    }
+    for record in records.iter() {
+        match format {
+            TapFormat::Text => writeln!(out, "{}", text_line(&record))?,
+            TapFormat::Smf => {
+                let track = match tracks
+                    .iter()
+                    .position(|t| t.direction == record.direction && t.port == record.port)
+                {
+                    Some(index) => &mut tracks[index],
+                    None => {
+                        tracks.push(SmfTrack::new(record.direction, record.port.clone()));
+                        tracks.last_mut().unwrap()
+                    }
+                };
+                track.add(record.time, &record.bytes);
+            }
+        }
+        // Keep the text on disk up to date, so it is there if the
+        // programme crashes
+        if records.is_empty() {
+            out.flush()?;
+        }
+    }
+    if format == TapFormat::Smf {
+        write_smf(&mut out, &tracks)?;
+    }
+    out.flush()
+}
+
+struct Inner {
+    start: Instant,
+    sender: Option<Sender<TapRecord>>,
+    thread: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl Drop for Inner {
+    /// Finish the file
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            if let Ok(Err(err)) = thread.join() {
+                eprintln!("MIDI tap: {}", err);
+            }
+        }
+    }
+}
+
+/// Clones write to the same file
+#[derive(Clone)]
+pub struct Tap {
+    inner: Arc<Inner>,
+}
+
+impl std::fmt::Debug for Tap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tap").finish()
+    }
+}
+
+impl Tap {
+    /// Write a record to a new file at `path`
+    pub fn create<P: AsRef<Path>>(path: P, format: TapFormat) -> Result<Tap, MIDIError> {
+        let file = File::create(path)?;
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let thread = thread::spawn(move || writer(file, format, receiver));
+        Ok(Tap {
+            inner: Arc::new(Inner {
+                start: Instant::now(),
+                sender: Some(sender),
+                thread: Some(thread),
+            }),
+        })
+    }
+
+    /// Take `--tap FILE` out of `args` and make a tap writing to
+    /// `FILE`.  A file ending `.mid` is a Standard MIDI File, anything
+    /// else is text.  `None` without `--tap`
+    pub fn from_args(args: &mut Vec<String>) -> Result<Option<Tap>, MIDIError> {
+        match args.iter().position(|arg| arg == "--tap") {
+            Some(i) if i + 1 < args.len() => {
+                let path = args.remove(i + 1);
+                args.remove(i);
+                let format = match Path::new(&path).extension() {
+                    Some(ext) if ext.eq_ignore_ascii_case("mid") => TapFormat::Smf,
+                    _ => TapFormat::Text,
+                };
+                Tap::create(path, format).map(Some)
+            }
+            Some(_) => Err(MIDIError::BadArgument {
+                argument: "--tap".to_string(),
+                reason: "expected the name of a file after it".to_string(),
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Record `bytes` going in `direction` through `port`
+    pub fn record(&self, direction: Direction, port: &Arc<str>, bytes: &[u8]) {
+        if let Some(sender) = self.inner.sender.as_ref() {
+            let _ = sender.send(TapRecord {
+                time: self.inner.start.elapsed(),
+                direction,
+                port: port.clone(),
+                bytes: bytes.to_vec(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_file() {
+        let path = std::env::temp_dir().join(format!("midi-tap-{}.txt", std::process::id()));
+        let lpx: Arc<str> = Arc::from("LPX");
+        let tap = Tap::create(&path, TapFormat::Text).unwrap();
+        tap.record(Direction::Input, &lpx, &[144, 51, 100]);
+        tap.record(Direction::Output, &lpx, &[240, 0, 32, 41, 2, 12, 0, 1, 247]);
+        drop(tap);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].ends_with("\tin\tLPX\t90 33 64"));
+        assert!(lines[2].ends_with("\tout\tLPX\tF0 00 20 29 02 0C 00 01 F7"));
+        let records = read_text(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Input);
+        assert_eq!(records[1].bytes, vec![240, 0, 32, 41, 2, 12, 0, 1, 247]);
+        assert_eq!(text_line(&records[1]), lines[2]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn tap_from_args() {
+        let path = std::env::temp_dir().join(format!("midi-tap-{}.mid", std::process::id()));
+        let mut args: Vec<String> = vec!["lpx_manager", "--tap", path.to_str().unwrap(), "60"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let tap = Tap::from_args(&mut args).unwrap();
+        assert!(tap.is_some());
+        assert_eq!(args, vec!["lpx_manager", "60"]);
+        drop(tap);
+        // An empty MIDI file, with no tracks
+        assert_eq!(std::fs::read(&path).unwrap()[..4], *b"MThd");
+        std::fs::remove_file(path).unwrap();
+
+        assert!(Tap::from_args(&mut args).unwrap().is_none());
+        let mut args = vec!["lpx_manager".to_string(), "--tap".to_string()];
+        assert!(matches!(
+            Tap::from_args(&mut args),
+            Err(MIDIError::BadArgument { .. })
+        ));
+    }
+
+    #[test]
+    fn smf_tracks() {
+        let mut track = SmfTrack::new(Direction::Input, Arc::from("LPX"));
+        track.add(Duration::from_millis(200), &[144, 51, 100]);
+        track.add(Duration::from_millis(450), &[240, 0, 1, 247]);
+        // Nothing to write, not even its time
+        track.add(Duration::from_millis(500), &[]);
+        let mut smf = Vec::new();
+        write_smf(&mut smf, &[track]).unwrap();
+        assert_eq!(
+            smf,
+            [
+                b"MThd\0\0\0\x06\0\x01\0\x01\xE7\x28".as_slice(),
+                b"MTrk\0\0\0\x1A\0\xFF\x03\x06in LPX".as_slice(),
+                // 200ms and 250ms in variable length
+                b"\x81\x48\x90\x33\x64\x81\x7A\xF0\x03\0\x01\xF7".as_slice(),
+                b"\0\xFF\x2F\0".as_slice(),
+            ]
+            .concat()
+        );
+    }
+}
@@ -0,0 +1,291 @@
+//! The MIDI system underneath a `MIDICommunicator`.  `Midir` talks
+//! to real devices through `midir` (the ALSA sequencer on Linux).
+//! Other transports, like `Loopback`, let the rest of the code run
+//! without a Launchpad plugged in.
+use crate::{Direction, MIDIError};
+
+/// Called with the timestamp (microseconds) and bytes of each
+/// incoming MIDI message
+pub type InputCallback = Box<dyn FnMut(u64, &[u8]) + Send>;
+
+/// An open incoming connection.  Messages are delivered to the
+/// callback it was made with until it is dropped
+pub trait InputConnection: Send {}
+
+/// An open outgoing connection
+pub trait OutputConnection: Send {
+    fn send(&mut self, msg: &[u8]) -> Result<(), MIDIError>;
+}
+
+/// Lists the ports again and again, for the thread that watches them
+/// for a `MIDICommunicator`.  It is made once, so looking often is
+/// cheap
+pub trait PortLister: Send {
+    /// The names of ports that can be connected to for input
+    fn input_ports(&mut self) -> Result<Vec<String>, MIDIError>;
+
+    /// The names of ports that can be connected to for output
+    fn output_ports(&mut self) -> Result<Vec<String>, MIDIError>;
+}
+
+/// A source of MIDI ports.  `client_name` is the name this programme
+/// is known by to the MIDI system.  Ports are identified by the full
+/// names returned from `input_ports` and `output_ports`
+pub trait Transport: Send + Sync {
+    /// The names of ports that can be connected to for input
+    fn input_ports(&self, client_name: &str) -> Result<Vec<String>, MIDIError>;
+
+    /// The names of ports that can be connected to for output
+    fn output_ports(&self, client_name: &str) -> Result<Vec<String>, MIDIError>;
+
+    /// A `PortLister` for these ports, known to the MIDI system as
+    /// `client_name`
+    fn port_lister(&self, client_name: &str) -> Result<Box<dyn PortLister>, MIDIError>;
+
+    /// Connect to the input port called `port_name`.  `callback` is
+    /// called for each message received
+    fn connect_input(
+        &self,
+        client_name: &str,
+        port_name: &str,
+        callback: InputCallback,
+    ) -> Result<Box<dyn InputConnection>, MIDIError>;
+
+    /// Connect to the output port called `port_name`
+    fn connect_output(
+        &self,
+        client_name: &str,
+        port_name: &str,
+    ) -> Result<Box<dyn OutputConnection>, MIDIError>;
+
+    /// Make a new input port called `port_name` that other clients
+    /// can connect to and send to us.  `callback` is called for each
+    /// message received
+    fn create_virtual_input(
+        &self,
+        client_name: &str,
+        port_name: &str,
+        callback: InputCallback,
+    ) -> Result<Box<dyn InputConnection>, MIDIError>;
+
+    /// Make a new output port called `port_name` that other clients
+    /// can connect to and receive what we send
+    fn create_virtual_output(
+        &self,
+        client_name: &str,
+        port_name: &str,
+    ) -> Result<Box<dyn OutputConnection>, MIDIError>;
+}
+
+/// Real MIDI devices, through `midir`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Midir;
+
+impl Midir {
+    fn midi_input(client_name: &str) -> Result<midir::MidiInput, MIDIError> {
+        midir::MidiInput::new(client_name).map_err(|err| MIDIError::Init {
+            client_name: client_name.to_string(),
+            reason: err.to_string(),
+        })
+    }
+
+    fn midi_output(client_name: &str) -> Result<midir::MidiOutput, MIDIError> {
+        midir::MidiOutput::new(client_name).map_err(|err| MIDIError::Init {
+            client_name: client_name.to_string(),
+            reason: err.to_string(),
+        })
+    }
+}
+
+/// The names of all the ports `midi_io` can see
+fn port_names<T: midir::MidiIO>(midi_io: &T) -> Vec<String> {
+    midi_io
+        .ports()
+        .iter()
+        .filter_map(|p| midi_io.port_name(p).ok())
+        .collect()
+}
+
+/// The port of `midi_io` called exactly `port_name`
+fn find_port<T: midir::MidiIO>(
+    midi_io: &T,
+    port_name: &str,
+    direction: Direction,
+) -> Result<T::Port, MIDIError> {
+    for p in midi_io.ports().iter() {
+        if midi_io.port_name(p)? == port_name {
+            return Ok(p.clone());
+        }
+    }
+    Err(MIDIError::NoMatchingPort {
+        direction,
+        requested: port_name.to_string(),
+        available: port_names(midi_io),
+    })
+}
+
+/// Lists ports through an ALSA client for each direction, made the
+/// first time it is needed and kept
+struct MidirLister {
+    client_name: String,
+    midi_input: Option<midir::MidiInput>,
+    midi_output: Option<midir::MidiOutput>,
+}
+
+impl PortLister for MidirLister {
+    fn input_ports(&mut self) -> Result<Vec<String>, MIDIError> {
+        let midi_input = match self.midi_input.take() {
+            Some(midi_input) => midi_input,
+            None => Midir::midi_input(self.client_name.as_str())?,
+        };
+        Ok(port_names(self.midi_input.insert(midi_input)))
+    }
+
+    fn output_ports(&mut self) -> Result<Vec<String>, MIDIError> {
+        let midi_output = match self.midi_output.take() {
+            Some(midi_output) => midi_output,
+            None => Midir::midi_output(self.client_name.as_str())?,
+        };
+        Ok(port_names(self.midi_output.insert(midi_output)))
+    }
+}
+
+struct MidirInput {
+    _connection: midir::MidiInputConnection<()>,
+}
+impl InputConnection for MidirInput {}
+
+struct MidirOutput(midir::MidiOutputConnection);
+impl OutputConnection for MidirOutput {
+    fn send(&mut self, msg: &[u8]) -> Result<(), MIDIError> {
+        Ok(self.0.send(msg)?)
+    }
+}
+
+impl Transport for Midir {
+    fn input_ports(&self, client_name: &str) -> Result<Vec<String>, MIDIError> {
+        Ok(port_names(&Self::midi_input(client_name)?))
+    }
+
+    fn output_ports(&self, client_name: &str) -> Result<Vec<String>, MIDIError> {
+        Ok(port_names(&Self::midi_output(client_name)?))
+    }
+
+    fn port_lister(&self, client_name: &str) -> Result<Box<dyn PortLister>, MIDIError> {
+        Ok(Box::new(MidirLister {
+            client_name: client_name.to_string(),
+            midi_input: None,
+            midi_output: None,
+        }))
+    }
+
+    fn connect_input(
+        &self,
+        client_name: &str,
+        port_name: &str,
+        mut callback: InputCallback,
+    ) -> Result<Box<dyn InputConnection>, MIDIError> {
+        let mut midi_in = Self::midi_input(client_name)?;
+        midi_in.ignore(midir::Ignore::None);
+        let port = find_port(&midi_in, port_name, Direction::Input)?;
+        let connection = midi_in
+            .connect(
+                &port,
+                format!("{}-in", client_name).as_str(),
+                move |stamp, message, _| callback(stamp, message),
+                (),
+            )
+            .map_err(|err| MIDIError::Connect {
+                direction: Direction::Input,
+                port: port_name.to_string(),
+                reason: err.to_string(),
+            })?;
+        Ok(Box::new(MidirInput {
+            _connection: connection,
+        }))
+    }
+
+    fn connect_output(
+        &self,
+        client_name: &str,
+        port_name: &str,
+    ) -> Result<Box<dyn OutputConnection>, MIDIError> {
+        let midi_out = Self::midi_output(client_name)?;
+        let port = find_port(&midi_out, port_name, Direction::Output)?;
+        let connection = midi_out
+            .connect(&port, format!("{}-out", client_name).as_str())
+            .map_err(|err| MIDIError::Connect {
+                direction: Direction::Output,
+                port: port_name.to_string(),
+                reason: err.to_string(),
+            })?;
+        Ok(Box::new(MidirOutput(connection)))
+    }
+
+    #[cfg(unix)]
+    fn create_virtual_input(
+        &self,
+        client_name: &str,
+        port_name: &str,
+        mut callback: InputCallback,
+    ) -> Result<Box<dyn InputConnection>, MIDIError> {
+        use midir::os::unix::VirtualInput;
+        let mut midi_in = Self::midi_input(client_name)?;
+        midi_in.ignore(midir::Ignore::None);
+        let connection = midi_in
+            .create_virtual(
+                port_name,
+                move |stamp, message, _| callback(stamp, message),
+                (),
+            )
+            .map_err(|err| MIDIError::Connect {
+                direction: Direction::Input,
+                port: port_name.to_string(),
+                reason: err.to_string(),
+            })?;
+        Ok(Box::new(MidirInput {
+            _connection: connection,
+        }))
+    }
+
+    #[cfg(not(unix))]
+    fn create_virtual_input(
+        &self,
+        _client_name: &str,
+        _port_name: &str,
+        _callback: InputCallback,
+    ) -> Result<Box<dyn InputConnection>, MIDIError> {
+        Err(MIDIError::Unsupported(
+            "virtual ports are not available on this system",
+        ))
+    }
+
+    #[cfg(unix)]
+    fn create_virtual_output(
+        &self,
+        client_name: &str,
+        port_name: &str,
+    ) -> Result<Box<dyn OutputConnection>, MIDIError> {
+        use midir::os::unix::VirtualOutput;
+        let midi_out = Self::midi_output(client_name)?;
+        let connection = midi_out
+            .create_virtual(port_name)
+            .map_err(|err| MIDIError::Connect {
+                direction: Direction::Output,
+                port: port_name.to_string(),
+                reason: err.to_string(),
+            })?;
+        Ok(Box::new(MidirOutput(connection)))
+    }
+
+    #[cfg(not(unix))]
+    fn create_virtual_output(
+        &self,
+        _client_name: &str,
+        _port_name: &str,
+    ) -> Result<Box<dyn OutputConnection>, MIDIError> {
+        Err(MIDIError::Unsupported(
+            "virtual ports are not available on this system",
+        ))
+    }
+}